@@ -73,6 +73,10 @@ pub fn boot_services() -> &'static uefi::table::boot::BootServices {
     unsafe { SYSTEM_TABLE.as_ref().unwrap().boot_services() }
 }
 
+pub fn runtime_services() -> &'static uefi::table::runtime::RuntimeServices {
+    unsafe { SYSTEM_TABLE.as_ref().unwrap().runtime_services() }
+}
+
 pub fn init(_handle: uefi::Handle, system_table: &SystemTable<Boot>) -> uefi::Result {
     unsafe {
         SYSTEM_TABLE = Some(system_table.unsafe_clone());
@@ -0,0 +1,551 @@
+// Codec graph dump in the format of Linux sound/pci/hda/hda_proc.c
+// so that it can be diffed against /proc/asound/card*/codec#* or
+// alsa-info output taken on the same machine. Mixer controls and
+// coefficients are not known to us and thus are not printed.
+use core::fmt::Write;
+use alloc::string::String;
+
+use super::*;
+
+const PCM_RATES: [u32; 12] = [
+    8000, 11025, 16000, 22050, 32000, 44100, 48000, 88200, 96000, 176400, 192000, 384000
+];
+
+const PCM_BITS: [u32; 5] = [8, 16, 20, 24, 32];
+
+const POWER_STATES: [&str; 5] = ["D0", "D1", "D2", "D3", "D3cold"];
+
+const WIDGET_TYPES: [&str; 8] = [
+    "Audio Output",
+    "Audio Input",
+    "Audio Mixer",
+    "Audio Selector",
+    "Pin Complex",
+    "Power Widget",
+    "Volume Knob Widget",
+    "Beep Generator Widget",
+];
+
+const JACK_PORTS: [&str; 4] = ["Jack", "N/A", "Fixed", "Both"];
+
+const JACK_DEVICES: [&str; 16] = [
+    "Line Out", "Speaker", "HP Out", "CD",
+    "SPDIF Out", "Digital Out", "Modem Line", "Modem Hand",
+    "Line In", "Aux", "Mic", "Telephony",
+    "SPDIF In", "Digital In", "Reserved", "Other"
+];
+
+const JACK_CONNECTIVITY: [&str; 4] = ["Ext", "Int", "Sep", "Oth"];
+
+const JACK_LOCATIONS: [&str; 7] = ["N/A", "Rear", "Front", "Left", "Right", "Top", "Bottom"];
+
+const JACK_SPECIAL_LOCATIONS: [(u32, &str); 7] = [
+    (0x07, "Rear Panel"),
+    (0x08, "Drive Bar"),
+    (0x17, "Riser"),
+    (0x18, "HDMI"),
+    (0x19, "ATAPI"),
+    (0x37, "Mobile-In"),
+    (0x38, "Mobile-Out"),
+];
+
+const JACK_CONNECTIONS: [&str; 16] = [
+    "Unknown", "1/8", "1/4", "ATAPI", "RCA", "Optical", "Digital", "Analog",
+    "DIN", "XLR", "RJ11", "Comb", "UNKNOWN", "UNKNOWN", "UNKNOWN", "Other"
+];
+
+const JACK_COLORS: [&str; 16] = [
+    "Unknown", "Black", "Grey", "Blue", "Green", "Red", "Orange", "Yellow",
+    "Purple", "Pink", "UNKNOWN", "UNKNOWN", "UNKNOWN", "UNKNOWN", "White", "Other"
+];
+
+// Vendors that use the HDMI pin capability bit for R/L swap
+const VID_REALTEK: u32 = 0x10ec;
+
+fn power_state_name(state: u32) -> &'static str {
+    POWER_STATES
+        .get((state & 0xf) as usize)
+        .cloned()
+        .unwrap_or("UNKNOWN")
+}
+
+fn jack_location(config: &PinConfig) -> &'static str {
+    let location = config.location();
+    if let Some(&name) = JACK_LOCATIONS.get((location & 0xf) as usize) {
+        return name;
+    }
+    JACK_SPECIAL_LOCATIONS
+        .iter()
+        .find(|&&(special, _)| special == location)
+        .map(|&(_, name)| name)
+        .unwrap_or("UNKNOWN")
+}
+
+fn dump_pcm_caps<B: BusIo>(out: &mut String, bus: &mut B, codec: Codec, node: Node) -> uefi::Result {
+    let pcm = bus.exec(make_command(codec, node, HDA_VERB_PARAMS, HDA_PARAM_SUPPORTED_PCM))
+        .ignore_warning()?;
+    let formats = bus.exec(make_command(codec, node, HDA_VERB_PARAMS, HDA_PARAM_SUPPORTED_STREAM_FORMATS))
+        .ignore_warning()?;
+    write!(out, "    rates [{:#x}]:", pcm & 0xfff);
+    for (bit, rate) in PCM_RATES.iter().enumerate() {
+        if pcm & (1 << bit) != 0 {
+            write!(out, " {}", rate);
+        }
+    }
+    writeln!(out);
+    write!(out, "    bits [{:#x}]:", (pcm >> 16) & 0xff);
+    for (bit, bits) in PCM_BITS.iter().enumerate() {
+        if pcm & (1 << (16 + bit)) != 0 {
+            write!(out, " {}", bits);
+        }
+    }
+    writeln!(out);
+    write!(out, "    formats [{:#x}]:", formats & 0xf);
+    for (bit, name) in ["PCM", "FLOAT", "AC3"].iter().enumerate() {
+        if formats & (1 << bit) != 0 {
+            write!(out, " {}", name);
+        }
+    }
+    writeln!(out);
+    Ok(().into())
+}
+
+fn dump_amp_caps(out: &mut String, caps: u32) {
+    if caps == 0 {
+        writeln!(out, "N/A");
+        return;
+    }
+    let caps = AmpCapabilities::from(caps);
+    writeln!(out, "ofs={:#04x}, nsteps={:#04x}, stepsize={:#04x}, mute={}",
+             caps.offset(), caps.num_steps(), caps.step_size(), caps.mute() as u32);
+}
+
+fn dump_amp_vals<B: BusIo>(out: &mut String, bus: &mut B, codec: Codec, node: Node, output: bool, stereo: bool, indices: usize) -> uefi::Result {
+    let direction = if output { HDA_AMPLIFIER_GAIN_MUTE_GET_OUT_BIT } else { 0 };
+    for index in 0..indices as u32 {
+        let left = bus.exec(make_command(codec, node, HDA_VERB_GET_AMPLIFIER_GAIN_MUTE, Param(direction | HDA_AMPLIFIER_GAIN_MUTE_GET_LEFT_BIT | index)))
+            .ignore_warning()?;
+        write!(out, " [{:#04x}", left);
+        if stereo {
+            let right = bus.exec(make_command(codec, node, HDA_VERB_GET_AMPLIFIER_GAIN_MUTE, Param(direction | index)))
+                .ignore_warning()?;
+            write!(out, " {:#04x}", right);
+        }
+        write!(out, "]");
+    }
+    writeln!(out);
+    Ok(().into())
+}
+
+fn dump_power_state<B: BusIo>(out: &mut String, bus: &mut B, codec: Codec, node: Node) -> uefi::Result {
+    let supported = bus.exec(make_command(codec, node, HDA_VERB_PARAMS, HDA_PARAM_SUPPORTED_POWER_STATES))
+        .ignore_warning()?;
+    let power = bus.exec(make_command(codec, node, HDA_VERB_GET_POWER_STATE, Param(0x0)))
+        .ignore_warning()?;
+    write!(out, "  Power states: ");
+    for (bit, name) in POWER_STATES.iter().enumerate() {
+        if supported & (1 << bit) != 0 {
+            write!(out, " {}", name);
+        }
+    }
+    for &(bit, name) in [(BIT29, "S3D3cold"), (BIT30, "CLKSTOP"), (BIT31, "EPSS")].iter() {
+        if supported & bit != 0 {
+            write!(out, " {}", name);
+        }
+    }
+    writeln!(out);
+    write!(out, "  Power: setting={}, actual={}", power_state_name(power), power_state_name(power >> 4));
+    if power & BIT8 != 0 {
+        write!(out, ", Error");
+    }
+    if power & BIT9 != 0 {
+        write!(out, ", Clock-stop-OK");
+    }
+    if power & BIT10 != 0 {
+        write!(out, ", Setting-reset");
+    }
+    writeln!(out);
+    Ok(().into())
+}
+
+fn dump_gpio<B: BusIo>(out: &mut String, bus: &mut B, codec: Codec, afg: Node) -> uefi::Result {
+    let gpio = bus.exec(make_command(codec, afg, HDA_VERB_PARAMS, HDA_PARAM_GPIO_COUNT))
+        .ignore_warning()?;
+    let count = gpio & 0xff;
+    writeln!(out, "GPIO: io={}, o={}, i={}, unsolicited={}, wake={}",
+             count, (gpio >> 8) & 0xff, (gpio >> 16) & 0xff, (gpio >> 30) & 1, (gpio >> 31) & 1);
+    if count == 0 || count > 8 {
+        return Ok(().into());
+    }
+    let mut read = |verb| {
+        bus.exec(make_command(codec, afg, verb, Param(0x0)))
+            .ignore_warning()
+    };
+    let enable = read(HDA_VERB_GET_GPIO_ENABLE_MASK)?;
+    let direction = read(HDA_VERB_GET_GPIO_DIRECTION)?;
+    let wake = read(HDA_VERB_GET_GPIO_WAKE_ENABLE_MASK)?;
+    let unsolicited = read(HDA_VERB_GET_GPIO_UNSOLICITED_ENABLE_MASK)?;
+    let sticky = read(HDA_VERB_GET_GPIO_STICKY_MASK)?;
+    let data = read(HDA_VERB_GET_GPIO_DATA)?;
+    for io in 0..count {
+        let bit = |mask: u32| (mask >> io) & 1;
+        writeln!(out, "  IO[{}]: enable={}, dir={}, wake={}, sticky={}, data={}, unsol={}",
+                 io, bit(enable), bit(direction), bit(wake), bit(sticky), bit(data), bit(unsolicited));
+    }
+    Ok(().into())
+}
+
+// Returns whether the pin supports VREF control
+fn dump_pin_caps<B: BusIo>(out: &mut String, bus: &mut B, codec: Codec, node: Node, vendor_id: u32) -> uefi::Result<bool> {
+    let caps = bus.exec(make_command(codec, node, HDA_VERB_PARAMS, HDA_PARAM_PIN_WIDGET_CAPABILITIES))
+        .ignore_warning()?;
+    let pin_caps = PinCapabilities::from(caps);
+    write!(out, "  Pincap {:#010x}:", caps);
+    if pin_caps.input_capable() != 0 {
+        write!(out, " IN");
+    }
+    if pin_caps.output_capable() != 0 {
+        write!(out, " OUT");
+    }
+    if pin_caps.headphone_drive_capable() != 0 {
+        write!(out, " HP");
+    }
+    if pin_caps.eapd_capable() {
+        write!(out, " EAPD");
+    }
+    if pin_caps.presence_detect_capable() != 0 {
+        write!(out, " Detect");
+    }
+    if pin_caps.balanced_io_pins() {
+        write!(out, " Balanced");
+    }
+    if pin_caps.hdmi() != 0 {
+        if (vendor_id >> 16) == VID_REALTEK {
+            write!(out, " R/L");
+        } else {
+            if pin_caps.high_bit_rate() != 0 {
+                write!(out, " HBR");
+            }
+            write!(out, " HDMI");
+        }
+    }
+    if pin_caps.display_port() != 0 {
+        write!(out, " DP");
+    }
+    if pin_caps.trigger_required() != 0 {
+        write!(out, " Trigger");
+    }
+    if pin_caps.impedance_sense_capable() != 0 {
+        write!(out, " ImpSense");
+    }
+    writeln!(out);
+    let mut supports_vref = false;
+    if pin_caps.input_capable() != 0 {
+        write!(out, "    Vref caps:");
+        let vref = pin_caps.vref_control();
+        for &(bit, name) in [(BIT0, "HIZ"), (BIT1, "50"), (BIT2, "GRD"), (BIT4, "80"), (BIT5, "100")].iter() {
+            if vref & bit != 0 {
+                write!(out, " {}", name);
+            }
+        }
+        writeln!(out);
+        supports_vref = vref != 0;
+    }
+    if pin_caps.eapd_capable() {
+        let eapd = bus.exec(make_command(codec, node, HDA_VERB_GET_EAPDBTL_ENABLE, Param(0x0)))
+            .ignore_warning()?;
+        write!(out, "  EAPD {:#x}:", eapd);
+        if eapd & HDA_PIN_EAPDBTL_BTL_ENABLE_BIT != 0 {
+            write!(out, " BALANCED");
+        }
+        if eapd & HDA_PIN_EAPDBTL_EAPD_ENABLE_BIT != 0 {
+            write!(out, " EAPD");
+        }
+        if eapd & HDA_PIN_EAPDBTL_LR_SWAP_BIT != 0 {
+            write!(out, " R/L");
+        }
+        writeln!(out);
+    }
+    let config = bus.exec(make_command(codec, node, HDA_VERB_GET_CONFIG_DEFAULT, Param(0x0)))
+        .ignore_warning()?;
+    let pin_config = PinConfig::from(config);
+    writeln!(out, "  Pin Default {:#010x}: [{}] {} at {} {}",
+             config,
+             JACK_PORTS[pin_config.port_connectivity() as usize],
+             JACK_DEVICES[pin_config.device() as usize],
+             JACK_CONNECTIVITY[(pin_config.location() >> 4) as usize],
+             jack_location(&pin_config));
+    writeln!(out, "    Conn = {}, Color = {}",
+             JACK_CONNECTIONS[pin_config.typ() as usize],
+             JACK_COLORS[pin_config.color() as usize]);
+    writeln!(out, "    DefAssociation = {:#x}, Sequence = {:#x}",
+             pin_config.association(), pin_config.sequence());
+    if pin_config.misc() & HDA_JACK_MISC_DETECT_OVERRIDE != 0 {
+        writeln!(out, "    Misc = NO_PRESENCE");
+    }
+    Ok(supports_vref.into())
+}
+
+fn dump_pin_ctls<B: BusIo>(out: &mut String, bus: &mut B, codec: Codec, node: Node, supports_vref: bool) -> uefi::Result {
+    let ctl = bus.exec(make_command(codec, node, HDA_VERB_GET_PIN_WIDGET_CONTROL, Param(0x0)))
+        .ignore_warning()?;
+    write!(out, "  Pin-ctls: {:#04x}:", ctl);
+    if ctl & HDA_PIN_WIDGET_CONTROL_IN_ENABLE_BIT != 0 {
+        write!(out, " IN");
+    }
+    if ctl & HDA_PIN_WIDGET_CONTROL_OUT_ENABLE_BIT != 0 {
+        write!(out, " OUT");
+    }
+    if ctl & HDA_PIN_WIDGET_CONTROL_PHN_ENABLE_BIT != 0 {
+        write!(out, " HP");
+    }
+    if supports_vref {
+        match ctl & HDA_PIN_WIDGET_CONTROL_VREF_MASK {
+            0 => write!(out, " VREF_HIZ"),
+            1 => write!(out, " VREF_50"),
+            2 => write!(out, " VREF_GRD"),
+            4 => write!(out, " VREF_80"),
+            5 => write!(out, " VREF_100"),
+            _ => Ok(())
+        };
+    }
+    writeln!(out);
+    Ok(().into())
+}
+
+fn dump_converter<B: BusIo>(out: &mut String, bus: &mut B, codec: Codec, node: Node, caps: &WidgetCapabilities) -> uefi::Result {
+    let converter = bus.exec(make_command(codec, node, HDA_VERB_GET_CHANNEL_STREAM, Param(0x0)))
+        .ignore_warning()?;
+    writeln!(out, "  Converter: stream={}, channel={}", (converter >> 4) & 0xf, converter & 0xf);
+    if caps.digital() != 0 {
+        let digital = bus.exec(make_command(codec, node, HDA_VERB_GET_DIGITAL_CONVERTER, Param(0x0)))
+            .ignore_warning()?;
+        write!(out, "  Digital:");
+        let names = [
+            "Enabled", "Validity", "ValidityCfg", "Preemphasis",
+            "Non-Copyright", "Non-Audio", "Pro", "GenLevel"
+        ];
+        for (bit, name) in names.iter().enumerate() {
+            if digital & (1 << bit) != 0 {
+                write!(out, " {}", name);
+            }
+        }
+        if digital & BIT23 != 0 {
+            write!(out, " KAE");
+        }
+        writeln!(out);
+        writeln!(out, "  Digital category: {:#x}", (digital >> 8) & 0x7f);
+        writeln!(out, "  IEC Coding Type: {:#x}", (digital >> 16) & 0xf);
+    }
+    if caps.format_override() != 0 {
+        writeln!(out, "  PCM:");
+        dump_pcm_caps(out, bus, codec, node)?;
+    }
+    Ok(().into())
+}
+
+fn dump_connections<B: BusIo>(out: &mut String, bus: &mut B, codec: Codec, node: Node, typ: u32, connections: &[Option<Node>]) -> uefi::Result {
+    if connections.is_empty() {
+        return Ok(().into());
+    }
+    // Mixers and volume knobs do not select an input
+    let select = if typ != HDA_WIDGET_AUDIO_MIX && typ != HDA_WIDGET_VOLUME_KNOB {
+        let select = bus.exec(make_command(codec, node, HDA_VERB_GET_CONNECTION_SELECT, Param(0x0)))
+            .ignore_warning()?;
+        Some(select as usize)
+    } else {
+        None
+    };
+    writeln!(out, "  Connection: {}", connections.len());
+    write!(out, "    ");
+    for (index, connection) in connections.iter().enumerate() {
+        match connection {
+            Some(connection) => write!(out, " {:#04x}", connection.0),
+            // Rejected by codec_get_connections()
+            None => write!(out, " ????"),
+        };
+        if select == Some(index) {
+            write!(out, "*");
+        }
+    }
+    writeln!(out);
+    Ok(().into())
+}
+
+fn dump_node<B: BusIo>(out: &mut String, bus: &mut B, codec: Codec, node: Node, afg_nodes: &NodeDescriptor, afg_caps: (u32, u32), vendor_id: u32) -> uefi::Result {
+    let wcaps = bus.exec(make_command(codec, node, HDA_VERB_PARAMS, HDA_PARAM_AUDIO_WIDGET_CAPABILITIES))
+        .ignore_warning()?;
+    let caps = WidgetCapabilities::from(wcaps);
+    let typ = caps.typ();
+    let name = match typ {
+        HDA_WIDGET_VENDOR => "Vendor Defined Widget",
+        typ => WIDGET_TYPES.get(typ as usize).cloned().unwrap_or("UNKNOWN Widget")
+    };
+    write!(out, "Node {:#04x} [{}] wcaps {:#x}:", node.0, name, wcaps);
+    if caps.stereo() != 0 {
+        if caps.channels() == 2 {
+            write!(out, " Stereo");
+        } else {
+            write!(out, " {}-Channels", caps.channels());
+        }
+    } else {
+        write!(out, " Mono");
+    }
+    if caps.digital() != 0 {
+        write!(out, " Digital");
+    }
+    if caps.in_amp_present() != 0 {
+        write!(out, " Amp-In");
+    }
+    if caps.out_amp_present() != 0 {
+        write!(out, " Amp-Out");
+    }
+    if caps.stripe() != 0 {
+        write!(out, " Stripe");
+    }
+    if caps.lr_swap() != 0 {
+        write!(out, " R/L");
+    }
+    if caps.cp_caps() != 0 {
+        write!(out, " CP");
+    }
+    writeln!(out);
+    // Volume knobs always have a connection list
+    let has_connections = caps.connection_list() != 0 || typ == HDA_WIDGET_VOLUME_KNOB;
+    let connections = if has_connections {
+        codec_get_connections(bus, codec, node, afg_nodes)
+            .ignore_warning()?
+    } else {
+        alloc::vec::Vec::new()
+    };
+    let stereo = caps.stereo() != 0;
+    // Widgets without the override use the AFG defaults
+    let (afg_in_caps, afg_out_caps) = afg_caps;
+    if caps.in_amp_present() != 0 {
+        let amp_caps = if caps.amp_param_override() != 0 {
+            bus.exec(make_command(codec, node, HDA_VERB_PARAMS, HDA_PARAM_AMPLIFIER_INPUT_CAPABILITY))
+                .ignore_warning()?
+        } else {
+            afg_in_caps
+        };
+        write!(out, "  Amp-In caps: ");
+        dump_amp_caps(out, amp_caps);
+        write!(out, "  Amp-In vals: ");
+        let indices = if typ == HDA_WIDGET_PIN_COMPLEX { 1 } else { connections.len() };
+        dump_amp_vals(out, bus, codec, node, false, stereo, indices)?;
+    }
+    if caps.out_amp_present() != 0 {
+        let amp_caps = if caps.amp_param_override() != 0 {
+            bus.exec(make_command(codec, node, HDA_VERB_PARAMS, HDA_PARAM_AMPLIFIER_OUTPUT_CAPABILITY))
+                .ignore_warning()?
+        } else {
+            afg_out_caps
+        };
+        write!(out, "  Amp-Out caps: ");
+        dump_amp_caps(out, amp_caps);
+        write!(out, "  Amp-Out vals: ");
+        dump_amp_vals(out, bus, codec, node, true, stereo, 1)?;
+    }
+    match typ {
+        HDA_WIDGET_PIN_COMPLEX => {
+            let supports_vref = dump_pin_caps(out, bus, codec, node, vendor_id)
+                .ignore_warning()?;
+            dump_pin_ctls(out, bus, codec, node, supports_vref)?;
+        },
+        HDA_WIDGET_VOLUME_KNOB => {
+            let knob_caps = bus.exec(make_command(codec, node, HDA_VERB_PARAMS, HDA_PARAM_VOLUME_KNOB_CAPABILITIES))
+                .ignore_warning()
+                .map(VolumeKnobCapabilities::from)?;
+            let knob = bus.exec(make_command(codec, node, HDA_VERB_GET_VOLUME_KNOB, Param(0x0)))
+                .ignore_warning()?;
+            writeln!(out, "  Volume-Knob: delta={}, steps={}, direct={}, val={}",
+                     knob_caps.delta() as u32, knob_caps.num_steps(),
+                     (knob >> 7) & 1, knob & HDA_SET_VOLUME_KNOB_VOLUME_MASK);
+        },
+        HDA_WIDGET_AUDIO_OUT | HDA_WIDGET_AUDIO_IN => {
+            dump_converter(out, bus, codec, node, &caps)?;
+        },
+        _ => {}
+    }
+    if caps.unsol_capable() != 0 {
+        let unsolicited = bus.exec(make_command(codec, node, HDA_VERB_GET_UNSOLICITED_RESPONSE, Param(0x0)))
+            .ignore_warning()?;
+        writeln!(out, "  Unsolicited: tag={:02x}, enabled={}",
+                 unsolicited & HDA_UNSOLICITED_RESPONSE_TAG_MASK,
+                 (unsolicited & HDA_UNSOLICITED_RESPONSE_ENABLE_BIT != 0) as u32);
+    }
+    if caps.power_ctl() != 0 {
+        dump_power_state(out, bus, codec, node)?;
+    }
+    if caps.delay() != 0 {
+        writeln!(out, "  Delay: {} samples", caps.delay());
+    }
+    if has_connections {
+        dump_connections(out, bus, codec, node, typ, &connections)?;
+    }
+    if caps.proc_widget() != 0 {
+        let proc_caps = bus.exec(make_command(codec, node, HDA_VERB_PARAMS, HDA_PARAM_PROCESSING_CAPABILITIES))
+            .ignore_warning()?;
+        writeln!(out, "  Processing caps: benign={}, ncoeff={}", proc_caps & 1, (proc_caps >> 8) & 0xff);
+    }
+    Ok(().into())
+}
+
+/// Describe the audio function group of the codec and all of
+/// its widgets. Other function groups are only mentioned.
+pub(crate) fn codec_dump<B: BusIo>(bus: &mut B, codec: Codec, afg: Node) -> uefi::Result<String> {
+    let mut out = String::new();
+    let vendor_id = bus.exec(make_command(codec, HDA_NODE_ROOT, HDA_VERB_PARAMS, HDA_PARAM_VID))
+        .ignore_warning()?;
+    let revision_id = bus.exec(make_command(codec, HDA_NODE_ROOT, HDA_VERB_PARAMS, HDA_PARAM_REVISION_ID))
+        .ignore_warning()?;
+    let groups = codec_function_groups(bus, codec)
+        .ignore_warning()?;
+    let function_type = bus.exec(make_command(codec, afg, HDA_VERB_PARAMS, HDA_PARAM_FUNCTION_TYPE))
+        .ignore_warning()?;
+    let mfg = groups
+        .iter()
+        .find(|group| group.is_modem());
+    let subsystem_id = bus.exec(make_command(codec, afg, HDA_VERB_GET_SUBSYSTEM_ID, Param(0x0)))
+        .ignore_warning()?;
+    // We have no table of chip names
+    writeln!(out, "Codec: ID {:x}", vendor_id);
+    writeln!(out, "Address: {}", codec.0);
+    writeln!(out, "AFG Function Id: {:#x} (unsol {})",
+             function_type & HDA_FUNCTION_TYPE_MASK, (function_type >> 8) & 1);
+    if let Some(mfg) = mfg {
+        writeln!(out, "MFG Function Id: {:#x} (unsol {})", mfg.typ, mfg.unsol_capable as u32);
+    }
+    writeln!(out, "Vendor Id: {:#010x}", vendor_id);
+    writeln!(out, "Subsystem Id: {:#010x}", subsystem_id);
+    writeln!(out, "Revision Id: {:#x}", revision_id);
+    match mfg {
+        Some(mfg) => writeln!(out, "Modem Function Group: {:#x}", mfg.node.0),
+        None => writeln!(out, "No Modem Function Group found")
+    };
+    writeln!(out, "Default PCM:");
+    dump_pcm_caps(&mut out, bus, codec, afg)?;
+    let afg_in_caps = bus.exec(make_command(codec, afg, HDA_VERB_PARAMS, HDA_PARAM_AMPLIFIER_INPUT_CAPABILITY))
+        .ignore_warning()?;
+    let afg_out_caps = bus.exec(make_command(codec, afg, HDA_VERB_PARAMS, HDA_PARAM_AMPLIFIER_OUTPUT_CAPABILITY))
+        .ignore_warning()?;
+    write!(out, "Default Amp-In caps: ");
+    dump_amp_caps(&mut out, afg_in_caps);
+    write!(out, "Default Amp-Out caps: ");
+    dump_amp_caps(&mut out, afg_out_caps);
+    writeln!(out, "State of AFG node {:#04x}:", afg.0);
+    dump_power_state(&mut out, bus, codec, afg)?;
+    let afg_nodes = bus.exec(make_command(codec, afg, HDA_VERB_PARAMS, HDA_PARAM_NODE_COUNT))
+        .ignore_warning()
+        .map(parse_node_count)?;
+    if afg_nodes.count == 0 {
+        writeln!(out, "Invalid AFG subtree");
+        return Ok(out.into());
+    }
+    dump_gpio(&mut out, bus, codec, afg)?;
+    let NodeDescriptor { start_id, count } = afg_nodes;
+    for n in start_id..(start_id + count) {
+        dump_node(&mut out, bus, codec, Node(n), &afg_nodes, (afg_in_caps, afg_out_caps), vendor_id)?;
+    }
+    Ok(out.into())
+}
@@ -16,7 +16,11 @@ pub const HDA_CODEC_DEVICE_PATH_GUID: uefi::Guid = uefi::Guid::from_values(
 pub struct HdaDevicePath {
     pub header: DevicePath,
     pub guid: uefi::Guid,
-    pub codec_address: u32
+    pub codec_address: u32,
+    // NID of the audio function group
+    pub function_group: u32,
+    // 0 for the analog and HDMI/DP outputs, 1 for S/PDIF
+    pub output: u32
 }
 
 #[repr(C, packed)]
@@ -136,7 +140,7 @@ pub fn concat_device_path(lhs: &DevicePath, rhs: &DevicePath) -> uefi::Result<Bo
     }
 }
 
-pub fn make_codec_subpath(codec: u32) -> CodecDevicePath {
+pub fn make_codec_subpath(codec: u32, function_group: u32, output: u32) -> CodecDevicePath {
     CodecDevicePath {
         hda: HdaDevicePath {
             header: DevicePath {
@@ -145,7 +149,9 @@ pub fn make_codec_subpath(codec: u32) -> CodecDevicePath {
                 length: u16::to_le_bytes(mem::size_of::<HdaDevicePath>() as u16)
             },
             guid: HDA_CODEC_DEVICE_PATH_GUID,
-            codec_address: codec
+            codec_address: codec,
+            function_group,
+            output
         },
         end: DevicePath {
             device_type: DeviceType::End,
@@ -0,0 +1,57 @@
+// Audio InfoFrame of HDMI and DisplayPort sinks. Kept free of
+// UEFI so that the byte layout can be checked on the host, see
+// miri-tests/display.
+use alloc::vec::Vec;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DisplaySink {
+    Hdmi,
+    DisplayPort,
+}
+
+// CEA-861-D 6.6 Audio InfoFrame
+const HDMI_INFOFRAME_TYPE_AUDIO: u8 = 0x84;
+const HDMI_INFOFRAME_AUDIO_VERSION: u8 = 0x01;
+const HDMI_INFOFRAME_AUDIO_LENGTH: u8 = 0x0a;
+const DP_INFOFRAME_AUDIO_LENGTH: u8 = 0x1b;
+const DP_INFOFRAME_AUDIO_VERSION: u8 = 0x11 << 2;
+
+// CEA-861-D Table 20 speaker allocation for the channel count
+// along with the audio sample packet slot of each channel. The
+// samples are interleaved in WAVE order: FL FR FC LFE BL BR SL
+// SR while the slots go FL FR LFE FC RL RR RLC RRC.
+pub fn hdmi_channel_allocation(channels: u32) -> (u8, &'static [u32]) {
+    match channels {
+        0..=2 => (0x00, &[0, 1]),
+        3..=4 => (0x08, &[0, 1, 4, 5]),
+        5..=6 => (0x0b, &[0, 1, 3, 2, 4, 5]),
+        _ => (0x13, &[0, 1, 3, 2, 4, 5, 6, 7]),
+    }
+}
+
+// Fill in the audio infoframe sent along with the samples
+pub fn make_audio_infoframe(sink: DisplaySink, channels: u32) -> Vec<u8> {
+    let cc = (channels - 1) as u8 & 0x7;
+    let (ca, _) = hdmi_channel_allocation(channels);
+    match sink {
+        DisplaySink::Hdmi => {
+            let mut frame = vec![
+                HDMI_INFOFRAME_TYPE_AUDIO,
+                HDMI_INFOFRAME_AUDIO_VERSION,
+                HDMI_INFOFRAME_AUDIO_LENGTH,
+                0, cc, 0, 0, ca, 0
+            ];
+            let sum = frame.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+            frame[3] = 0u8.wrapping_sub(sum);
+            frame
+        },
+        DisplaySink::DisplayPort => {
+            vec![
+                HDMI_INFOFRAME_TYPE_AUDIO,
+                DP_INFOFRAME_AUDIO_LENGTH,
+                DP_INFOFRAME_AUDIO_VERSION,
+                cc, 0, 0, ca, 0
+            ]
+        }
+    }
+}
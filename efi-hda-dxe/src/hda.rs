@@ -46,6 +46,7 @@ impl fmt::Debug for AmpGain {
     }
 }
 
+#[derive(Copy, Clone)]
 pub struct AmpCapabilities(u32);
 
 impl fmt::Debug for AmpCapabilities {
@@ -75,6 +76,18 @@ impl AmpCapabilities {
     pub fn mute(&self) -> bool {
         (self.0 >> 31) & 1 == 1
     }
+    // Step size in 1/4 dB units, offset is the 0 dB step
+    pub fn step_qdb(&self) -> i32 {
+        self.step_size() as i32 + 1
+    }
+    pub fn gain_qdb(&self, gain: u32) -> i32 {
+        (gain as i32 - self.offset() as i32) * self.step_qdb()
+    }
+    // Closest step at or below the given gain in 1/4 dB units
+    pub fn gain_from_qdb(&self, qdb: i32) -> u32 {
+        let gain = self.offset() as i32 + qdb.div_euclid(self.step_qdb());
+        gain.max(0).min(self.num_steps() as i32) as u32
+    }
 }
 
 pub struct PinCapabilities(u32);
@@ -300,3 +313,215 @@ impl GlobalCapabilities {
         (self.0 & 0x1) == 0x1
     }
 }
+
+// 3.7.1 Response Format -- unsolicited responses carry the
+// tag assigned by the Unsolicited Response verb in the top
+// bits; the rest is vendor or widget specific.
+#[derive(Copy, Clone)]
+pub struct UnsolicitedResponse(u32);
+
+impl fmt::Debug for UnsolicitedResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UnsolicitedResponse")
+            .field("tag", &self.tag())
+            .field("sub_tag", &self.sub_tag())
+            .field("eld_valid", &self.eld_valid())
+            .field("presence_detect", &self.presence_detect())
+            .finish()
+    }
+}
+
+impl UnsolicitedResponse {
+    pub fn from(response: u32) -> UnsolicitedResponse {
+        UnsolicitedResponse(response)
+    }
+    pub fn tag(&self) -> u32 {
+        (self.0 >> 26) & 0x3f
+    }
+    pub fn sub_tag(&self) -> u32 {
+        (self.0 >> 21) & 0x1f
+    }
+    // 7.3.3.14 HDMI/DP pins only
+    pub fn eld_valid(&self) -> bool {
+        (self.0 >> 1) & 1 == 1
+    }
+    // 7.3.3.14 HDMI/DP pins only
+    pub fn presence_detect(&self) -> bool {
+        self.0 & 1 == 1
+    }
+}
+
+// 7.3.3.9 Digital Converter Control, the bits follow the
+// IEC 60958 channel status
+pub struct DigitalConverterControl(u32);
+
+impl fmt::Debug for DigitalConverterControl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DigitalConverterControl")
+            .field("digital_enable", &self.digital_enable())
+            .field("validity", &self.validity())
+            .field("preemphasis", &self.preemphasis())
+            .field("copyright", &self.copyright())
+            .field("non_audio", &self.non_audio())
+            .field("professional", &self.professional())
+            .field("level", &self.level())
+            .field("category", &self.category())
+            .finish()
+    }
+}
+
+impl DigitalConverterControl {
+    pub fn from(control: u32) -> DigitalConverterControl {
+        DigitalConverterControl(control)
+    }
+    pub fn digital_enable(&self) -> bool {
+        (self.0 >> 0) & 1 == 1
+    }
+    pub fn validity(&self) -> bool {
+        (self.0 >> 1) & 1 == 1
+    }
+    pub fn preemphasis(&self) -> bool {
+        (self.0 >> 3) & 1 == 1
+    }
+    // The Copy bit is set when copyright is NOT asserted
+    pub fn copyright(&self) -> bool {
+        (self.0 >> 4) & 1 == 0
+    }
+    pub fn non_audio(&self) -> bool {
+        (self.0 >> 5) & 1 == 1
+    }
+    pub fn professional(&self) -> bool {
+        (self.0 >> 6) & 1 == 1
+    }
+    pub fn level(&self) -> bool {
+        (self.0 >> 7) & 1 == 1
+    }
+    pub fn category(&self) -> u32 {
+        (self.0 >> 8) & 0x7f
+    }
+}
+
+// 7.3.3.34 HDMI ELD Data -- the ELD memory structure is defined
+// in HDA Spec 1.0a section 7.3.3.34.1, the Short Audio
+// Descriptors are defined by CEA-861-D.
+pub struct ShortAudioDescriptor([u8; 3]);
+
+impl ShortAudioDescriptor {
+    pub fn from(bytes: [u8; 3]) -> ShortAudioDescriptor {
+        ShortAudioDescriptor(bytes)
+    }
+    pub fn format(&self) -> u32 {
+        u32::from(self.0[0] >> 3) & 0xf
+    }
+    pub fn channels(&self) -> u32 {
+        (u32::from(self.0[0]) & 0x7) + 1
+    }
+    // Bit 0 is 32 kHz and bit 6 is 192 kHz
+    pub fn rates(&self) -> u32 {
+        u32::from(self.0[1]) & 0x7f
+    }
+    // Bit 0 is 16 bits, bit 1 is 20 bits and bit 2 is 24 bits;
+    // only valid for LPCM
+    pub fn sample_sizes(&self) -> u32 {
+        u32::from(self.0[2]) & 0x7
+    }
+    // Checks an LPCM stream against the descriptor
+    pub fn supports(&self, rate: u32, bits: u32, channels: u32) -> bool {
+        let rate_bit = match rate {
+            32000 => 0,
+            44100 => 1,
+            48000 => 2,
+            88200 => 3,
+            96000 => 4,
+            176400 => 5,
+            192000 => 6,
+            _ => return false,
+        };
+        let size_bit = match bits {
+            16 => 0,
+            20 => 1,
+            24 => 2,
+            _ => return false,
+        };
+        channels <= self.channels() &&
+            (self.rates() >> rate_bit) & 1 == 1 &&
+            (self.sample_sizes() >> size_bit) & 1 == 1
+    }
+}
+
+impl fmt::Debug for ShortAudioDescriptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ShortAudioDescriptor")
+            .field("format", &self.format())
+            .field("channels", &self.channels())
+            .field("rates", &self.rates())
+            .field("sample_sizes", &self.sample_sizes())
+            .finish()
+    }
+}
+
+pub struct Eld<'a>(&'a [u8]);
+
+impl<'a> Eld<'a> {
+    const HEADER_SIZE: usize = 4;
+    const MONITOR_NAME_OFFSET: usize = 20;
+    const SAD_SIZE: usize = 3;
+
+    // Returns None if the buffer is too short for the
+    // descriptors it claims to contain
+    pub fn from(bytes: &'a [u8]) -> Option<Eld<'a>> {
+        if bytes.len() < Eld::MONITOR_NAME_OFFSET {
+            return None;
+        }
+        let eld = Eld(bytes);
+        let baseline_end = Eld::HEADER_SIZE + eld.baseline_length();
+        let sads_end = eld.sads_offset() + eld.sad_count() * Eld::SAD_SIZE;
+        if baseline_end > bytes.len() || sads_end > baseline_end {
+            return None;
+        }
+        Some(eld)
+    }
+    pub fn version(&self) -> u32 {
+        u32::from(self.0[0] >> 3)
+    }
+    pub fn baseline_length(&self) -> usize {
+        usize::from(self.0[2]) * 4
+    }
+    pub fn monitor_name_length(&self) -> usize {
+        usize::from(self.0[4] & 0x1f)
+    }
+    pub fn sad_count(&self) -> usize {
+        usize::from(self.0[5] >> 4)
+    }
+    // 0 is HDMI and 1 is DisplayPort
+    pub fn connection_type(&self) -> u32 {
+        u32::from(self.0[5] >> 2) & 0x3
+    }
+    pub fn speaker_allocation(&self) -> u32 {
+        u32::from(self.0[7]) & 0x7f
+    }
+    pub fn monitor_name(&self) -> &'a [u8] {
+        &self.0[Eld::MONITOR_NAME_OFFSET..self.sads_offset()]
+    }
+    pub fn sads(&self) -> impl Iterator<Item=ShortAudioDescriptor> + 'a {
+        let offset = self.sads_offset();
+        self.0[offset..offset + self.sad_count() * Eld::SAD_SIZE]
+            .chunks(Eld::SAD_SIZE)
+            .map(|sad| ShortAudioDescriptor::from([sad[0], sad[1], sad[2]]))
+    }
+    fn sads_offset(&self) -> usize {
+        Eld::MONITOR_NAME_OFFSET + self.monitor_name_length()
+    }
+}
+
+impl<'a> fmt::Debug for Eld<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Eld")
+            .field("version", &self.version())
+            .field("monitor_name", &core::str::from_utf8(self.monitor_name()).unwrap_or("?"))
+            .field("connection_type", &self.connection_type())
+            .field("speaker_allocation", &self.speaker_allocation())
+            .field("sad_count", &self.sad_count())
+            .finish()
+    }
+}
@@ -66,6 +66,14 @@ mod driver_binding;
 mod hda;
 use hda::*;
 
+mod codec_dump;
+
+mod display;
+use display::*;
+
+mod patch;
+use patch::PatchCodec;
+
 mod iobase;
 use iobase::*;
 
@@ -87,6 +95,33 @@ macro_rules! static_assert {
 // Timeout in microseconds
 const INFINITY: u64 = !0;
 
+// Codec addresses are 4 bits wide
+const HDA_MAX_CODECS: u32 = 16;
+
+// Unsolicited responses that are not dispatched in time are
+// dropped, oldest first
+const UNSOLICITED_QUEUE_DEPTH: usize = 64;
+const UNSOLICITED_POLL_PERIOD_MS: u64 = 100;
+
+// How often jack presence is checked during playback
+const JACK_POLL_PERIOD_MS: u64 = 200;
+// Extra time given to the stream to report IOC for the
+// last sample before playback is stopped anyway
+const STREAM_END_TIMEOUT_MS: u64 = 500;
+// FIFO and descriptor errors a stream is reset for before
+// playback is given up
+const STREAM_MAX_RECOVERIES: usize = 3;
+
+// Number of polls in a row the DMA position may stay the same
+// before it is checked against LPIB
+const DMA_POSITION_STALE_POLLS: usize = 2;
+
+// Function groups are put into D3 once no stream has been
+// played for this long
+const POWER_IDLE_TIMEOUT_MS: u64 = 5000;
+// Time given to a node to report the requested power state
+const POWER_STATE_TIMEOUT_MS: u64 = 100;
+
 /// PCI Configuration Space
 /// Section 1.1, Intel I/O Controller Hub 7 Family External Design Specification, April 2005
 const PCI_VID: u32      = 0x0;                      // ro, u16
@@ -102,8 +137,24 @@ const PCI_LT: u32       = 0xd;                      // ro, u8
 const PCI_HEADTYP: u32  = 0xe;                      // ro, u8
 const PCI_HDRBARL: u32  = 0x10;                     // rw, u32
 const PCI_HDRBARU: u32  = 0x14;                     // rw, u32
+const PCI_SVID: u32     = 0x2c;                     // rwo, u16
+const PCI_SID: u32      = 0x2e;                     // rwo, u16
 const PCI_INT_LN: u32   = 0x3c;                     // rw, u8
 const PCI_INT_PN: u32   = 0x3d;                     // ro, u8
+const PCI_TCSEL: u32    = 0x44;                     // rw, u8
+const PCI_TCSEL_MASK: u8 = bitspan(2, 0) as u8;
+
+/// Vendor specific snoop control
+const PCI_ATI_MISC_CNTR2: u32 = 0x42;               // rw, u8
+const PCI_ATI_SNOOP_MASK: u8 = bitspan(2, 0) as u8;
+const PCI_ATI_SNOOP_ENABLE: u8 = 0x2;
+const PCI_NVIDIA_TRANSREG: u32 = 0x4e;              // rw, u8
+const PCI_NVIDIA_COHBITS_MASK: u8 = bitspan(3, 0) as u8;
+const PCI_NVIDIA_ISTRM_COH: u32 = 0x4d;             // rw, u8
+const PCI_NVIDIA_OSTRM_COH: u32 = 0x4c;             // rw, u8
+const PCI_NVIDIA_COH_BIT: u8 = BIT0 as u8;
+const PCI_INTEL_DEVC: u32 = 0x78;                   // rw, u16
+const PCI_INTEL_DEVC_NOSNOOP_BIT: u16 = BIT11 as u16;
 
 const BIT0: u32 = 0b1;
 const BIT1: u32 = 0b10;
@@ -205,6 +256,7 @@ const PCI_CORBSIZE_SZCAP_MASK: u8 = bitspan(7, 4) as u8;
 const PCI_GCTL_RST_BIT: u32 = BIT0;
 const PCI_GCTL_UNSOLICITED_BIT: u32 = BIT8;
 const PCI_SDCTL8_STREAM_MASK: u8 = (BIT7 | BIT6 | BIT5 | BIT4) as u8;
+const PCI_SDCTL8_DIR_BIT: u8 = BIT3 as u8;
 const PCI_SDCTL8_STRIPE_MASK: u8 = (BIT1 | BIT0) as u8;
 const PCI_SDCTL16_RSVDP_MASK: u16 = bitspan(15, 5) as u16;
 const PCI_SDCTL16_DEI_BIT: u16 = BIT4 as u16;
@@ -242,6 +294,7 @@ const PCI_IRS_ICB_BIT: u16 = BIT0 as u16;
 const PCI_IRS_IRV_BIT: u16 = BIT1 as u16;
 
 const PCI_RIRB_EX_UNSOLICITED_BIT: u32 = BIT4;
+const PCI_RIRB_EX_CODEC_MASK: u32 = bitspan(3, 0) as u32;
 
 const PCI_CORBSIZE_CAP_2_BIT: u8 = BIT4 as u8;
 const PCI_CORBSIZE_CAP_16_BIT: u8 = BIT5 as u8;
@@ -251,6 +304,8 @@ const PCI_RIRBSIZE_CAP_2_BIT: u8 = BIT4 as u8;
 const PCI_RIRBSIZE_CAP_16_BIT: u8 = BIT5 as u8;
 const PCI_RIRBSIZE_CAP_256_BIT: u8 = BIT6 as u8;
 
+const HDA_MAX_STREAM_TAG: u32 = 15;
+
 const PCI_SDCTL8_STREAM_1_MASK: u8 = 1 << 4;
 const PCI_SDCTL8_STREAM_2_MASK: u8 = 2 << 4;
 const PCI_SDCTL8_STREAM_3_MASK: u8 = 3 << 4;
@@ -275,6 +330,12 @@ const PCI_INTCTL_SIE_MASK: u32 = !(PCI_INTCTL_CIE_BIT | PCI_INTCTL_GIE_BIT);
 const PCI_INTCTL_CIE_BIT: u32 = BIT30;
 const PCI_INTCTL_GIE_BIT: u32 = BIT31;
 
+// EFI_PCI_IO_ATTRIBUTE_DUAL_ADDRESS_CYCLE
+const PCI_IO_ATTRIBUTE_DUAL_ADDRESS_CYCLE: u64 = 0x8000;
+
+const PCI_DPLBASE_ENABLE_BIT: u32 = BIT0;
+const PCI_DPLBASE_MASK: u32 = !(bitspan(6, 0) as u32);
+
 // CORBSZ sizes
 const PCI_CORBSIZE_SZ_256: u8 = 0b10;
 const PCI_CORBSIZE_SZ_16: u8 = 0b01;
@@ -437,8 +498,90 @@ fn in_stream(gcap: &GlobalCapabilities, index: usize) -> StreamRegisterSet {
     StreamRegisterSet::new(index as u32)
 }
 
-fn out_stream_1(device: &DeviceContext) -> StreamRegisterSet {
-    StreamRegisterSet::new(device.in_streams)
+// Bidirectional stream descriptors follow the output ones
+fn bd_stream(gcap: &GlobalCapabilities, index: usize) -> StreamRegisterSet {
+    StreamRegisterSet::new(u32::from(gcap.in_streams()) + u32::from(gcap.out_streams()) + index as u32)
+}
+
+// SDCTL.DIR selects the direction of a bidirectional stream
+// and may only be changed while the stream is stopped
+// (3.3.35). Other descriptors ignore it.
+fn stream_set_direction(stream: &StreamRegisterSet, pci: &PciIO, output: bool) -> uefi::Result {
+    if output {
+        stream.ctl8().or(pci, PCI_SDCTL8_DIR_BIT)
+    } else {
+        stream.ctl8().and(pci, !PCI_SDCTL8_DIR_BIT)
+    }
+}
+
+// Output stream descriptor handed to the child for the
+// running playback by StreamAllocator
+fn device_stream(device: &DeviceContext) -> StreamRegisterSet {
+    let index = device.stream.map_or(device.in_streams, |stream| stream.index);
+    StreamRegisterSet::new(index)
+}
+
+#[derive(Copy, Clone, Debug)]
+struct StreamSlot {
+    // Index of the stream descriptor among all of them
+    index: u32,
+    tag: u8,
+    bidirectional: bool,
+}
+
+impl StreamSlot {
+    // Tag as found in SDCTL bits 23:20 and in the converter
+    // stream verb (7.3.3.11)
+    fn tag_mask(&self) -> u8 {
+        self.tag << 4
+    }
+}
+
+// Output stream descriptors and stream tags of a controller.
+// Children only hold them while playing so that independent
+// children can play at the same time. Bidirectional
+// descriptors are used once the output ones run out.
+struct StreamAllocator {
+    in_streams: u32,
+    out_streams: u32,
+    bd_streams: u32,
+    // Bit n is set if stream descriptor n is in use
+    descriptors: u64,
+    // Bit n is set if stream tag n is in use
+    tags: u32,
+}
+
+impl StreamAllocator {
+    fn new(gcap: &GlobalCapabilities) -> StreamAllocator {
+        StreamAllocator {
+            in_streams: u32::from(gcap.in_streams()),
+            out_streams: u32::from(gcap.out_streams()),
+            bd_streams: u32::from(gcap.bd_streams()),
+            descriptors: 0,
+            tags: 0
+        }
+    }
+
+    fn alloc(&mut self) -> Option<StreamSlot> {
+        let first_bd = self.in_streams + self.out_streams;
+        let index = (self.in_streams..first_bd + self.bd_streams)
+            .find(|index| (self.descriptors & (1 << index)) == 0)?;
+        // Tag 0 is reserved (3.3.35)
+        let tag = (1..=HDA_MAX_STREAM_TAG)
+            .find(|tag| (self.tags & (1 << tag)) == 0)?;
+        self.descriptors |= 1 << index;
+        self.tags |= 1 << tag;
+        Some(StreamSlot {
+            index,
+            tag: tag as u8,
+            bidirectional: index >= first_bd
+        })
+    }
+
+    fn free(&mut self, stream: StreamSlot) {
+        self.descriptors &= !(1 << stream.index);
+        self.tags &= !(1 << u32::from(stream.tag));
+    }
 }
 
 #[repr(C, packed)]
@@ -449,45 +592,343 @@ struct Descriptor {
     control: u32
 }
 
+// Maximum count is 256 according to the spec
+const BDL_MAX_ENTRIES: usize = 256;
+
+// Sample storage shared by all BDL entries, the geometry
+// is chosen at runtime per controller (see BdlLimits)
+const BDL_MAX_SAMPLES: usize = 1 << 16;
+
 // Minimum size is not specified but the minimum alignment
 // is 128 bytes
-const BUFFER_SIZE: usize = 2048;
-
-// Maximum count is 256 according to the spec. Due to a bug
-// in vbox the maximum buffers is 8 for 44100hz or 4 for
-// 22050hz. The idea for workaround follows. While trying to
-// split the DMA timer intervals the vbox r3vm goes into
-// infinite loop in hdaR3StreamAddScheduleItem() but only if
-// the guest driver supplies more than 200 msec BDL. The
-// number of buffers is choosen to workaround vbox bug while
-// running our test app (which is 44100hz and 22050hz).
-const BUFFER_COUNT: usize = 8;
+const BDL_ENTRY_ALIGN: usize = 128 / mem::size_of::<i16>();
 
-#[repr(C, align(128))]
-#[derive(Copy, Clone)]
-struct SampleBuffer {
-    samples: [i16; BUFFER_SIZE]
-}
+// Samples generated for a tone, the buffer is cycled
+const TONE_BUFFER_SIZE: usize = 2048;
+
+// Gain of every amplifier on an output path in 1/4 dB. Unity
+// gain keeps the level independent of how many amplifiers the
+// path goes through.
+const PATH_AMP_GAIN_QDB: i32 = 0;
 
 // The alignment of 128 bytes is mandatory per the spec
 #[repr(C, align(128))]
 #[derive(Copy, Clone)]
 struct BufferDescriptorListWithBuffers {
-    descriptors: [Descriptor; BUFFER_COUNT],
-    buffers: [SampleBuffer; BUFFER_COUNT]
+    descriptors: [Descriptor; BDL_MAX_ENTRIES],
+    samples: [i16; BDL_MAX_SAMPLES]
+}
+
+// Per controller limits of the cyclic buffer
+#[derive(Copy, Clone, Debug)]
+struct BdlLimits {
+    // Maximum number of BDL entries
+    entries: usize,
+    // Samples per entry, multiple of BDL_ENTRY_ALIGN
+    entry_size: usize,
+    // Maximum duration of the whole cyclic buffer
+    max_ms: Option<u64>,
+}
+
+const BDL_DEFAULT_LIMITS: BdlLimits = BdlLimits {
+    entries: 16,
+    entry_size: 2048,
+    max_ms: None,
+};
+
+// Geometry of the cyclic buffer used by a single playback
+#[derive(Copy, Clone, Debug)]
+struct BdlLayout {
+    entries: usize,
+    entry_size: usize,
+    // Length of the cyclic buffer in samples
+    cbl: usize,
+}
+
+impl BdlLayout {
+    fn select(limits: &BdlLimits, total: usize, sampling_rate: u32, channel_count: u8) -> BdlLayout {
+        let mut entry_size = limits.entry_size;
+        let mut entries = limits.entries
+            .min(BDL_MAX_ENTRIES)
+            .min(BDL_MAX_SAMPLES / entry_size);
+        if let Some(max_ms) = limits.max_ms {
+            let samples = max_ms as usize * sampling_rate as usize * usize::from(channel_count) / 1000;
+            entries = entries.min(samples / entry_size);
+            // LVI must be at least 1 (3.3.41), at low rates
+            // shorten the entries rather than exceed max_ms
+            if entries < 2 {
+                entry_size = (samples / 2 / BDL_ENTRY_ALIGN * BDL_ENTRY_ALIGN).max(BDL_ENTRY_ALIGN);
+            }
+        }
+        let entries = entries.max(2);
+        let padded = div_round_up(total, BDL_ENTRY_ALIGN) * BDL_ENTRY_ALIGN;
+        if padded >= entries * entry_size {
+            return BdlLayout {
+                entries,
+                entry_size,
+                cbl: entries * entry_size
+            };
+        }
+        // Short payloads get a cyclic buffer ending right after
+        // the last sample
+        let cbl = padded.max(2 * BDL_ENTRY_ALIGN);
+        let entry_size = if cbl <= entry_size {
+            div_round_up(cbl / 2, BDL_ENTRY_ALIGN) * BDL_ENTRY_ALIGN
+        } else {
+            entry_size
+        };
+        BdlLayout {
+            entries: div_round_up(cbl, entry_size),
+            entry_size,
+            cbl
+        }
+    }
+
+    fn entry_offset(&self, index: usize) -> usize {
+        index * self.entry_size
+    }
+
+    fn entry_length(&self, index: usize) -> usize {
+        self.entry_size.min(self.cbl - self.entry_offset(index))
+    }
 }
 
 impl Mappable for BufferDescriptorListWithBuffers {}
 
+// Stream descriptors are 4 bits wide for input and output and 5
+// bits wide for bidirectional streams
+const HDA_MAX_STREAMS: usize = 15 + 15 + 30;
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct PositionEntry {
+    position: u32,
+    reserved: u32
+}
+
+// 3.6.1 DMA Position in Current Buffer -- the controller writes
+// the LPIB of every stream descriptor into this buffer, in the
+// order of the descriptors
+#[repr(C, align(128))]
+#[derive(Copy, Clone)]
+struct PositionBuffer {
+    entries: [PositionEntry; HDA_MAX_STREAMS]
+}
+
+impl Mappable for PositionBuffer {}
+
+struct DmaPositions<'a> {
+    pci: &'a PciIO,
+    dma: Option<DmaBuffer<'a, PositionBuffer>>,
+    // Cross-check stale positions against LPIB
+    verify: bool,
+}
+
+impl<'a> Drop for DmaPositions<'a> {
+    fn drop(&mut self) {
+        if let Err(error) = self.disable() {
+            warn!("failed to disable DMA position buffer: {:?}", error.status());
+        }
+        self.dma.take();
+    }
+}
+
+impl<'a> DmaPositions<'a> {
+    fn new(pci: &'a PciIO, verify: bool) -> uefi::Result<DmaPositions<'a>> {
+        let dma = DmaBuffer::<PositionBuffer>::new(pci, "DMA positions")
+            .ignore_warning()?;
+        let address = dma.device_address();
+        if ((address & 0xffff_ffff) as u32 & !PCI_DPLBASE_MASK) != 0 {
+            error!("DMA position buffer address {:#x} is not supported", address);
+            return Err(uefi::Status::UNSUPPORTED.into());
+        }
+        bus_check_dma_address(pci, address, mem::size_of::<PositionBuffer>())?;
+        let positions = DmaPositions {
+            pci,
+            dma: Some(dma),
+            verify
+        };
+        positions.enable()?;
+        Ok(positions.into())
+    }
+
+    // Also needed after a link reset which clears DPLBASE
+    fn enable(&self) -> uefi::Result {
+        let address = self.dma.as_ref().unwrap().device_address();
+        DPUBASE.write(self.pci, ((address >> 32) & 0xffff_ffff) as u32)?;
+        DPLBASE.write(self.pci, ((address & 0xffff_ffff) as u32 & PCI_DPLBASE_MASK) | PCI_DPLBASE_ENABLE_BIT)?;
+        Ok(().into())
+    }
+
+    fn disable(&self) -> uefi::Result {
+        DPLBASE.and(self.pci, !PCI_DPLBASE_ENABLE_BIT)?;
+        Ok(().into())
+    }
+
+    // The entry keeps the position of the former run until the
+    // controller updates it
+    fn clear(&mut self, stream: &StreamRegisterSet) {
+        let positions = unsafe { &mut *self.dma.as_mut().unwrap().get_mut() };
+        let entry_ptr = (&mut positions.entries[stream.index as usize].position) as *mut u32;
+        // SAFETY: pointer is valid and properly aligned
+        unsafe {
+            core::ptr::write_volatile(entry_ptr, 0);
+        }
+        sfence();
+    }
+
+    fn read(&self, stream: &StreamRegisterSet) -> u32 {
+        let positions = unsafe { &*self.dma.as_ref().unwrap().get() };
+        lfence();
+        let entry_ptr = (&positions.entries[stream.index as usize].position) as *const u32;
+        // SAFETY: pointer is valid, properly aligned and
+        //         initialized
+        unsafe {
+            core::ptr::read_volatile(entry_ptr)
+        }
+    }
+}
+
+// Position of a running stream within its cyclic buffer. It is
+// read from the DMA position buffer if there is one, LPIB is
+// only read if the buffer looks stale and is used from then on
+// if they disagree. Controllers known to keep the buffer up
+// to date skip the check.
+struct StreamPosition<'a, 'b> {
+    stream: StreamRegisterSet,
+    positions: Option<&'b mut DmaPositions<'a>>,
+    cbl: u32,
+    last: u32,
+    unchanged: usize,
+}
+
+impl<'a, 'b> StreamPosition<'a, 'b> {
+    // Must be called before the stream is started
+    fn new(stream: StreamRegisterSet, positions: Option<&'b mut DmaPositions<'a>>, cbl: u32) -> StreamPosition<'a, 'b> {
+        let mut positions = positions;
+        if let Some(positions) = positions.as_mut() {
+            positions.clear(&stream);
+        }
+        StreamPosition {
+            stream,
+            positions,
+            cbl,
+            last: 0,
+            unchanged: 0
+        }
+    }
+
+    // Must be called before the stream is started again
+    fn restart(&mut self) {
+        if let Some(positions) = self.positions.as_mut() {
+            positions.clear(&self.stream);
+        }
+        self.last = 0;
+        self.unchanged = 0;
+    }
+
+    fn read(&mut self, pci: &PciIO) -> uefi::Result<u32> {
+        let position = match self.positions.as_ref() {
+            Some(positions) => positions.read(&self.stream),
+            None => return self.stream.lpib().read(pci)
+        };
+        let verify = self.positions.as_ref().map_or(false, |positions| positions.verify);
+        self.unchanged = if position == self.last { self.unchanged + 1 } else { 0 };
+        // A position past the cyclic buffer is never valid, a
+        // stale one is only cross-checked against LPIB on request
+        if position >= self.cbl {
+            let lpib = self.stream.lpib().read(pci)
+                .ignore_warning()?;
+            warn!("DMA position {:#x} is past the cyclic buffer, LPIB is {:#x}, falling back to LPIB", position, lpib);
+            self.positions = None;
+            return Ok(lpib.into());
+        }
+        if verify && (self.unchanged >= DMA_POSITION_STALE_POLLS) {
+            let lpib = self.stream.lpib().read(pci)
+                .ignore_warning()?;
+            if lpib != position {
+                warn!("DMA position {:#x} looks stale, LPIB is {:#x}, falling back to LPIB", position, lpib);
+                self.positions = None;
+                return Ok(lpib.into());
+            }
+            self.unchanged = 0;
+        }
+        self.last = position;
+        Ok(position.into())
+    }
+}
+
 struct DeviceContext {
     controller_handle: Handle,
     child_handle: Handle,
     driver_handle: Handle,
     audio_interface: Box<SimpleAudioOut>,
+    info_interface: Box<HdaCodecInfo>,
     in_streams: u32,
     out_streams: u32,
     codec: Codec,
+    // Audio function group driven by this child
+    afg: Node,
+    // Pins of the function group driven by this child
+    output: OutputKind,
     device_path: Box<DevicePath>,
+    // Pins that report presence changes via unsolicited responses
+    jacks: alloc::vec::Vec<JackState>,
+    jacks_changed: bool,
+    // Jacks are not sensed while the group is in D3 and have
+    // to be read again once it is powered up
+    jacks_stale: bool,
+    // Output pins configured by the last codec_setup_stream()
+    routing: Option<OutputRouting>,
+    // Held during playback only
+    stream: Option<StreamSlot>,
+    // S/PDIF channel status (SPDIF_*) set via HdaCodecInfo
+    spdif_control: u32,
+}
+
+struct OutputRouting {
+    // Stream format the DACs are programmed with
+    format: u16,
+    routes: alloc::vec::Vec<OutputRoute>,
+    // Widgets with power control left in D0
+    powered: alloc::vec::Vec<Node>,
+    // Widgets on the paths of the routes
+    nodes: alloc::vec::Vec<Node>,
+}
+
+// S/PDIF pins are exposed as a child of their own, analog and
+// HDMI/DP pins share the other one
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum OutputKind {
+    Primary = 0,
+    Spdif = 1,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct OutputRoute {
+    pin: Node,
+    // Headphone jack with reliable presence detection
+    headphones: bool,
+    // HDMI or DisplayPort pin, presence means a monitor is connected
+    display: Option<DisplaySink>,
+    presence: Option<bool>,
+    eld_valid: bool,
+    enabled: Option<bool>,
+    amps: WidgetAmps,
+}
+
+impl OutputRoute {
+    fn is_headphones(&self) -> bool {
+        self.headphones && self.presence.unwrap_or(false)
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct JackState {
+    node: Node,
+    tag: u32,
+    presence: bool,
+    eld_valid: bool,
 }
 
 struct EventGuard (uefi::Event);
@@ -521,6 +962,28 @@ impl DerefMut for EventGuard {
 
 static mut DEVICE_CONTEXTS: alloc::vec::Vec<Box<DeviceContext>> = alloc::vec::Vec::new();
 
+// SimpleAudioOut and HdaCodecInfo calls in progress. They hold
+// references into the bus and device contexts across waits, so
+// the unsolicited timer leaves the contexts alone meanwhile.
+static CONTEXT_USERS: atomic::AtomicUsize = atomic::AtomicUsize::new(0);
+
+// Taken first thing by every protocol call that looks up a
+// context and dropped after the last use of it
+struct ContextUser;
+
+impl ContextUser {
+    fn enter() -> ContextUser {
+        CONTEXT_USERS.fetch_add(1, atomic::Ordering::SeqCst);
+        ContextUser
+    }
+}
+
+impl Drop for ContextUser {
+    fn drop(&mut self) {
+        CONTEXT_USERS.fetch_sub(1, atomic::Ordering::SeqCst);
+    }
+}
+
 impl DeviceContext {
     // BootServices reference is only needed to inherit its lifetime
     fn from_protocol(_bs: &uefi::table::boot::BootServices, raw: *const SimpleAudioOut) -> Option<&DeviceContext> {
@@ -532,6 +995,16 @@ impl DeviceContext {
         }
     }
 
+    // BootServices reference is only needed to inherit its lifetime
+    fn from_info_protocol_mut(_bs: &uefi::table::boot::BootServices, raw: *mut HdaCodecInfo) -> Option<&mut DeviceContext> {
+        unsafe {
+            DEVICE_CONTEXTS
+                .iter_mut()
+                .find(|context| core::ptr::eq(&*context.info_interface, raw))
+                .map(alloc::boxed::Box::as_mut)
+        }
+    }
+
     // BootServices reference is only needed to inhert its lifetime
     fn from_protocol_mut(_bs: &uefi::table::boot::BootServices, raw: *mut SimpleAudioOut) -> Option<&mut DeviceContext> {
         unsafe {
@@ -558,108 +1031,536 @@ impl DeviceContext {
     }
 }
 
-fn bus_trace_registers(pci: &PciIO) -> uefi::Result {
-    let gctl = GCTL.read(pci).ignore_warning()?;
-    let statests = STATESTS.read(pci).ignore_warning()?;
-    let intsts = INTSTS.read(pci).ignore_warning()?;
-    info!("GCTL:{:#010x} STATESTS:{:#010x} INTSTS:{:#010x}",
-          gctl, statests, intsts);
-    uefi::Status::SUCCESS.into()
+// Controller wide state. The command rings must keep running
+// between SimpleAudioOut calls or unsolicited responses would
+// be lost.
+struct BusContext {
+    controller_handle: Handle,
+    pci: &'static PciIO,
+    // PCI I/O attribute enabled by us that must be disabled on stop
+    dual_address_cycle: bool,
+    bus: BusIoImpl<'static>,
+    quirk: &'static ControllerQuirk,
+    // None if the controller could not be given one
+    positions: Option<DmaPositions<'static>>,
+    streams: StreamAllocator,
+    // Audio function groups that have children
+    groups: alloc::vec::Vec<GroupPower>,
+    // Patches read by hda_start(), applied again after a link reset
+    patches: alloc::vec::Vec<PatchCodec>,
+    // Link is held in reset until the next stream
+    link_reset: bool,
+    // Codecs lost their unsolicited response enables with the link
+    restore_unsolicited: bool,
 }
 
-fn bus_clear_interrupt(pci: &PciIO) -> uefi::Result {
-    let gcap = GCAP.read(pci)
-        .ignore_warning()
-        .map(GlobalCapabilities::from)?;
-    for stream in 0..gcap.in_streams() {
-        in_stream(&gcap, stream as usize)
-            .sts()
-            .write(pci, PCI_SDSTS_INT_MASK)?;
-    }
-    for stream in 0..gcap.out_streams() {
-        out_stream(&gcap, stream as usize)
-            .sts()
-            .write(pci, PCI_SDSTS_INT_MASK)?;
-    }
-    STATESTS.write(pci, PCI_STATESTS_INT_MASK)?;
-    RIRBSTS.write(pci, PCI_RIRBSTS_INT_MASK)?;
-    INTSTS.write(pci, PCI_INTSTS_CIS_BIT | PCI_INTSTS_SIS_MASK)?;
-    uefi::Status::SUCCESS.into()
+// Power state of an audio function group. Children of the
+// same group share it.
+struct GroupPower {
+    codec: Codec,
+    afg: Node,
+    powered: bool,
+    // Unsolicited timer periods left until the group is powered
+    // down, None while a stream is running or if it is down
+    idle_polls: Option<u64>,
+    // Running streams of the children
+    streams: usize,
+    // Widgets on the paths of the running streams
+    busy: alloc::vec::Vec<Node>,
 }
 
-fn bus_trace_config(pci: &PciIO) -> uefi::Result {
-    let gcap = GCAP.read(pci)
-        .ignore_warning()
-        .map(GlobalCapabilities::from)?;
-    info!("HDA controller Global Capabilities: {:#?}", gcap);
-    uefi::Status::SUCCESS.into()
+impl GroupPower {
+    fn new(codec: Codec, afg: Node) -> GroupPower {
+        // Left as found until a stream has run so that jack
+        // detection keeps working if nothing is ever played
+        GroupPower {
+            codec,
+            afg,
+            powered: true,
+            idle_polls: None,
+            streams: 0,
+            busy: alloc::vec::Vec::new()
+        }
+    }
 }
 
-fn bus_reset(pci: &PciIO) -> uefi::Result<u16> {
-    bus_stop(pci)?;
-    let codec_mask = bus_start(pci).ignore_warning()?;
-    bus_trace_config(pci)?;
-    Ok(codec_mask.into())
-}
+static mut BUS_CONTEXTS: alloc::vec::Vec<Box<BusContext>> = alloc::vec::Vec::new();
 
-fn bus_start(pci: &PciIO) -> uefi::Result<u16> {
-    let codec_mask = bus_reset_link(pci).ignore_warning()?;
-    bus_clear_interrupt(pci)?;
-    bus_trace_registers(pci)?;
-    INTCTL.or(pci, PCI_INTCTL_CIE_BIT | PCI_INTCTL_SIE_MASK)?;
-    Ok(codec_mask.into())
-}
+// Periodic timer that dispatches unsolicited responses of all
+// buses. Exists as long as there is at least one bus.
+static mut UNSOLICITED_TIMER: Option<EventGuard> = None;
 
-fn bus_stop(pci: &PciIO) -> uefi::Result {
-    let gcap = GCAP.read(pci)
-        .ignore_warning()
-        .map(GlobalCapabilities::from)?;
-    // disabling interrupts for each stream descriptors is
-    // only necessary when we taking ownership of the bus
-    // link
-    for stream in 0..gcap.in_streams() {
-        in_stream(&gcap, stream as usize)
-            .ctl16()
-            .and(pci, !PCI_SDCTL16_INT_MASK)?;
+impl BusContext {
+    fn new(controller_handle: Handle, pci: &'static PciIO, quirk: &'static ControllerQuirk) -> uefi::Result<Box<BusContext>> {
+        // Must be done before anything is mapped
+        let dual_address_cycle = match bus_enable_dual_address_cycle(pci).ignore_warning() {
+            Ok(true) => Some(DualAddressCycleGuard { pci }),
+            Ok(false) => None,
+            Err(error) => {
+                warn!("failed to enable dual address cycle: {:?}", error.status());
+                None
+            }
+        };
+        let bus = make_bus_io(pci, quirk)
+            .ignore_warning()?;
+        let gcap = GCAP.read(pci)
+            .ignore_warning()
+            .map(GlobalCapabilities::from)?;
+        let positions = match quirk.position_fix {
+            PositionFix::Lpib => None,
+            fix => match DmaPositions::new(pci, fix == PositionFix::Auto).ignore_warning() {
+                Ok(positions) => Some(positions),
+                Err(error) => {
+                    warn!("DMA position buffer is not available, using LPIB: {:?}", error.status());
+                    None
+                }
+            }
+        };
+        Ok(Box::new(BusContext {
+            controller_handle,
+            pci,
+            dual_address_cycle: dual_address_cycle.map_or(false, DualAddressCycleGuard::disarm),
+            bus,
+            quirk,
+            positions,
+            streams: StreamAllocator::new(&gcap),
+            groups: alloc::vec::Vec::new(),
+            patches: alloc::vec::Vec::new(),
+            link_reset: false,
+            restore_unsolicited: false
+        }).into())
     }
-    for stream in 0..gcap.out_streams() {
-        out_stream(&gcap, stream as usize)
-            .ctl16()
-            .and(pci, !PCI_SDCTL16_INT_MASK)?;
+
+    fn group_mut(&mut self, codec: Codec, afg: Node) -> Option<&mut GroupPower> {
+        self.groups
+            .iter_mut()
+            .find(|group| group.codec.0 == codec.0 && group.afg == afg)
     }
-    // disable SIE and GIE for all streams
-    INTCTL.and(pci, !(PCI_INTCTL_SIE_MASK | PCI_INTCTL_CIE_BIT | PCI_INTCTL_GIE_BIT))?;
-    bus_clear_interrupt(pci)?;
-    uefi::Status::SUCCESS.into()
-}
 
-fn bus_reset_link(pci: &PciIO) -> uefi::Result<u16> {
-    // disable interrupts is only necessary because someone might configured them (not me)
-    STATESTS.write(pci, PCI_STATESTS_INT_MASK)?;
-    // enter bus reset state
-    GCTL.and(pci, !PCI_GCTL_RST_BIT)?;
-    GCTL.wait(pci, INFINITY, PCI_GCTL_RST_BIT, 0)?;
-    // leave bus reset state
-    GCTL.or(pci, PCI_GCTL_RST_BIT)?;
-    GCTL.wait(pci, INFINITY, PCI_GCTL_RST_BIT, PCI_GCTL_RST_BIT)?;
-    // GCTL.RST is sticky so double check the bus state
-    let gctl = GCTL.read(pci).ignore_warning()?;
-    if gctl == 0 {
-        info!("bus is not ready");
-        return Err(uefi::Status::NOT_READY.into());
+    // Must be called before a stream is set up on the group so
+    // that it is neither powered down nor the link reset under
+    // the stream
+    fn power_wake(&mut self, codec: Codec, afg: Node) -> uefi::Result {
+        {
+            // SAFETY: a second child can only start playing from
+            //         a callback while the first one is parked in
+            //         wait_for_event(), see StreamPoll. The shared
+            //         state is touched at TPL_NOTIFY to keep start
+            //         and stop out.
+            let _tpl = unsafe { boot_services().raise_tpl(uefi::table::boot::Tpl::NOTIFY) };
+            if let Some(group) = self.group_mut(codec, afg) {
+                group.streams += 1;
+                group.idle_polls = None;
+                // Powered up by codec_setup_stream()
+                group.powered = true;
+            }
+        }
+        self.link_wake()
     }
-    // TBD: detect codecs by timer or interrupt
-    let codec_mask: u16 = STATESTS.read(pci).ignore_warning()?;
-    info!("codec_mask:{:#b}", codec_mask);
-    Ok(codec_mask.into())
-}
 
-trait BusIo {
-    fn exec(&mut self, cmd: u32) -> uefi::Result<u32>;
-}
+    // Start the idle countdown of the group after its last
+    // stream ends
+    fn power_idle(&mut self, codec: Codec, afg: Node) {
+        // SAFETY: see power_wake()
+        let _tpl = unsafe { boot_services().raise_tpl(uefi::table::boot::Tpl::NOTIFY) };
+        if let Some(group) = self.group_mut(codec, afg) {
+            group.streams = group.streams.saturating_sub(1);
+            if group.streams == 0 {
+                group.idle_polls = Some(POWER_IDLE_TIMEOUT_MS / UNSOLICITED_POLL_PERIOD_MS);
+            }
+        }
+    }
 
-#[repr(C, align(128))]
-struct CommandRing {
+    fn stream_alloc(&mut self) -> Option<StreamSlot> {
+        // SAFETY: see power_wake()
+        let _tpl = unsafe { boot_services().raise_tpl(uefi::table::boot::Tpl::NOTIFY) };
+        self.streams.alloc()
+    }
+
+    fn stream_free(&mut self, stream: StreamSlot) {
+        // SAFETY: see power_wake()
+        let _tpl = unsafe { boot_services().raise_tpl(uefi::table::boot::Tpl::NOTIFY) };
+        self.streams.free(stream);
+    }
+
+    // Widgets used by other streams of the group
+    fn busy_nodes(&self, codec: Codec, afg: Node) -> alloc::vec::Vec<Node> {
+        // SAFETY: see power_wake()
+        let _tpl = unsafe { boot_services().raise_tpl(uefi::table::boot::Tpl::NOTIFY) };
+        self.groups
+            .iter()
+            .find(|group| group.codec.0 == codec.0 && group.afg == afg)
+            .map_or(alloc::vec::Vec::new(), |group| group.busy.clone())
+    }
+
+    fn claim_nodes(&mut self, codec: Codec, afg: Node, nodes: &[Node]) {
+        // SAFETY: see power_wake()
+        let _tpl = unsafe { boot_services().raise_tpl(uefi::table::boot::Tpl::NOTIFY) };
+        if let Some(group) = self.group_mut(codec, afg) {
+            group.busy.extend_from_slice(nodes);
+        }
+    }
+
+    fn release_nodes(&mut self, codec: Codec, afg: Node, nodes: &[Node]) {
+        // SAFETY: see power_wake()
+        let _tpl = unsafe { boot_services().raise_tpl(uefi::table::boot::Tpl::NOTIFY) };
+        if let Some(group) = self.group_mut(codec, afg) {
+            group.busy.retain(|node| !nodes.contains(node));
+        }
+    }
+
+    fn link_wake(&mut self) -> uefi::Result {
+        if !self.link_reset {
+            return Ok(().into());
+        }
+        info!("link_wake: bringing the link out of reset");
+        let codec_mask = bus_start(self.pci, self.quirk).ignore_warning()?;
+        self.bus.resume(self.quirk)?;
+        if let Some(positions) = self.positions.as_ref() {
+            positions.enable()?;
+        }
+        self.link_reset = false;
+        // Codecs are reset along with the link and have to be
+        // fixed up again. Unsolicited responses are enabled by
+        // the unsolicited timer as it knows the jacks.
+        for group in self.groups.iter() {
+            if (codec_mask & (1 << group.codec.0)) == 0 {
+                warn!("codec {:?} did not come back after link reset", group.codec);
+                continue;
+            }
+            if let Err(error) = codec_apply_fixups(&mut self.bus, group.codec, group.afg, self.patches.as_slice()) {
+                warn!("failed to apply fixup to {:?} of codec {:?}: {:?}", group.afg, group.codec, error.status());
+            }
+        }
+        self.restore_unsolicited = true;
+        Ok(().into())
+    }
+
+    // Only done once every group is powered down so that
+    // there is nothing to be lost but unsolicited responses
+    fn link_suspend(&mut self) -> uefi::Result {
+        info!("link_suspend: putting the idle link into reset");
+        self.bus.suspend()?;
+        if let Some(positions) = self.positions.as_ref() {
+            positions.disable()?;
+        }
+        bus_stop(self.pci)?;
+        GCTL.and(self.pci, !PCI_GCTL_RST_BIT)?;
+        GCTL.wait(self.pci, 1000, PCI_GCTL_RST_BIT, 0)?;
+        self.link_reset = true;
+        Ok(().into())
+    }
+
+    // BootServices reference is only needed to inherit its lifetime
+    fn from_controller_mut(_bs: &uefi::table::boot::BootServices, controller_handle: Handle) -> Option<&mut BusContext> {
+        unsafe {
+            BUS_CONTEXTS
+                .iter_mut()
+                .find(|context| context.controller_handle == controller_handle)
+                .map(alloc::boxed::Box::as_mut)
+        }
+    }
+
+    fn register(self: Box<BusContext>) -> uefi::Result {
+        unsafe {
+            if UNSOLICITED_TIMER.is_none() {
+                match create_unsolicited_timer().ignore_warning() {
+                    Ok(timer) => UNSOLICITED_TIMER = Some(timer),
+                    Err(error) => {
+                        if self.dual_address_cycle {
+                            bus_disable_dual_address_cycle(self.pci);
+                        }
+                        return Err(error);
+                    }
+                }
+            }
+            BUS_CONTEXTS
+                .push(self);
+        }
+        Ok(().into())
+    }
+
+    fn unregister(controller_handle: Handle) {
+        unsafe {
+            BUS_CONTEXTS
+                .retain(|context| context.controller_handle != controller_handle);
+            if BUS_CONTEXTS.is_empty() {
+                UNSOLICITED_TIMER.take();
+            }
+        }
+    }
+}
+
+fn create_unsolicited_timer() -> uefi::Result<EventGuard> {
+    // SAFETY: the notify function only touches the bus and
+    //         device contexts while no protocol call uses them
+    let event = unsafe {
+        boot_services()
+            .create_event(
+                uefi::table::boot::EventType::TIMER | uefi::table::boot::EventType::NOTIFY_SIGNAL,
+                uefi::table::boot::Tpl::CALLBACK,
+                Some(hda_unsolicited_notify))
+    }
+        .ignore_warning()
+        .map(EventGuard::wrap)?;
+    boot_services()
+        .set_timer(
+            *event,
+            uefi::table::boot::TimerTrigger::Periodic(milliseconds_to_timer_period(UNSOLICITED_POLL_PERIOD_MS)))?;
+    Ok(event.into())
+}
+
+fn hda_unsolicited_notify(_event: uefi::Event) {
+    // The responses stay queued and a running stream polls the
+    // jacks of its own device, see device_poll_unsolicited()
+    if CONTEXT_USERS.load(atomic::Ordering::SeqCst) != 0 {
+        return;
+    }
+    // SAFETY: no protocol call is in progress. They are only to
+    //         be called at TPL_APPLICATION or TPL_CALLBACK and so
+    //         cannot start before we return, start and stop run
+    //         at TPL_NOTIFY and do not wait.
+    let (buses, devices) = unsafe { (&mut BUS_CONTEXTS, &mut DEVICE_CONTEXTS) };
+    for bus_context in buses.iter_mut() {
+        bus_power_poll(bus_context, devices);
+        if bus_context.link_reset {
+            continue;
+        }
+        if mem::replace(&mut bus_context.restore_unsolicited, false) {
+            for device in devices.iter_mut().filter(|device| device.controller_handle == bus_context.controller_handle) {
+                if let Err(error) = codec_restore_unsolicited(&mut bus_context.bus, device) {
+                    warn!("failed to restore unsolicited responses of codec {:?}: {:?}", device.codec, error.status());
+                }
+            }
+        }
+        // Children of a group woken up by a sibling
+        let controller_handle = bus_context.controller_handle;
+        let groups = &bus_context.groups;
+        let woken = devices
+            .iter_mut()
+            .filter(|device| device.controller_handle == controller_handle && device.jacks_stale)
+            .filter(|device| {
+                groups
+                    .iter()
+                    .any(|group| group.codec.0 == device.codec.0 && group.afg == device.afg && group.powered)
+            });
+        for device in woken {
+            if let Err(error) = codec_restore_unsolicited(&mut bus_context.bus, device) {
+                warn!("failed to sense jacks of codec {:?}: {:?}", device.codec, error.status());
+            }
+        }
+        if let Err(error) = bus_context.bus.poll() {
+            warn!("failed to poll RIRB: {:?}", error.status());
+            continue;
+        }
+        for codec in 0..HDA_MAX_CODECS {
+            while let Some(response) = bus_context.bus.take_unsolicited(Codec(codec)) {
+                // Function groups of a codec share the tag space
+                let device = devices
+                    .iter_mut()
+                    .find(|device| {
+                        device.controller_handle == bus_context.controller_handle &&
+                            device.codec.0 == codec &&
+                            device.jacks.iter().any(|jack| jack.tag == response.tag())
+                    });
+                match device {
+                    Some(device) => {
+                        if let Err(error) = device_handle_unsolicited(&mut bus_context.bus, device, response) {
+                            warn!("failed to handle {:?}: {:?}", response, error.status());
+                        }
+                    },
+                    None => {
+                        info!("dropping {:?} from codec {:#x}", response, codec);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Power down function groups that have been idle for
+// POWER_IDLE_TIMEOUT_MS and optionally put the link into reset
+// once all of them are down
+fn bus_power_poll(bus_context: &mut BusContext, devices: &mut [Box<DeviceContext>]) {
+    if bus_context.link_reset {
+        return;
+    }
+    let controller_handle = bus_context.controller_handle;
+    for group in bus_context.groups.iter_mut() {
+        match group.idle_polls {
+            Some(0) => {},
+            Some(polls) => {
+                group.idle_polls = Some(polls - 1);
+                continue;
+            },
+            None => continue
+        }
+        group.idle_polls = None;
+        let group_devices = devices
+            .iter_mut()
+            .filter(|device| {
+                device.controller_handle == controller_handle &&
+                    device.codec.0 == group.codec.0 &&
+                    device.afg == group.afg
+            })
+            .map(alloc::boxed::Box::as_mut);
+        if let Err(error) = codec_power_down(&mut bus_context.bus, group.codec, group.afg, group_devices) {
+            warn!("failed to power down {:?} of codec {:?}: {:?}", group.afg, group.codec, error.status());
+        }
+        group.powered = false;
+    }
+    let idle = bus_context.groups
+        .iter()
+        .all(|group| !group.powered && group.idle_polls.is_none());
+    // Off by default, see README for RUSTFLAGS
+    if cfg!(idle_link_reset) && idle && !bus_context.groups.is_empty() {
+        if let Err(error) = bus_context.link_suspend() {
+            warn!("failed to put the link into reset: {:?}", error.status());
+        }
+    }
+}
+
+fn bus_trace_registers(pci: &PciIO) -> uefi::Result {
+    let gctl = GCTL.read(pci).ignore_warning()?;
+    let statests = STATESTS.read(pci).ignore_warning()?;
+    let intsts = INTSTS.read(pci).ignore_warning()?;
+    info!("GCTL:{:#010x} STATESTS:{:#010x} INTSTS:{:#010x}",
+          gctl, statests, intsts);
+    uefi::Status::SUCCESS.into()
+}
+
+fn bus_clear_interrupt(pci: &PciIO) -> uefi::Result {
+    let gcap = GCAP.read(pci)
+        .ignore_warning()
+        .map(GlobalCapabilities::from)?;
+    for stream in 0..gcap.in_streams() {
+        in_stream(&gcap, stream as usize)
+            .sts()
+            .write(pci, PCI_SDSTS_INT_MASK)?;
+    }
+    for stream in 0..gcap.out_streams() {
+        out_stream(&gcap, stream as usize)
+            .sts()
+            .write(pci, PCI_SDSTS_INT_MASK)?;
+    }
+    for stream in 0..gcap.bd_streams() {
+        bd_stream(&gcap, stream as usize)
+            .sts()
+            .write(pci, PCI_SDSTS_INT_MASK)?;
+    }
+    STATESTS.write(pci, PCI_STATESTS_INT_MASK)?;
+    RIRBSTS.write(pci, PCI_RIRBSTS_INT_MASK)?;
+    INTSTS.write(pci, PCI_INTSTS_CIS_BIT | PCI_INTSTS_SIS_MASK)?;
+    uefi::Status::SUCCESS.into()
+}
+
+fn bus_trace_config(pci: &PciIO) -> uefi::Result {
+    let gcap = GCAP.read(pci)
+        .ignore_warning()
+        .map(GlobalCapabilities::from)?;
+    info!("HDA controller Global Capabilities: {:#?}", gcap);
+    uefi::Status::SUCCESS.into()
+}
+
+fn bus_reset(pci: &PciIO, quirk: &ControllerQuirk) -> uefi::Result<u16> {
+    bus_stop(pci)?;
+    let codec_mask = bus_start(pci, quirk).ignore_warning()?;
+    bus_trace_config(pci)?;
+    Ok(codec_mask.into())
+}
+
+fn bus_start(pci: &PciIO, quirk: &ControllerQuirk) -> uefi::Result<u16> {
+    let codec_mask = bus_reset_link(pci, quirk).ignore_warning()?;
+    bus_clear_interrupt(pci)?;
+    bus_trace_registers(pci)?;
+    INTCTL.or(pci, PCI_INTCTL_CIE_BIT | PCI_INTCTL_SIE_MASK)?;
+    // Unsolicited responses are demultiplexed from RIRB by
+    // CommandResponseBuffers
+    GCTL.or(pci, PCI_GCTL_UNSOLICITED_BIT)?;
+    Ok(codec_mask.into())
+}
+
+fn bus_stop(pci: &PciIO) -> uefi::Result {
+    let gcap = GCAP.read(pci)
+        .ignore_warning()
+        .map(GlobalCapabilities::from)?;
+    // disabling interrupts for each stream descriptors is
+    // only necessary when we taking ownership of the bus
+    // link
+    for stream in 0..gcap.in_streams() {
+        in_stream(&gcap, stream as usize)
+            .ctl16()
+            .and(pci, !PCI_SDCTL16_INT_MASK)?;
+    }
+    for stream in 0..gcap.out_streams() {
+        out_stream(&gcap, stream as usize)
+            .ctl16()
+            .and(pci, !PCI_SDCTL16_INT_MASK)?;
+    }
+    for stream in 0..gcap.bd_streams() {
+        bd_stream(&gcap, stream as usize)
+            .ctl16()
+            .and(pci, !PCI_SDCTL16_INT_MASK)?;
+    }
+    // disable SIE and GIE for all streams
+    INTCTL.and(pci, !(PCI_INTCTL_SIE_MASK | PCI_INTCTL_CIE_BIT | PCI_INTCTL_GIE_BIT))?;
+    GCTL.and(pci, !PCI_GCTL_UNSOLICITED_BIT)?;
+    bus_clear_interrupt(pci)?;
+    uefi::Status::SUCCESS.into()
+}
+
+fn bus_reset_link(pci: &PciIO, quirk: &ControllerQuirk) -> uefi::Result<u16> {
+    // disable interrupts is only necessary because someone might configured them (not me)
+    STATESTS.write(pci, PCI_STATESTS_INT_MASK)?;
+    // enter bus reset state
+    GCTL.and(pci, !PCI_GCTL_RST_BIT)?;
+    GCTL.wait(pci, INFINITY, PCI_GCTL_RST_BIT, 0)?;
+    // leave bus reset state
+    GCTL.or(pci, PCI_GCTL_RST_BIT)?;
+    GCTL.wait(pci, INFINITY, PCI_GCTL_RST_BIT, PCI_GCTL_RST_BIT)?;
+    // GCTL.RST is sticky so double check the bus state
+    let gctl = GCTL.read(pci).ignore_warning()?;
+    if gctl == 0 {
+        info!("bus is not ready");
+        return Err(uefi::Status::NOT_READY.into());
+    }
+    // TBD: detect codecs by timer or interrupt
+    boot_services().stall(quirk.reset_delay_us);
+    let codec_mask: u16 = STATESTS.read(pci).ignore_warning()?;
+    info!("codec_mask:{:#b}", codec_mask);
+    Ok(codec_mask.into())
+}
+
+trait BusIo {
+    fn exec(&mut self, cmd: u32) -> uefi::Result<u32>;
+
+    // Fetch pending responses without sending a command
+    fn poll(&mut self) -> uefi::Result {
+        Ok(().into())
+    }
+
+    fn take_unsolicited(&mut self, codec: Codec) -> Option<UnsolicitedResponse> {
+        None
+    }
+
+    // Same for the responses with the given tag only
+    fn take_unsolicited_tag(&mut self, codec: Codec, tag: u32) -> Option<UnsolicitedResponse> {
+        None
+    }
+
+    // Stop using the controller before the link is reset
+    fn suspend(&mut self) -> uefi::Result {
+        Ok(().into())
+    }
+
+    // Program the controller again after the link is reset
+    fn resume(&mut self, quirk: &ControllerQuirk) -> uefi::Result {
+        Ok(().into())
+    }
+}
+
+#[repr(C, align(128))]
+struct CommandRing {
     slots: [u32; 256]
 }
 
@@ -691,10 +1592,15 @@ impl Mappable for ResponseRing {}
 struct CommandResponseBuffers<'a> {
     corbsize: usize,
     rirbsize: usize,
+    // CORBSIZE and RIRBSIZE register values
+    corbsz: u8,
+    rirbsz: u8,
     read_pos: usize,
     pci: &'a PciIO,
-    corb_dma: Option<MappingEx<'a, CommandRing>>,
-    rirb_dma: Option<MappingEx<'a, ResponseRing>>,
+    corb_dma: Option<DmaBuffer<'a, CommandRing>>,
+    rirb_dma: Option<DmaBuffer<'a, ResponseRing>>,
+    // Unsolicited responses indexed by codec address
+    unsolicited: alloc::vec::Vec<Fifo<UnsolicitedResponse>>,
 }
 
 impl<'a> Drop for CommandResponseBuffers<'a> {
@@ -730,14 +1636,10 @@ fn mfence() {
 }
 
 impl<'a> CommandResponseBuffers<'a> {
-    fn new(pci: &'a PciIO) -> uefi::Result<CommandResponseBuffers<'a>> {
-        let corb = pci
-            .map_ex(uefi::proto::pci::IoOperation::BusMasterWrite)
-            .map_err(inspect("PCI I/O map_ex(CORB)"))
+    fn new(pci: &'a PciIO, quirk: &ControllerQuirk) -> uefi::Result<CommandResponseBuffers<'a>> {
+        let corb = DmaBuffer::<CommandRing>::new(pci, "CORB")
             .ignore_warning()?;
-        let rirb = pci
-            .map_ex(uefi::proto::pci::IoOperation::BusMasterWrite)
-            .map_err(inspect("PCI I/O map_ex(RIRB)"))
+        let rirb = DmaBuffer::<ResponseRing>::new(pci, "RIRB")
             .ignore_warning()?;
         if (corb.device_address() & 0b1111111) != 0 {
             error!("CORB address {:#x} is not supported", corb.device_address());
@@ -747,16 +1649,16 @@ impl<'a> CommandResponseBuffers<'a> {
             error!("RIRB address {:#x} is not supported", rirb.device_address());
             return Err(uefi::Status::UNSUPPORTED.into());
         }
+        bus_check_dma_address(pci, corb.device_address(), mem::size_of::<CommandRing>())?;
+        bus_check_dma_address(pci, rirb.device_address(), mem::size_of::<ResponseRing>())?;
 
         // We don't check of size capabilities an only rely
         // on the implementation dependent size bits because vbox refuses to return
         let corbszcap = CORBSIZE.read(pci)
             .ignore_warning()?;
-        let corbsize = match (corbszcap & PCI_CORBSIZE_SZ_MASK) {
-            PCI_CORBSIZE_SZ_256 => 256,
-            PCI_CORBSIZE_SZ_16 => 16,
-            PCI_CORBSIZE_SZ_2 => 2,
-            _ => {
+        let (corbsz, corbsize) = match bus_ring_size(corbszcap, quirk.ring_entries) {
+            Some(size) => size,
+            None => {
                 error!("CORB size capabilities are not supported: {:#x}", corbszcap);
                 return Err(uefi::Status::UNSUPPORTED.into());
             }
@@ -764,16 +1666,34 @@ impl<'a> CommandResponseBuffers<'a> {
 
         let rirbszcap = RIRBSIZE.read(pci)
             .ignore_warning()?;
-        let rirbsize = match (rirbszcap & PCI_RIRBSIZE_SZ_MASK) {
-            PCI_RIRBSIZE_SZ_256 => 256,
-            PCI_RIRBSIZE_SZ_16 => 16,
-            PCI_RIRBSIZE_SZ_2 => 2,
-            _ => {
+        let (rirbsz, rirbsize) = match bus_ring_size(rirbszcap, quirk.ring_entries) {
+            Some(size) => size,
+            None => {
                 error!("RIRB size capabilities are not supported: {:#x}", rirbszcap);
                 return Err(uefi::Status::UNSUPPORTED.into());
             }
         };
 
+        let mut buffers = CommandResponseBuffers {
+            read_pos: 0,
+            corbsize,
+            rirbsize,
+            corbsz,
+            rirbsz,
+            corb_dma: Some(corb),
+            rirb_dma: Some(rirb),
+            unsolicited: (0..HDA_MAX_CODECS).map(|_| Fifo::new()).collect(),
+            pci
+        };
+        buffers.init_io(quirk)?;
+        Ok(buffers.into())
+    }
+
+    fn init_io(&mut self, quirk: &ControllerQuirk) -> uefi::Result {
+        let pci = self.pci;
+        let corb_address = self.corb_dma.as_ref().unwrap().device_address();
+        let rirb_address = self.rirb_dma.as_ref().unwrap().device_address();
+
         // Ensure that CORBCTL_DMA=0 and RIRBCTL_DMA=0
         RIRBCTL.and(pci, !PCI_RIRBCTL_DMA_BIT)?;
         CORBCTL.and(pci, !PCI_CORBCTL_DMA_BIT)?;
@@ -786,8 +1706,10 @@ impl<'a> CommandResponseBuffers<'a> {
         // really RO on both qemu and vbox so we must write
         // 0's.
         CORBRP.update(pci, PCI_CORBRP_RST_BIT, !PCI_CORBRP_RSVDP_MASK)?;
-        CORBRP.wait(pci, 1000, PCI_CORBRP_RST_BIT, PCI_CORBRP_RST_BIT)
-            .map_err(inspect("wait CORBRP.RST=1"))?;
+        if !quirk.corbrp_self_clear {
+            CORBRP.wait(pci, 1000, PCI_CORBRP_RST_BIT, PCI_CORBRP_RST_BIT)
+                .map_err(inspect("wait CORBRP.RST=1"))?;
+        }
         CORBRP.and(pci, !PCI_CORBRP_RST_BIT)?;
         CORBRP.wait(pci, 1000, PCI_CORBRP_RST_BIT, 0)
             .map_err(inspect("wait CORBRP.RST=0"))?;
@@ -796,14 +1718,14 @@ impl<'a> CommandResponseBuffers<'a> {
         CORBWP.and(pci, PCI_CORBWP_RSVDP_MASK)?;
 
         // Program the CORB base address
-        CORBLBASE.write(pci, (corb.device_address() & 0xffff_ffff) as u32)?;
-        CORBUBASE.write(pci, ((corb.device_address() >> 32) & 0xffff_ffff) as u32)?;
-        CORBSIZE.update(pci, (corbszcap & PCI_CORBSIZE_SZ_MASK), !PCI_CORBSIZE_RSVDP_MASK)?;
+        CORBLBASE.write(pci, (corb_address & 0xffff_ffff) as u32)?;
+        CORBUBASE.write(pci, ((corb_address >> 32) & 0xffff_ffff) as u32)?;
+        CORBSIZE.update(pci, self.corbsz, !PCI_CORBSIZE_RSVDP_MASK)?;
 
         // Program the RIRB base address
-        RIRBLBASE.write(pci, (rirb.device_address() & 0xffff_ffff) as u32)?;
-        RIRBUBASE.write(pci, ((rirb.device_address() >> 32) & 0xffff_ffff) as u32)?;
-        RIRBSIZE.update(pci, (rirbszcap & PCI_RIRBSIZE_SZ_MASK), !PCI_RIRBSIZE_RSVDP_MASK)?;
+        RIRBLBASE.write(pci, (rirb_address & 0xffff_ffff) as u32)?;
+        RIRBUBASE.write(pci, ((rirb_address >> 32) & 0xffff_ffff) as u32)?;
+        RIRBSIZE.update(pci, self.rirbsz, !PCI_RIRBSIZE_RSVDP_MASK)?;
 
         // Reset RIRB write pointer
         RIRBWP.or(pci, PCI_RIRBWP_RST_BIT)?;
@@ -815,14 +1737,8 @@ impl<'a> CommandResponseBuffers<'a> {
         // Vbox is pretty sensitive to the order of CORBCTL/RIRBCTL changes.
         CORBCTL.or(pci, PCI_CORBCTL_DMA_BIT)?;
 
-        Ok(CommandResponseBuffers {
-            read_pos: 0,
-            corbsize,
-            rirbsize,
-            corb_dma: Some(corb),
-            rirb_dma: Some(rirb),
-            pci
-        }.into())
+        self.read_pos = 0;
+        Ok(().into())
     }
 
     fn trace(&self) -> uefi::Result {
@@ -889,14 +1805,27 @@ impl<'a> CommandResponseBuffers<'a> {
         // process CORB without response control interrupt
         // and we don't have proper interrupt routine.
         RIRBSTS.or(self.pci, PCI_RIRBSTS_RESPONSE_BIT)?;
-        if (entry.response_ex & PCI_RIRB_EX_UNSOLICITED_BIT) != 0 {
-            error!("Got unsolicited event even though not requested!");
-            return Err(uefi::Status::DEVICE_ERROR.into());
-        }
         mfence();
         Ok(entry.into())
     }
 
+    // Queue an unsolicited response for its codec or return
+    // the result of a solicited one
+    fn demux(&mut self, entry: ResponseEntry) -> Option<u32> {
+        let ResponseEntry {result, response_ex} = entry;
+        if (response_ex & PCI_RIRB_EX_UNSOLICITED_BIT) == 0 {
+            return Some(result);
+        }
+        let codec = (response_ex & PCI_RIRB_EX_CODEC_MASK) as usize;
+        let queue = &mut self.unsolicited[codec];
+        if queue.len() >= UNSOLICITED_QUEUE_DEPTH {
+            warn!("unsolicited queue of codec {} overflowed", codec);
+            queue.pop();
+        }
+        queue.push(UnsolicitedResponse::from(result));
+        None
+    }
+
     fn recv(&mut self) -> uefi::Result<u32> {
         let timeout_event = boot_services()
             .create_timer_event()
@@ -913,8 +1842,11 @@ impl<'a> CommandResponseBuffers<'a> {
             // non-existant codec has been addressed or an
             // interrupt bit was asserted and the DMA engine
             // stuck waiting for it to be deasserted.
-            if let Ok(ResponseEntry {result, ..}) = self.poll_rirb().ignore_warning() {
-                return Ok(result.into());
+            if let Ok(entry) = self.poll_rirb().ignore_warning() {
+                match self.demux(entry) {
+                    Some(result) => return Ok(result.into()),
+                    None => continue
+                }
             }
             boot_services().stall(20);
             if let Ok(..) = boot_services().check_event(*timeout_event) {
@@ -927,9 +1859,43 @@ impl<'a> CommandResponseBuffers<'a> {
 
 impl<'a> BusIo for CommandResponseBuffers<'a> {
     fn exec(&mut self, cmd: u32) -> uefi::Result<u32> {
+        // Keep the unsolicited response timer away from the
+        // rings while we are waiting for our response
+        // SAFETY: hda_start() already calls us at TPL_NOTIFY;
+        //         raising to the same level is fine
+        let _tpl = unsafe { boot_services().raise_tpl(uefi::table::boot::Tpl::NOTIFY) };
         self.send(cmd)?;
         self.recv()
     }
+
+    fn poll(&mut self) -> uefi::Result {
+        while let Ok(entry) = self.poll_rirb().ignore_warning() {
+            if let Some(result) = self.demux(entry) {
+                warn!("dropping stray response {:#x}", result);
+            }
+        }
+        Ok(().into())
+    }
+
+    fn take_unsolicited(&mut self, codec: Codec) -> Option<UnsolicitedResponse> {
+        self.unsolicited
+            .get_mut(codec.0 as usize)
+            .and_then(Fifo::pop)
+    }
+
+    fn take_unsolicited_tag(&mut self, codec: Codec, tag: u32) -> Option<UnsolicitedResponse> {
+        self.unsolicited
+            .get_mut(codec.0 as usize)
+            .and_then(|queue| queue.take(|response| response.tag() == tag))
+    }
+
+    fn suspend(&mut self) -> uefi::Result {
+        self.uninit_io()
+    }
+
+    fn resume(&mut self, quirk: &ControllerQuirk) -> uefi::Result {
+        self.init_io(quirk)
+    }
 }
 
 struct Immediate<'a> {
@@ -995,6 +1961,8 @@ const HDA_VERB_GET_AMPLIFIER_GAIN_MUTE: Verb = Verb(0xb00);
 const HDA_VERB_SET_AMPLIFIER_GAIN_MUTE: Verb = Verb(0x300);
 const HDA_VERB_GET_EAPDBTL_ENABLE: Verb = Verb(0xf0c);
 const HDA_VERB_SET_EAPDBTL_ENABLE: Verb = Verb(0x70c);
+const HDA_VERB_GET_BEEP_GENERATION: Verb = Verb(0xf0a);
+const HDA_VERB_SET_BEEP_GENERATION: Verb = Verb(0x70a);
 const HDA_VERB_SET_PIN_WIDGET_CONTROL: Verb = Verb(0x707);
 const HDA_VERB_SET_POWER_STATE: Verb = Verb(0x705);
 const HDA_VERB_GET_POWER_STATE: Verb = Verb(0xf05);
@@ -1002,27 +1970,76 @@ const HDA_VERB_GET_CONNECTION_SELECT: Verb = Verb(0xf01);
 const HDA_VERB_SET_CONNECTION_SELECT: Verb = Verb(0x701);
 const HDA_VERB_GET_PIN_SENSE: Verb = Verb(0xf09);
 const HDA_VERB_EXECUTE_PIN_SENSE: Verb = Verb(0x709);
+const HDA_VERB_GET_UNSOLICITED_RESPONSE: Verb = Verb(0xf08);
+const HDA_VERB_SET_UNSOLICITED_RESPONSE: Verb = Verb(0x708);
+const HDA_VERB_GET_DIGITAL_CONVERTER: Verb = Verb(0xf0d);
+const HDA_VERB_GET_GPIO_DATA: Verb = Verb(0xf15);
+const HDA_VERB_GET_GPIO_ENABLE_MASK: Verb = Verb(0xf16);
+const HDA_VERB_GET_GPIO_DIRECTION: Verb = Verb(0xf17);
+const HDA_VERB_GET_GPIO_WAKE_ENABLE_MASK: Verb = Verb(0xf18);
+const HDA_VERB_GET_GPIO_UNSOLICITED_ENABLE_MASK: Verb = Verb(0xf19);
+const HDA_VERB_GET_GPIO_STICKY_MASK: Verb = Verb(0xf1a);
+const HDA_VERB_SET_GPIO_DATA: Verb = Verb(0x715);
+const HDA_VERB_SET_GPIO_ENABLE_MASK: Verb = Verb(0x716);
+const HDA_VERB_SET_GPIO_DIRECTION: Verb = Verb(0x717);
+const HDA_VERB_SET_CONFIG_DEFAULT_0: Verb = Verb(0x71c);
+const HDA_VERB_SET_CONFIG_DEFAULT_1: Verb = Verb(0x71d);
+const HDA_VERB_SET_CONFIG_DEFAULT_2: Verb = Verb(0x71e);
+const HDA_VERB_SET_CONFIG_DEFAULT_3: Verb = Verb(0x71f);
+const HDA_VERB_GET_SUBSYSTEM_ID: Verb = Verb(0xf20);
+const HDA_VERB_SET_DIGITAL_CONVERTER_1: Verb = Verb(0x70d);
+const HDA_VERB_SET_DIGITAL_CONVERTER_2: Verb = Verb(0x70e);
+const HDA_VERB_GET_CONVERTER_CHANNEL_COUNT: Verb = Verb(0xf2d);
+const HDA_VERB_SET_CONVERTER_CHANNEL_COUNT: Verb = Verb(0x72d);
+const HDA_VERB_GET_HDMI_DIP_SIZE: Verb = Verb(0xf2e);
+const HDA_VERB_GET_HDMI_ELD_DATA: Verb = Verb(0xf2f);
+const HDA_VERB_SET_HDMI_DIP_INDEX: Verb = Verb(0x730);
+const HDA_VERB_SET_HDMI_DIP_DATA: Verb = Verb(0x731);
+const HDA_VERB_SET_HDMI_DIP_XMIT: Verb = Verb(0x732);
+const HDA_VERB_SET_ASP_CHANNEL_MAPPING: Verb = Verb(0x734);
 
 const HDA_VERB_SET_VOLUME_KNOB: Verb = Verb(0x70f);
 const HDA_VERB_GET_VOLUME_KNOB: Verb = Verb(0xf0f);
 
+//
+// Codec vendor/device ID
+//
+const HDA_CODEC_ALC269: u32 = 0x10ec_0269;
+const HDA_CODEC_ALC885: u32 = 0x10ec_0885;
+
 const HDA_PARAM_VID: Param = Param(0x0);
+const HDA_PARAM_REVISION_ID: Param = Param(0x2);
 const HDA_PARAM_NODE_COUNT: Param = Param(0x4);
 const HDA_PARAM_FUNCTION_TYPE: Param = Param(0x5);
 const HDA_PARAM_AUDIO_WIDGET_CAPABILITIES: Param = Param(0x9);
 const HDA_PARAM_SUPPORTED_PCM: Param = Param(0xa);
+const HDA_PARAM_SUPPORTED_STREAM_FORMATS: Param = Param(0xb);
 const HDA_PARAM_PIN_WIDGET_CAPABILITIES: Param = Param(0xc);
 const HDA_PARAM_AMPLIFIER_INPUT_CAPABILITY: Param = Param(0xd);
 const HDA_PARAM_CONNECTION_LIST_LENGTH: Param = Param(0xe);
 const HDA_PARAM_SUPPORTED_POWER_STATES: Param = Param(0xf);
+const HDA_PARAM_PROCESSING_CAPABILITIES: Param = Param(0x10);
+const HDA_PARAM_GPIO_COUNT: Param = Param(0x11);
 const HDA_PARAM_AMPLIFIER_OUTPUT_CAPABILITY: Param = Param(0x12);
 const HDA_PARAM_VOLUME_KNOB_CAPABILITIES: Param = Param(0x13);
 
-const HDA_FUNCTION_TYPE_MASK: u32 = BIT7-1;
+const HDA_FUNCTION_TYPE_MASK: u32 = bitspan(7, 0) as u32;
+const HDA_FUNCTION_UNSOL_CAPABLE_BIT: u32 = BIT8;
+
+// 7.3.3.11 Converter Stream, Channel
+const HDA_CHANNEL_STREAM_CHANNEL_MASK: u32 = bitspan(3, 0) as u32;
 
 const HDA_CONNECTION_LIST_LONG_BIT: u32 = BIT7;
 const HDA_CONNECTION_LIST_LENGTH_MASK: u32 = BIT7-1;
 
+// 7.3.3.3 Get Connection List Entry
+const HDA_CONNECTION_LIST_SHORT_ENTRIES: u32 = 4;
+const HDA_CONNECTION_LIST_SHORT_RANGE_BIT: u32 = BIT7;
+const HDA_CONNECTION_LIST_SHORT_NID_MASK: u32 = bitspan(6, 0) as u32;
+const HDA_CONNECTION_LIST_LONG_ENTRIES: u32 = 2;
+const HDA_CONNECTION_LIST_LONG_RANGE_BIT: u32 = BIT15;
+const HDA_CONNECTION_LIST_LONG_NID_MASK: u32 = bitspan(14, 0) as u32;
+
 const HDA_AUDIO_CAPABILITY_STEREO_BIT: u32 = BIT0;
 const HDA_AUDIO_CAPABILITY_IN_AMP_PRESENT_BIT: u32 = BIT1;
 const HDA_AUDIO_CAPABILITY_OUT_AMP_PRESENT_BIT: u32 = BIT2;
@@ -1044,13 +2061,68 @@ const HDA_SET_VOLUME_KNOB_DIRECT_BIT: u32 = BIT7;
 const HDA_SET_VOLUME_KNOB_VOLUME_MASK: u32 = bitspan(6, 0) as u32;
 
 const HDA_PIN_SENSE_PRESENCE_DETECT: u32 = BIT31;
+const HDA_PIN_SENSE_ELD_VALID: u32 = BIT30;
+
+// 7.3.3.9 Digital Converter Control
+const HDA_DIGITAL_CONVERTER_DIGEN_BIT: u32 = BIT0;
+const HDA_DIGITAL_CONVERTER_VALIDITY_BIT: u32 = BIT1;
+const HDA_DIGITAL_CONVERTER_VALIDITY_CFG_BIT: u32 = BIT2;
+const HDA_DIGITAL_CONVERTER_PREEMPHASIS_BIT: u32 = BIT3;
+const HDA_DIGITAL_CONVERTER_COPY_BIT: u32 = BIT4;
+const HDA_DIGITAL_CONVERTER_NON_AUDIO_BIT: u32 = BIT5;
+const HDA_DIGITAL_CONVERTER_PRO_BIT: u32 = BIT6;
+const HDA_DIGITAL_CONVERTER_LEVEL_BIT: u32 = BIT7;
+const HDA_DIGITAL_CONVERTER_1_MASK: u32 = bitspan(7, 0) as u32;
+const HDA_DIGITAL_CONVERTER_CATEGORY_SHIFT: u32 = 8;
+const HDA_DIGITAL_CONVERTER_CATEGORY_MASK: u32 = bitspan(6, 0) as u32;
+
+// IEC 60958-3 category code of a general PCM encoder/decoder
+const IEC958_CATEGORY_PCM_CODER: u32 = 0x02;
+
+// Consumer mode PCM, no copyright asserted
+const SPDIF_DEFAULT_CONTROL: u32 = IEC958_CATEGORY_PCM_CODER << SPDIF_CATEGORY_SHIFT;
+
+// 7.3.3.34 HDMI ELD Data, 7.3.3.36 HDMI DIP Size
+const HDA_HDMI_DIP_SIZE_ELD_BUFFER_BIT: u32 = BIT3;
+const HDA_HDMI_DIP_SIZE_MASK: u32 = bitspan(7, 0) as u32;
+const HDA_HDMI_ELD_DATA_VALID_BIT: u32 = BIT31;
+const HDA_HDMI_ELD_DATA_MASK: u32 = bitspan(7, 0) as u32;
+const HDA_HDMI_ELD_MAX_SIZE: u32 = 256;
+// Size to use if the codec reports an empty ELD buffer
+const HDA_HDMI_ELD_DEFAULT_SIZE: u32 = 128;
+const HDA_HDMI_ELD_VERSION: u32 = 2;
+const HDA_HDMI_ELD_CONNECTION_HDMI: u32 = 0;
+const HDA_HDMI_ELD_CONNECTION_DP: u32 = 1;
+const HDA_HDMI_SAD_FORMAT_LPCM: u32 = 1;
+
+// 7.3.3.37 HDMI DIP Index, 7.3.3.39 HDMI DIP Transmit Control
+const HDA_HDMI_DIP_INDEX_PACKET_SHIFT: u32 = 5;
+const HDA_HDMI_DIP_AUDIO_INFOFRAME: u32 = 0;
+const HDA_HDMI_DIP_XMIT_DISABLE: u32 = 0;
+const HDA_HDMI_DIP_XMIT_BEST_EFFORT: u32 = BIT7 | BIT6;
+
+// 7.3.3.40 ASP Channel Mapping
+const HDA_ASP_SLOT_COUNT: u32 = 8;
+const HDA_ASP_CHANNEL_SHIFT: u32 = 4;
+const HDA_ASP_CHANNEL_UNUSED: u32 = 0xf;
+
+// 7.3.3.14 Unsolicited Response
+const HDA_UNSOLICITED_RESPONSE_ENABLE_BIT: u32 = BIT7;
+const HDA_UNSOLICITED_RESPONSE_TAG_MASK: u32 = bitspan(5, 0) as u32;
 
 const HDA_PIN_CAPABILITY_EAPDBTL_BIT: u32 = BIT16;
 const HDA_PIN_EAPDBTL_EAPD_ENABLE_BIT: u32 = BIT1;
 const HDA_PIN_EAPDBTL_BTL_ENABLE_BIT: u32 = BIT0;
+const HDA_PIN_EAPDBTL_LR_SWAP_BIT: u32 = BIT2;
 const HDA_PIN_WIDGET_CONTROL_PHN_ENABLE_BIT: u32 = BIT7;
 const HDA_PIN_WIDGET_CONTROL_OUT_ENABLE_BIT: u32 = BIT6;
 const HDA_PIN_WIDGET_CONTROL_IN_ENABLE_BIT: u32 = BIT5;
+const HDA_PIN_WIDGET_CONTROL_VREF_MASK: u32 = bitspan(2, 0) as u32;
+
+// 7.3.3.8 the tone frequency is 48kHz / (4 * divider), a
+// divider of 0 turns the beep generator off
+const HDA_BEEP_GENERATION_RATE: u32 = AUDIO_RATE_48000 / 4;
+const HDA_BEEP_GENERATION_DIVIDER_MASK: u32 = 0xff;
 
 const HDA_AMPLIFIER_CAPABILITY_OFFSET_MASK: u32 = 0x7f;
 const HDA_AMPLIFIER_CAPABILITY_NUMSTEPS_MASK: u32 = 0x7f00;
@@ -1060,10 +2132,13 @@ const HDA_AMPLIFIER_CAPABILITY_MUTE_BIT: u32 = BIT31;
 const HDA_AMPLIFIER_GAIN_MUTE_GAIN_MASK: u32 = 0x7f;
 const HDA_AMPLIFIER_GAIN_MUTE_MUTE_BIT: u32 = BIT7;
 const HDA_AMPLIFIER_GAIN_MUTE_INDEX_MASK: u32 = 0xf00;
+const HDA_AMPLIFIER_GAIN_MUTE_INDEX_SHIFT: u32 = 8;
 const HDA_AMPLIFIER_GAIN_MUTE_SETR_BIT: u32 = BIT12;
 const HDA_AMPLIFIER_GAIN_MUTE_SETL_BIT: u32 = BIT13;
 const HDA_AMPLIFIER_GAIN_MUTE_SETI_BIT: u32 = BIT14;
 const HDA_AMPLIFIER_GAIN_MUTE_SETO_BIT: u32 = BIT15;
+// Input amplifiers addressable by the index field
+const HDA_AMPLIFIER_MAX_INPUTS: usize = (HDA_AMPLIFIER_GAIN_MUTE_INDEX_MASK >> HDA_AMPLIFIER_GAIN_MUTE_INDEX_SHIFT) as usize + 1;
 
 // 7.3.3.7 Amplifier Gain/Mute
 const HDA_AMPLIFIER_GAIN_MUTE_GET_OUT_BIT: u32 = BIT15;
@@ -1104,6 +2179,9 @@ const HDA_JACK_OTHER: u32 = 0xf;
 // Misc
 const HDA_JACK_MISC_DETECT_OVERRIDE: u32 = BIT0;
 
+// Association whose pins are not associated with each other
+const HDA_JACK_ASSOCIATION_INDIVIDUAL: u32 = 0xf;
+
 // Port connectivity
 const HDA_JACK_PORT_COMPLEX: u32 = 0x0;
 const HDA_JACK_PORT_NONE: u32 = 0x1;
@@ -1117,30 +2195,239 @@ const HDA_POWER_STATE_D2: u32 = 0b010;
 const HDA_POWER_STATE_D3HOT: u32 = 0b011;
 const HDA_POWER_STATE_D3COLD: u32 = 0b100;
 
+// 7.3.3.10 Power State
+const HDA_POWER_STATE_SET_MASK: u32 = bitspan(3, 0) as u32;
+const HDA_POWER_STATE_ACT_MASK: u32 = bitspan(7, 4) as u32;
+const HDA_POWER_STATE_ACT_SHIFT: u32 = 4;
+const HDA_POWER_STATE_ERROR_BIT: u32 = BIT8;
+
 fn make_command(codec: Codec, node: Node, verb: Verb, param: Param) -> u32 {
     (codec.0 << 28) | (node.0 << 20) | (verb.0 << 8) | (param.0)
 }
 
+// Immediate command interface does not see unsolicited
+// responses at all
+#[cfg(immediate_command_mode)]
+type BusIoImpl<'a> = Immediate<'a>;
+
+#[cfg(not(immediate_command_mode))]
+type BusIoImpl<'a> = CommandResponseBuffers<'a>;
+
 #[cfg(immediate_command_mode)]
-fn make_bus_io(pci: &PciIO) -> uefi::Result<Immediate> {
+fn make_bus_io<'a>(pci: &'a PciIO, quirk: &ControllerQuirk) -> uefi::Result<Immediate<'a>> {
     Immediate::new(pci)
 }
 
 #[cfg(not(immediate_command_mode))]
-fn make_bus_io(pci: &PciIO) -> uefi::Result<CommandResponseBuffers> {
-    CommandResponseBuffers::new(pci)
+fn make_bus_io<'a>(pci: &'a PciIO, quirk: &ControllerQuirk) -> uefi::Result<CommandResponseBuffers<'a>> {
+    CommandResponseBuffers::new(pci, quirk)
 }
 
-fn bus_probe_codecs(pci: &PciIO, codec_mask: u16) -> uefi::Result<alloc::vec::Vec<u32>> {
-    let mut codecs = alloc::vec::Vec::new();
-    for codec in (0..16).filter(|n| (codec_mask & (1 << n)) != 0) {
-        // Create new command response buffer for each new
-        // codec because old one can be broken due to an
-        // access to non-existant codec
-        let mut bus = make_bus_io(pci).ignore_warning()?;
-
-        let cmd = make_command(Codec(codec), HDA_NODE_ROOT, HDA_VERB_PARAMS, HDA_PARAM_VID);
-        match bus.exec(cmd).ignore_warning() {
+// GCAP.64OK unless the controller is known to lie about it
+fn bus_ok_64(pci: &PciIO) -> uefi::Result<bool> {
+    let gcap = GCAP.read(pci)
+        .ignore_warning()
+        .map(GlobalCapabilities::from)?;
+    let quirk = controller_quirk(pci)
+        .ignore_warning()?;
+    Ok((gcap.ok_64() && !quirk.no_64bit).into())
+}
+
+// CORBSIZE and RIRBSIZE share the same layout. The size
+// currently programmed is used unless it exceeds max_entries
+// because vbox reports no size capabilities. Returns the SZ
+// field value and the number of entries.
+fn bus_ring_size(szcap: u8, max_entries: usize) -> Option<(u8, usize)> {
+    let sizes = [
+        (PCI_CORBSIZE_SZ_256, 256, PCI_CORBSIZE_CAP_256_BIT),
+        (PCI_CORBSIZE_SZ_16, 16, PCI_CORBSIZE_CAP_16_BIT),
+        (PCI_CORBSIZE_SZ_2, 2, PCI_CORBSIZE_CAP_2_BIT),
+    ];
+    let current = sizes
+        .iter()
+        .find(|&&(sz, _, _)| sz == (szcap & PCI_CORBSIZE_SZ_MASK))?;
+    if current.1 <= max_entries {
+        return Some((current.0, current.1));
+    }
+    sizes
+        .iter()
+        .find(|&&(_, entries, cap)| entries <= max_entries && (szcap & cap) != 0)
+        .map(|&(sz, entries, _)| (sz, entries))
+}
+
+// Let the root bridge map buffers above 4 GiB if the controller
+// supports 64 bit addresses. Without the attribute the buffers
+// are bounced below 4 GiB. Returns true if the attribute has
+// been enabled.
+fn bus_enable_dual_address_cycle(pci: &PciIO) -> uefi::Result<bool> {
+    if !bus_ok_64(pci).ignore_warning()? {
+        info!("controller is limited to 32 bit addresses");
+        return Ok(false.into());
+    }
+    let supported = pci.attributes(uefi::proto::pci::AttributeOperation::Supported, 0)
+        .map_err(inspect("PCI I/O attributes(Supported)"))
+        .ignore_warning()?;
+    if (supported & PCI_IO_ATTRIBUTE_DUAL_ADDRESS_CYCLE) == 0 {
+        info!("root bridge does not support dual address cycle");
+        return Ok(false.into());
+    }
+    pci.attributes(uefi::proto::pci::AttributeOperation::Enable, PCI_IO_ATTRIBUTE_DUAL_ADDRESS_CYCLE)
+        .map_err(inspect("PCI I/O attributes(Enable)"))
+        .ignore_warning()?;
+    Ok(true.into())
+}
+
+fn bus_disable_dual_address_cycle(pci: &PciIO) {
+    let result = pci.attributes(uefi::proto::pci::AttributeOperation::Disable, PCI_IO_ATTRIBUTE_DUAL_ADDRESS_CYCLE)
+        .map_err(inspect("PCI I/O attributes(Disable)"));
+    if let Err(error) = result {
+        warn!("failed to disable dual address cycle: {:?}", error.status());
+    }
+}
+
+// Disables dual address cycle again if the bus context could
+// not be set up. Disarmed once the context takes it over.
+struct DualAddressCycleGuard<'a> {
+    pci: &'a PciIO,
+}
+
+impl<'a> DualAddressCycleGuard<'a> {
+    fn disarm(self) -> bool {
+        mem::forget(self);
+        true
+    }
+}
+
+impl<'a> Drop for DualAddressCycleGuard<'a> {
+    fn drop(&mut self) {
+        bus_disable_dual_address_cycle(self.pci);
+    }
+}
+
+// 3.1.3 Behavior With 64-bit Addresses -- the upper base
+// registers are reserved if the controller does not support
+// 64 bit addresses so the buffers must be below 4 GiB.
+// DmaBuffer allocates them there, this catches a root bridge
+// that maps them elsewhere anyway.
+fn bus_check_dma_address(pci: &PciIO, address: u64, size: usize) -> uefi::Result {
+    let last = address.checked_add(size as u64 - 1)
+        .ok_or(uefi::Status::UNSUPPORTED)?;
+    if (last >> 32) != 0 && !bus_ok_64(pci).ignore_warning()? {
+        error!("DMA buffer at {:#x} is out of reach of 32 bit controller", address);
+        return Err(uefi::Status::UNSUPPORTED.into());
+    }
+    Ok(().into())
+}
+
+const DMA_PAGE_SIZE: usize = 4096;
+
+// Buffer shared with the controller. The CPU keeps writing the
+// rings and the BDL after they are mapped, so a controller
+// limited to 32 bit addresses gets pages below 4 GiB mapped as
+// a common buffer: a bounced BusMasterWrite mapping would hide
+// those writes from it.
+enum DmaBuffer<'a, T: Mappable> {
+    Mapped(MappingEx<'a, T>),
+    Low(LowDmaBuffer<'a, T>),
+}
+
+impl<'a, T: Mappable> DmaBuffer<'a, T> {
+    fn new(pci: &'a PciIO, name: &str) -> uefi::Result<DmaBuffer<'a, T>> {
+        if bus_ok_64(pci).ignore_warning()? {
+            let mapping = pci
+                .map_ex::<T>(uefi::proto::pci::IoOperation::BusMasterWrite)
+                .map_err(inspect(name))
+                .ignore_warning()?;
+            return Ok(DmaBuffer::Mapped(mapping).into());
+        }
+        LowDmaBuffer::new(pci, name)
+            .ignore_warning()
+            .map(|buffer| DmaBuffer::Low(buffer).into())
+    }
+
+    fn device_address(&self) -> u64 {
+        match self {
+            DmaBuffer::Mapped(mapping) => mapping.device_address(),
+            DmaBuffer::Low(buffer) => buffer.mapping.as_ref().unwrap().device_address(),
+        }
+    }
+
+    fn get(&self) -> *const T {
+        match self {
+            DmaBuffer::Mapped(mapping) => mapping.get(),
+            DmaBuffer::Low(buffer) => buffer.address as *const T,
+        }
+    }
+
+    fn get_mut(&mut self) -> *mut T {
+        match self {
+            DmaBuffer::Mapped(mapping) => mapping.get_mut(),
+            DmaBuffer::Low(buffer) => buffer.address as *mut T,
+        }
+    }
+}
+
+struct LowDmaBuffer<'a, T> {
+    pci: &'a PciIO,
+    // Host address of the pages
+    address: u64,
+    pages: usize,
+    mapping: Option<uefi::proto::pci::Mapping>,
+    _type: core::marker::PhantomData<T>,
+}
+
+impl<'a, T> LowDmaBuffer<'a, T> {
+    fn new(pci: &'a PciIO, name: &str) -> uefi::Result<LowDmaBuffer<'a, T>> {
+        let size = mem::size_of::<T>();
+        let pages = div_round_up(size, DMA_PAGE_SIZE);
+        let address = boot_services()
+            .allocate_pages(
+                uefi::table::boot::AllocateType::MaxAddress(0xffff_ffff),
+                uefi::table::boot::MemoryType::BOOT_SERVICES_DATA,
+                pages)
+            .map_err(inspect(name))
+            .ignore_warning()?;
+        let mut buffer = LowDmaBuffer {
+            pci,
+            address,
+            pages,
+            mapping: None,
+            _type: core::marker::PhantomData,
+        };
+        // SAFETY: the pages have just been allocated for us
+        unsafe { core::ptr::write_bytes(address as *mut u8, 0, pages * DMA_PAGE_SIZE) };
+        // SAFETY: the pages are only freed after unmapping
+        let mapping = unsafe {
+            pci.map(uefi::proto::pci::IoOperation::BusMasterCommonBuffer, address as *mut _, size)
+        };
+        buffer.mapping = Some(mapping.map_err(inspect(name)).ignore_warning()?);
+        Ok(buffer.into())
+    }
+}
+
+impl<'a, T> Drop for LowDmaBuffer<'a, T> {
+    fn drop(&mut self) {
+        if let Some(mapping) = self.mapping.take() {
+            if let Err(error) = self.pci.unmap(mapping).discard_errdata() {
+                warn!("failed to unmap DMA buffer: {:?}", error.status());
+            }
+        }
+        if let Err(error) = boot_services().free_pages(self.address, self.pages) {
+            warn!("failed to free DMA buffer: {:?}", error.status());
+        }
+    }
+}
+
+fn bus_probe_codecs(pci: &PciIO, quirk: &ControllerQuirk, codec_mask: u16) -> uefi::Result<alloc::vec::Vec<u32>> {
+    let mut codecs = alloc::vec::Vec::new();
+    for codec in (0..16).filter(|n| (codec_mask & (1 << n)) != 0) {
+        // Create new command response buffer for each new
+        // codec because old one can be broken due to an
+        // access to non-existant codec
+        let mut bus = make_bus_io(pci, quirk).ignore_warning()?;
+
+        let cmd = make_command(Codec(codec), HDA_NODE_ROOT, HDA_VERB_PARAMS, HDA_PARAM_VID);
+        match bus.exec(cmd).ignore_warning() {
             Ok(vid) => {
                 info!("codec {:#x} is detected, response:{:#x}", codec, vid);
                 codecs.push(codec);
@@ -1159,20 +2446,20 @@ fn bus_probe_codecs(pci: &PciIO, codec_mask: u16) -> uefi::Result<alloc::vec::Ve
 
 fn stream_clear(device: &mut DeviceContext, pci: &PciIO) -> uefi::Result {
     // TBD: wait for PCI_SDCTL16_RUN_BIT to be gone
-    out_stream_1(device)
+    device_stream(device)
         .ctl16()
         .and(pci, !(PCI_SDCTL16_RUN_BIT | PCI_SDCTL16_INT_MASK))?;
-    out_stream_1(device)
+    device_stream(device)
         .sts()
         .write(pci, PCI_SDSTS_INT_MASK)?;
-    out_stream_1(device)
+    device_stream(device)
         .ctl8()
         .and(pci, !PCI_SDCTL8_STRIPE_MASK)?;
     uefi::Status::SUCCESS.into()
 }
 
 fn stream_trace(device: &mut DeviceContext, pci: &PciIO) -> uefi::Result {
-    let sd = out_stream_1(device);
+    let sd = device_stream(device);
     let ctl16 = sd.ctl16().read(pci).ignore_warning()?;
     let ctl8 = sd.ctl8().read(pci).ignore_warning()?;
     let fmt = sd.fmt().read(pci).ignore_warning()?;
@@ -1192,11 +2479,13 @@ fn stream_trace(device: &mut DeviceContext, pci: &PciIO) -> uefi::Result {
     uefi::Status::SUCCESS.into()
 }
 
-fn codec_set_stream<B: BusIo>(bus: &mut B, codec: Codec, node: Node, mask: u8) -> uefi::Result {
+// The converter takes its samples starting from the given
+// channel of the stream
+fn codec_set_stream<B: BusIo>(bus: &mut B, codec: Codec, node: Node, mask: u8, channel: u32) -> uefi::Result {
     let stream_id = bus.exec(make_command(codec, node, HDA_VERB_GET_CHANNEL_STREAM, Param(0x0)))
         .ignore_warning()?;
     info!("codec_set_stream: {:?} read: {:#x}", node, stream_id);
-    bus.exec(make_command(codec, node, HDA_VERB_SET_CHANNEL_STREAM, Param(u32::from(mask))))?;
+    bus.exec(make_command(codec, node, HDA_VERB_SET_CHANNEL_STREAM, Param(u32::from(mask) | (channel & HDA_CHANNEL_STREAM_CHANNEL_MASK))))?;
     let readback = bus.exec(make_command(codec, node, HDA_VERB_GET_CHANNEL_STREAM, Param(0x0)))
         .ignore_warning()?;
     info!("codec_set_stream: -- readback: {:#x}", readback);
@@ -1258,46 +2547,69 @@ fn pin_enable_output<B: BusIo>(bus: &mut B, codec: Codec, node: Node, enable: bo
     uefi::Status::SUCCESS.into()
 }
 
+// Nodes follow the power state of their function group so
+// the group must be powered up before and powered down after
+// its widgets. The requested state takes effect only once
+// PS-Act reports it.
 fn pin_power<B: BusIo>(bus: &mut B, codec: Codec, node: Node, up: bool) -> uefi::Result {
+    let target = if up { HDA_POWER_STATE_D0 } else { HDA_POWER_STATE_D3HOT };
     let power_state = bus.exec(make_command(codec, node, HDA_VERB_GET_POWER_STATE, Param(0x0)))
         .ignore_warning()?;
     info!("pin_power: {:?} up: {}, current: {:#x}", node, up, power_state);
-    if up {
-        bus.exec(make_command(codec, node, HDA_VERB_SET_POWER_STATE, Param(HDA_POWER_STATE_D0)))?;
-    } else {
-        bus.exec(make_command(codec, node, HDA_VERB_SET_POWER_STATE, Param(HDA_POWER_STATE_D3HOT)))?;
+    if (power_state & HDA_POWER_STATE_SET_MASK) == target &&
+        ((power_state & HDA_POWER_STATE_ACT_MASK) >> HDA_POWER_STATE_ACT_SHIFT) == target {
+        return uefi::Status::SUCCESS.into();
     }
-    // TBD: do we need it at all?
-    boot_services().stall(milliseconds_to_stall(5));
-    let power_state = bus.exec(make_command(codec, node, HDA_VERB_GET_POWER_STATE, Param(0x0)))
-        .ignore_warning()?;
-    info!("pin_power: -- readback: {:#x}", power_state);
-    if (power_state & BIT8) != 0 {
-        return uefi::Status::DEVICE_ERROR.into();
+    bus.exec(make_command(codec, node, HDA_VERB_SET_POWER_STATE, Param(target)))?;
+    let timeout_event = boot_services()
+        .create_timer_event()
+        .ignore_warning()
+        .map(EventGuard::wrap)?;
+    boot_services()
+        .set_timer(
+            *timeout_event,
+            uefi::table::boot::TimerTrigger::Relative(milliseconds_to_timer_period(POWER_STATE_TIMEOUT_MS)))?;
+    loop {
+        let power_state = bus.exec(make_command(codec, node, HDA_VERB_GET_POWER_STATE, Param(0x0)))
+            .ignore_warning()?;
+        if (power_state & HDA_POWER_STATE_ERROR_BIT) != 0 {
+            warn!("pin_power: {:?} failed to enter {:#x}: {:#x}", node, target, power_state);
+            return uefi::Status::DEVICE_ERROR.into();
+        }
+        if ((power_state & HDA_POWER_STATE_ACT_MASK) >> HDA_POWER_STATE_ACT_SHIFT) == target {
+            info!("pin_power: -- readback: {:#x}", power_state);
+            break;
+        }
+        if let Ok(..) = boot_services().check_event(*timeout_event) {
+            warn!("pin_power: {:?} is still in {:#x}", node, power_state);
+            return uefi::Status::TIMEOUT.into();
+        }
+        boot_services().stall(100);
     }
     uefi::Status::SUCCESS.into()
 }
 
 #[derive(Debug)]
 struct Amps {
-    // TBD: handle index for widgets with multiple input amplifiers
     output_left: AmpGain,
     output_right: AmpGain,
     input_left: AmpGain,
     input_right: AmpGain,
 }
 
-fn pin_get_amps<B: BusIo>(bus: &mut B, codec: Codec, node: Node) -> uefi::Result<Amps> {
+// Input gains are read for the input amplifier at index
+fn pin_get_amps<B: BusIo>(bus: &mut B, codec: Codec, node: Node, index: usize) -> uefi::Result<Amps> {
+    let index = index as u32 & HDA_AMPLIFIER_GAIN_MUTE_GET_INDEX_MASK;
     let output_left = bus.exec(make_command(codec, node, HDA_VERB_GET_AMPLIFIER_GAIN_MUTE, Param(HDA_AMPLIFIER_GAIN_MUTE_GET_OUT_BIT | HDA_AMPLIFIER_GAIN_MUTE_GET_LEFT_BIT)))
         .ignore_warning()
         .map(AmpGain::from)?;
     let output_right = bus.exec(make_command(codec, node, HDA_VERB_GET_AMPLIFIER_GAIN_MUTE, Param(HDA_AMPLIFIER_GAIN_MUTE_GET_OUT_BIT)))
         .ignore_warning()
         .map(AmpGain::from)?;
-    let input_left = bus.exec(make_command(codec, node, HDA_VERB_GET_AMPLIFIER_GAIN_MUTE, Param(HDA_AMPLIFIER_GAIN_MUTE_GET_LEFT_BIT)))
+    let input_left = bus.exec(make_command(codec, node, HDA_VERB_GET_AMPLIFIER_GAIN_MUTE, Param(HDA_AMPLIFIER_GAIN_MUTE_GET_LEFT_BIT | index)))
         .ignore_warning()
         .map(AmpGain::from)?;
-    let input_right = bus.exec(make_command(codec, node, HDA_VERB_GET_AMPLIFIER_GAIN_MUTE, Param(0x0)))
+    let input_right = bus.exec(make_command(codec, node, HDA_VERB_GET_AMPLIFIER_GAIN_MUTE, Param(index)))
         .ignore_warning()
         .map(AmpGain::from)?;
     Ok (Amps {
@@ -1308,43 +2620,140 @@ fn pin_get_amps<B: BusIo>(bus: &mut B, codec: Codec, node: Node) -> uefi::Result
     }.into())
 }
 
-fn pin_mute_unmute<B: BusIo>(bus: &mut B, codec: Codec, afg_caps: Option<&AmpCapabilities>, node: Node, mute: bool) -> uefi::Result {
-    info!("pin_mute_unmute: {:?} {}", node, mute);
-    let pin_caps = bus.exec(make_command(codec, node, HDA_VERB_PARAMS, HDA_PARAM_AMPLIFIER_OUTPUT_CAPABILITY))
+// Amplifiers of a widget, None if the widget has no such
+// amplifier
+#[derive(Copy, Clone, Debug, Default)]
+struct WidgetAmps {
+    input: Option<AmpCapabilities>,
+    output: Option<AmpCapabilities>,
+}
+
+fn amp_get_caps<B: BusIo>(bus: &mut B, codec: Codec, node: Node, param: Param) -> uefi::Result<AmpCapabilities> {
+    bus.exec(make_command(codec, node, HDA_VERB_PARAMS, param))
         .ignore_warning()
-        .map(AmpCapabilities::from)?;
-    let amps = pin_get_amps(bus, codec, node).ignore_warning()?;
-    info!("pin_mute_unmute: mute = {}, {:?}", mute, amps);
-    let mut flags = HDA_AMPLIFIER_GAIN_MUTE_SETO_BIT
-        | HDA_AMPLIFIER_GAIN_MUTE_SETL_BIT
-        | HDA_AMPLIFIER_GAIN_MUTE_SETR_BIT;
-    if pin_caps.num_steps() != 0 {
-        // TBD: negative gain
-        // Note half of the min/max gain means half the volume
-        flags |= pin_caps.offset();
-    } else if afg_caps.map(AmpCapabilities::num_steps).unwrap_or(0) != 0 {
-        flags |= afg_caps.map(AmpCapabilities::offset).unwrap();
+        .map(AmpCapabilities::from)
+        .map(|caps| caps.into())
+}
+
+// Default amplifier parameters of the function group
+fn afg_get_amps<B: BusIo>(bus: &mut B, codec: Codec, afg: Node) -> uefi::Result<WidgetAmps> {
+    let input = amp_get_caps(bus, codec, afg, HDA_PARAM_AMPLIFIER_INPUT_CAPABILITY)
+        .ignore_warning()?;
+    let output = amp_get_caps(bus, codec, afg, HDA_PARAM_AMPLIFIER_OUTPUT_CAPABILITY)
+        .ignore_warning()?;
+    Ok(WidgetAmps {
+        input: Some(input),
+        output: Some(output),
+    }.into())
+}
+
+// 7.3.4.10 widgets without the Amp Param Override bit use the
+// amplifier parameters of the function group
+fn widget_get_amps<B: BusIo>(bus: &mut B, codec: Codec, afg_amps: &WidgetAmps, path_node: &PathNode) -> uefi::Result<WidgetAmps> {
+    let caps = match path_node.caps() {
+        Some(caps) => caps,
+        None => return Ok(WidgetAmps::default().into()),
+    };
+    let node = path_node.node();
+    let overridden = caps.amp_param_override() != 0;
+    let input = if caps.in_amp_present() == 0 {
+        None
+    } else if overridden {
+        Some(amp_get_caps(bus, codec, node, HDA_PARAM_AMPLIFIER_INPUT_CAPABILITY).ignore_warning()?)
+    } else {
+        afg_amps.input
+    };
+    let output = if caps.out_amp_present() == 0 {
+        None
+    } else if overridden {
+        Some(amp_get_caps(bus, codec, node, HDA_PARAM_AMPLIFIER_OUTPUT_CAPABILITY).ignore_warning()?)
+    } else {
+        afg_amps.output
+    };
+    Ok(WidgetAmps { input, output }.into())
+}
+
+fn codec_get_amps<B: BusIo>(bus: &mut B, codec: Codec, afg_amps: &WidgetAmps, nodes: &[PathNode]) -> uefi::Result<NodeMap<WidgetAmps>> {
+    let mut amps = NodeMap::new();
+    for path_node in nodes.iter() {
+        let widget_amps = widget_get_amps(bus, codec, afg_amps, path_node).ignore_warning()?;
+        amps.insert(&path_node.node(), widget_amps);
+    }
+    Ok(amps.into())
+}
+
+// Program the left and right gain in 1/4 dB of the output
+// amplifier, or of the input amplifier at the given index
+// (7.3.3.7). None mutes the channel, amplifiers that cannot be
+// muted are set to their lowest gain instead.
+fn amp_set_gain<B: BusIo>(bus: &mut B, codec: Codec, node: Node, caps: &AmpCapabilities, input: Option<usize>, left: Option<i32>, right: Option<i32>) -> uefi::Result {
+    let payload = |qdb: Option<i32>| match qdb {
+        Some(qdb) => caps.gain_from_qdb(qdb),
+        None if caps.mute() => HDA_AMPLIFIER_GAIN_MUTE_MUTE_BIT,
+        None => 0,
+    };
+    let target = match input {
+        Some(index) => HDA_AMPLIFIER_GAIN_MUTE_SETI_BIT
+            | (((index as u32) << HDA_AMPLIFIER_GAIN_MUTE_INDEX_SHIFT) & HDA_AMPLIFIER_GAIN_MUTE_INDEX_MASK),
+        None => HDA_AMPLIFIER_GAIN_MUTE_SETO_BIT,
+    };
+    // Actual gain of the closest step for the log
+    let actual = |qdb: Option<i32>| qdb.map(|qdb| caps.gain_qdb(caps.gain_from_qdb(qdb)));
+    info!("amp_set_gain: {:?} input: {:?}, left: {:?}, right: {:?} dB/4", node, input, actual(left), actual(right));
+    let (left, right) = (payload(left), payload(right));
+    if left == right {
+        let flags = target | HDA_AMPLIFIER_GAIN_MUTE_SETL_BIT | HDA_AMPLIFIER_GAIN_MUTE_SETR_BIT | left;
+        bus.exec(make_command(codec, node, HDA_VERB_SET_AMPLIFIER_GAIN_MUTE, Param(flags)))?;
     } else {
-        flags |= 0x7f;
+        bus.exec(make_command(codec, node, HDA_VERB_SET_AMPLIFIER_GAIN_MUTE, Param(target | HDA_AMPLIFIER_GAIN_MUTE_SETL_BIT | left)))?;
+        bus.exec(make_command(codec, node, HDA_VERB_SET_AMPLIFIER_GAIN_MUTE, Param(target | HDA_AMPLIFIER_GAIN_MUTE_SETR_BIT | right)))?;
     }
-    // TBD: not correct. if pin has amplifier capability and
-    // has no mute support we must ignore afg capabilities
-    if afg_caps.map(AmpCapabilities::mute).unwrap_or(false) || pin_caps.mute() {
-        if mute {
-            flags |= HDA_AMPLIFIER_GAIN_MUTE_MUTE_BIT;
+    let readback = pin_get_amps(bus, codec, node, input.unwrap_or(0)).ignore_warning()?;
+    info!("amp_set_gain: -- readback {:?}", readback);
+    Ok(().into())
+}
+
+// Set the output amplifier of the widget to PATH_AMP_GAIN_QDB
+// or mute it
+fn pin_mute_unmute<B: BusIo>(bus: &mut B, codec: Codec, amps: &WidgetAmps, node: Node, mute: bool) -> uefi::Result {
+    info!("pin_mute_unmute: {:?} {}", node, mute);
+    match amps.output {
+        Some(ref caps) => {
+            let gain = if mute { None } else { Some(PATH_AMP_GAIN_QDB) };
+            amp_set_gain(bus, codec, node, caps, None, gain, gain)
+        },
+        None => Ok(().into()),
+    }
+}
+
+// Unmute the input amplifier of the connection at index and
+// mute the others, so that a mixer only sums the path. Count is
+// the length of the connection list with ranges expanded, only
+// the first HDA_AMPLIFIER_MAX_INPUTS inputs can be addressed.
+fn pin_select_input<B: BusIo>(bus: &mut B, codec: Codec, amps: &WidgetAmps, node: Node, index: usize, count: usize) -> uefi::Result {
+    info!("pin_select_input: {:?} index: {} of {}", node, index, count);
+    if index >= HDA_AMPLIFIER_MAX_INPUTS {
+        warn!("pin_select_input: {:?} input {} has no addressable amplifier", node, index);
+    }
+    if let Some(ref caps) = amps.input {
+        for i in 0..count.min(HDA_AMPLIFIER_MAX_INPUTS) {
+            let gain = if i == index { Some(PATH_AMP_GAIN_QDB) } else { None };
+            amp_set_gain(bus, codec, node, caps, Some(i), gain, gain)?;
         }
     }
-    bus.exec(make_command(codec, node, HDA_VERB_SET_AMPLIFIER_GAIN_MUTE, Param(flags)))?;
-    let readback = pin_get_amps(bus, codec, node).ignore_warning()?;
-    info!("pin_mute_unmute: -- readback {:?}", readback);
     Ok(().into())
 }
 
-fn pin_set_volume<B: BusIo>(bus: &mut B, codec: Codec, node: Node) -> uefi::Result {
+// Turn the knob all the way up, the amplifiers it controls
+// are set up along the path
+fn pin_set_volume<B: BusIo>(bus: &mut B, codec: Codec, node: Node, caps: &VolumeKnobCapabilities) -> uefi::Result {
     let volume = bus.exec(make_command(codec, node, HDA_VERB_GET_VOLUME_KNOB, Param(0x0)))
         .ignore_warning()?;
-    info!("pin_set_volume: {:?} current: {:#x}", node, volume);
-    let flags = HDA_SET_VOLUME_KNOB_DIRECT_BIT | HDA_SET_VOLUME_KNOB_VOLUME_MASK;
+    info!("pin_set_volume: {:?} current: {:#x}, {:?}", node, volume, caps);
+    if caps.num_steps() == 0 {
+        return Ok(().into());
+    }
+    let flags = HDA_SET_VOLUME_KNOB_DIRECT_BIT | (caps.num_steps() & HDA_SET_VOLUME_KNOB_VOLUME_MASK);
     bus.exec(make_command(codec, node, HDA_VERB_SET_VOLUME_KNOB, Param(flags)))?;
     let readback = bus.exec(make_command(codec, node, HDA_VERB_GET_VOLUME_KNOB, Param(0x0)))
         .ignore_warning()?;
@@ -1352,13 +2761,13 @@ fn pin_set_volume<B: BusIo>(bus: &mut B, codec: Codec, node: Node) -> uefi::Resu
     Ok(().into())
 }
 
-fn pin_select<B: BusIo>(bus: &mut B, codec: Codec, node: Node, index: usize) -> uefi::Result {
-    let len = bus.exec(make_command(codec, node, HDA_VERB_PARAMS, HDA_PARAM_CONNECTION_LIST_LENGTH))
-        .ignore_warning()?;
+// The index and count refer to the connection list with ranges
+// expanded, which can be longer than the raw list length
+fn pin_select<B: BusIo>(bus: &mut B, codec: Codec, node: Node, index: usize, count: usize) -> uefi::Result {
     let select = bus.exec(make_command(codec, node, HDA_VERB_GET_CONNECTION_SELECT, Param(0x0)))
         .ignore_warning()?;
     info!("pin_select: {:?} is changing connection select from {} to {}", node, select, index);
-    if index < len as usize {
+    if index < count {
         bus.exec(make_command(codec, node, HDA_VERB_SET_CONNECTION_SELECT, Param(index as u32)))?;
         let select = bus.exec(make_command(codec, node, HDA_VERB_GET_CONNECTION_SELECT, Param(0x0)))
             .ignore_warning()?;
@@ -1376,39 +2785,283 @@ fn codec_set_format<B: BusIo>(bus: &mut B, codec: Codec, node: Node, format: u16
     uefi::Status::SUCCESS.into()
 }
 
+// 1.2.42 SDFMT decoding
+fn pcm_format_channels(format: u16) -> u32 {
+    u32::from(format & PCM_FMT_CHAN_MASK) + 1
+}
+
+fn pcm_format_rate(format: u16) -> u32 {
+    let base = if (format & PCM_FMT_44K_BIT) != 0 { AUDIO_RATE_44100 } else { AUDIO_RATE_48000 };
+    let mul = u32::from((format >> 11) & 0x7) + 1;
+    let div = u32::from((format >> 8) & 0x7) + 1;
+    base * mul / div
+}
+
+fn pcm_format_bits(format: u16) -> u32 {
+    match format & (0x7 << 4) {
+        PCM_FMT_PACK_8_MASK => 8,
+        PCM_FMT_PACK_16_MASK => 16,
+        PCM_FMT_PACK_20_MASK => 20,
+        PCM_FMT_PACK_24_MASK => 24,
+        _ => 32,
+    }
+}
+
+fn converter_get_digital<B: BusIo>(bus: &mut B, codec: Codec, node: Node) -> uefi::Result<u32> {
+    bus.exec(make_command(codec, node, HDA_VERB_GET_DIGITAL_CONVERTER, Param(0x0)))
+}
+
+// Program both the control bits and the category code
+fn converter_set_digital<B: BusIo>(bus: &mut B, codec: Codec, node: Node, control: u32) -> uefi::Result {
+    bus.exec(make_command(codec, node, HDA_VERB_SET_DIGITAL_CONVERTER_1, Param(control & HDA_DIGITAL_CONVERTER_1_MASK)))?;
+    let category = (control >> HDA_DIGITAL_CONVERTER_CATEGORY_SHIFT) & HDA_DIGITAL_CONVERTER_CATEGORY_MASK;
+    bus.exec(make_command(codec, node, HDA_VERB_SET_DIGITAL_CONVERTER_2, Param(category)))?;
+    let readback = converter_get_digital(bus, codec, node)
+        .ignore_warning()
+        .map(DigitalConverterControl::from)?;
+    info!("converter_set_digital: {:?} {:#?}", node, readback);
+    Ok(().into())
+}
+
+// Turn on digital output of PCM data, so that the sink does
+// not take the samples for a compressed stream
+fn converter_enable_digital<B: BusIo>(bus: &mut B, codec: Codec, node: Node, enable: bool) -> uefi::Result {
+    let control = converter_get_digital(bus, codec, node)
+        .ignore_warning()?;
+    let control = if enable {
+        (control | HDA_DIGITAL_CONVERTER_DIGEN_BIT) & !HDA_DIGITAL_CONVERTER_NON_AUDIO_BIT
+    } else {
+        control & !HDA_DIGITAL_CONVERTER_DIGEN_BIT
+    };
+    converter_set_digital(bus, codec, node, control)
+}
+
+// Set up an S/PDIF converter with the channel status given as
+// SPDIF_* flags. The stream is always marked as an original.
+fn converter_setup_spdif<B: BusIo>(bus: &mut B, codec: Codec, node: Node, control: u32) -> uefi::Result {
+    let category = (control & SPDIF_CATEGORY_MASK) >> SPDIF_CATEGORY_SHIFT;
+    let mut digital = HDA_DIGITAL_CONVERTER_DIGEN_BIT
+        | HDA_DIGITAL_CONVERTER_LEVEL_BIT
+        | (category << HDA_DIGITAL_CONVERTER_CATEGORY_SHIFT);
+    // The Copy bit set means that no copyright is asserted
+    if control & SPDIF_COPYRIGHT == 0 {
+        digital |= HDA_DIGITAL_CONVERTER_COPY_BIT;
+    }
+    if control & SPDIF_PROFESSIONAL != 0 {
+        digital |= HDA_DIGITAL_CONVERTER_PRO_BIT;
+    }
+    if control & SPDIF_NON_PCM != 0 {
+        digital |= HDA_DIGITAL_CONVERTER_NON_AUDIO_BIT;
+    }
+    converter_set_digital(bus, codec, node, digital)
+}
+
+// Program the channel count of an HDMI/DP converter and map
+// its channels to the audio sample packet slots
+fn converter_setup_display<B: BusIo>(bus: &mut B, codec: Codec, node: Node, format: u16) -> uefi::Result {
+    let channels = pcm_format_channels(format);
+    bus.exec(make_command(codec, node, HDA_VERB_SET_CONVERTER_CHANNEL_COUNT, Param(channels - 1)))?;
+    let readback = bus.exec(make_command(codec, node, HDA_VERB_GET_CONVERTER_CHANNEL_COUNT, Param(0x0)))
+        .ignore_warning()?;
+    info!("converter_setup_display: {:?} channels: {} readback: {:#x}", node, channels, readback);
+    let (_, slots) = hdmi_channel_allocation(channels);
+    for slot in 0..HDA_ASP_SLOT_COUNT {
+        let channel = slots
+            .iter()
+            .take(channels as usize)
+            .position(|&channel_slot| channel_slot == slot)
+            .map_or(HDA_ASP_CHANNEL_UNUSED, |channel| channel as u32);
+        bus.exec(make_command(codec, node, HDA_VERB_SET_ASP_CHANNEL_MAPPING, Param((channel << HDA_ASP_CHANNEL_SHIFT) | slot)))?;
+    }
+    converter_enable_digital(bus, codec, node, true)
+}
+
+fn pin_read_eld<B: BusIo>(bus: &mut B, codec: Codec, node: Node) -> uefi::Result<alloc::vec::Vec<u8>> {
+    let size = bus.exec(make_command(codec, node, HDA_VERB_GET_HDMI_DIP_SIZE, Param(HDA_HDMI_DIP_SIZE_ELD_BUFFER_BIT)))
+        .ignore_warning()? & HDA_HDMI_DIP_SIZE_MASK;
+    let size = match size {
+        0 => HDA_HDMI_ELD_DEFAULT_SIZE,
+        size => size.min(HDA_HDMI_ELD_MAX_SIZE),
+    };
+    let mut eld = alloc::vec::Vec::with_capacity(size as usize);
+    for offset in 0..size {
+        let data = bus.exec(make_command(codec, node, HDA_VERB_GET_HDMI_ELD_DATA, Param(offset)))
+            .ignore_warning()?;
+        if (data & HDA_HDMI_ELD_DATA_VALID_BIT) != 0 {
+            eld.push((data & HDA_HDMI_ELD_DATA_MASK) as u8);
+        } else if offset == 0 {
+            return Err(uefi::Status::NOT_READY.into());
+        } else {
+            // Some codecs do not mark the unused tail as valid
+            eld.push(0);
+        }
+    }
+    Ok(eld.into())
+}
+
+fn pin_write_infoframe<B: BusIo>(bus: &mut B, codec: Codec, node: Node, frame: &[u8]) -> uefi::Result {
+    bus.exec(make_command(codec, node, HDA_VERB_SET_HDMI_DIP_XMIT, Param(HDA_HDMI_DIP_XMIT_DISABLE)))?;
+    bus.exec(make_command(codec, node, HDA_VERB_SET_HDMI_DIP_INDEX, Param(HDA_HDMI_DIP_AUDIO_INFOFRAME << HDA_HDMI_DIP_INDEX_PACKET_SHIFT)))?;
+    for byte in frame.iter() {
+        bus.exec(make_command(codec, node, HDA_VERB_SET_HDMI_DIP_DATA, Param(u32::from(*byte))))?;
+    }
+    bus.exec(make_command(codec, node, HDA_VERB_SET_HDMI_DIP_XMIT, Param(HDA_HDMI_DIP_XMIT_BEST_EFFORT)))?;
+    Ok(().into())
+}
+
+// Check the stream format against the formats the monitor
+// accepts and send the audio infoframe. Returns false if the
+// monitor can not play the stream.
+fn pin_setup_display<B: BusIo>(bus: &mut B, codec: Codec, pin: Node, sink: DisplaySink, format: u16) -> uefi::Result<bool> {
+    let channels = pcm_format_channels(format);
+    let rate = pcm_format_rate(format);
+    let bits = pcm_format_bits(format);
+    // Every HDMI sink must accept 2 channel 16 bit LPCM at
+    // 32, 44.1 and 48 kHz
+    let basic_audio = channels <= 2 && bits == 16 &&
+        (rate == AUDIO_RATE_32000 || rate == AUDIO_RATE_44100 || rate == AUDIO_RATE_48000);
+    let mut sink = sink;
+    let supported = match pin_read_eld(bus, codec, pin).ignore_warning() {
+        Ok(bytes) => match Eld::from(&bytes) {
+            Some(ref eld) if eld.version() == HDA_HDMI_ELD_VERSION => {
+                info!("ELD of {:?}: {:#?}", pin, eld);
+                match eld.connection_type() {
+                    HDA_HDMI_ELD_CONNECTION_HDMI => sink = DisplaySink::Hdmi,
+                    HDA_HDMI_ELD_CONNECTION_DP => sink = DisplaySink::DisplayPort,
+                    _ => {}
+                }
+                eld.sads()
+                    .filter(|sad| sad.format() == HDA_HDMI_SAD_FORMAT_LPCM)
+                    .any(|sad| sad.supports(rate, bits, channels))
+            },
+            _ => {
+                warn!("ELD of {:?} is malformed, assuming basic audio", pin);
+                basic_audio
+            }
+        },
+        Err(error) => {
+            warn!("failed to read ELD of {:?}: {:?}, assuming basic audio", pin, error.status());
+            basic_audio
+        }
+    };
+    if !supported {
+        warn!("monitor on {:?} does not support {} Hz {} bit {} channels", pin, rate, bits, channels);
+        return Ok(false.into());
+    }
+    pin_write_infoframe(bus, codec, pin, &make_audio_infoframe(sink, channels))?;
+    Ok(true.into())
+}
+
+fn pin_sense<B: BusIo>(bus: &mut B, codec: Codec, node: Node, pin_caps: &PinCapabilities) -> uefi::Result<u32> {
+    if pin_caps.trigger_required() != 0 {
+        bus.exec(make_command(codec, node, HDA_VERB_EXECUTE_PIN_SENSE, Param(0x0)))
+            .ignore_warning()?;
+    }
+    bus.exec(make_command(codec, node, HDA_VERB_GET_PIN_SENSE, Param(0x0)))
+}
+
 #[derive(Copy, Clone, Debug)]
 struct NodeDescriptor {
     start_id: u32,
     count: u32
 }
 
+impl NodeDescriptor {
+    fn contains(&self, node: Node) -> bool {
+        node.0 >= self.start_id && node.0 < self.start_id + self.count
+    }
+}
+
 fn parse_node_count(response: u32) -> NodeDescriptor {
     let start_id = (response >> 16) & 0x7fff;
     let count = response & 0x7fff;
     NodeDescriptor { start_id, count }
 }
 
-fn find_audio_function_node<B: BusIo>(bus: &mut B, pci: &PciIO, codec: Codec) -> uefi::Result<Node> {
+// Read the connection list of the node and expand range
+// entries. NIDs outside of the function group and invalid
+// ranges are kept as None so that the positions still match
+// the connection select and input amplifier indices.
+fn codec_get_connections<B: BusIo>(bus: &mut B, codec: Codec, node: Node, afg_nodes: &NodeDescriptor) -> uefi::Result<alloc::vec::Vec<Option<Node>>> {
+    let len = bus.exec(make_command(codec, node, HDA_VERB_PARAMS, HDA_PARAM_CONNECTION_LIST_LENGTH))
+        .ignore_warning()?;
+    let count = len & HDA_CONNECTION_LIST_LENGTH_MASK;
+    let (entries_per_response, entry_bits, range_bit, nid_mask) = if len & HDA_CONNECTION_LIST_LONG_BIT != 0 {
+        (HDA_CONNECTION_LIST_LONG_ENTRIES, 16, HDA_CONNECTION_LIST_LONG_RANGE_BIT, HDA_CONNECTION_LIST_LONG_NID_MASK)
+    } else {
+        (HDA_CONNECTION_LIST_SHORT_ENTRIES, 8, HDA_CONNECTION_LIST_SHORT_RANGE_BIT, HDA_CONNECTION_LIST_SHORT_NID_MASK)
+    };
+    let mut connections = alloc::vec::Vec::new();
+    let push = |nid: u32, connections: &mut alloc::vec::Vec<Option<Node>>| {
+        if afg_nodes.contains(Node(nid)) {
+            connections.push(Some(Node(nid)));
+        } else {
+            warn!("{:?} has connection to {:#x} outside of the function group", node, nid);
+            connections.push(None);
+        }
+    };
+    let mut previous = None;
+    for offset in (0..count).step_by(entries_per_response as usize) {
+        // The offset selects the first of the returned entries
+        let mut entries = bus.exec(make_command(codec, node, HDA_VERB_GET_CONNECTION_LIST, Param(offset)))
+            .ignore_warning()?;
+        for _ in 0..entries_per_response.min(count - offset) {
+            let nid = entries & nid_mask;
+            if entries & range_bit != 0 {
+                // The range starts right after the previous entry
+                match previous {
+                    Some(first) if first < nid => {
+                        for n in (first + 1)..=nid {
+                            push(n, &mut connections);
+                        }
+                    },
+                    _ => {
+                        warn!("{:?} has invalid connection range {:?}..{:#x}", node, previous, nid);
+                        connections.push(None);
+                    }
+                }
+            } else {
+                push(nid, &mut connections);
+            }
+            previous = Some(nid);
+            entries >>= entry_bits;
+        }
+    }
+    Ok(connections.into())
+}
+
+#[derive(Copy, Clone, Debug)]
+struct FunctionGroup {
+    node: Node,
+    typ: u32,
+    unsol_capable: bool
+}
+
+impl FunctionGroup {
+    fn is_audio(&self) -> bool {
+        self.typ == HDA_FUNCTION_AUDIO
+    }
+    fn is_modem(&self) -> bool {
+        self.typ == HDA_FUNCTION_MODEM
+    }
+}
+
+fn codec_function_groups<B: BusIo>(bus: &mut B, codec: Codec) -> uefi::Result<alloc::vec::Vec<FunctionGroup>> {
     let NodeDescriptor {start_id, count} = bus.exec(make_command(codec, HDA_NODE_ROOT, HDA_VERB_PARAMS, HDA_PARAM_NODE_COUNT))
         .ignore_warning()
         .map(parse_node_count)?;
     info!("sub nodes: {} nodes starting from {}", start_id, count);
-    let mut afg = None;
+    let mut groups = alloc::vec::Vec::with_capacity(count as usize);
     for n in start_id..(start_id + count) {
         let fun = bus.exec(make_command(codec, Node(n), HDA_VERB_PARAMS, HDA_PARAM_FUNCTION_TYPE))
             .ignore_warning()?;
-        if fun & HDA_FUNCTION_TYPE_MASK == HDA_FUNCTION_AUDIO {
-            if afg.is_some() {
-                warn!("Multiple AFG found! The other was {:?}", afg.unwrap());
-            }
-            afg = Some(Node(n));
-        }
-    }
-    if afg.is_none() {
-        error!("No AFG node found!");
-        return Err(uefi::Status::NOT_FOUND.into());
+        groups.push(FunctionGroup {
+            node: Node(n),
+            typ: fun & HDA_FUNCTION_TYPE_MASK,
+            unsol_capable: (fun & HDA_FUNCTION_UNSOL_CAPABLE_BIT) != 0
+        });
     }
-    Ok(afg.unwrap().into())
+    Ok(groups.into())
 }
 
 #[derive(Debug)]
@@ -1420,25 +3073,25 @@ enum PathNode {
     AudioIn {
         node: Node,
         caps: WidgetCapabilities,
-        connections: alloc::vec::Vec<Node>,
+        connections: alloc::vec::Vec<Option<Node>>,
         select: usize
     },
     AudioMix {
         node: Node,
         caps: WidgetCapabilities,
-        connections: alloc::vec::Vec<Node>,
+        connections: alloc::vec::Vec<Option<Node>>,
         select: usize
     },
     AudioMux {
         node: Node,
         caps: WidgetCapabilities,
-        connections: alloc::vec::Vec<Node>,
+        connections: alloc::vec::Vec<Option<Node>>,
         select: usize
     },
     PinComplex {
         node: Node,
         caps: WidgetCapabilities,
-        connections: alloc::vec::Vec<Node>,
+        connections: alloc::vec::Vec<Option<Node>>,
         select: usize,
         pin_caps: PinCapabilities,
         config: PinConfig,
@@ -1447,12 +3100,12 @@ enum PathNode {
     Power {
         node: Node,
         caps: WidgetCapabilities,
-        connections: alloc::vec::Vec<Node>
+        connections: alloc::vec::Vec<Option<Node>>
     },
     Volume {
         node: Node,
         caps: WidgetCapabilities,
-        connections: alloc::vec::Vec<Node>,
+        connections: alloc::vec::Vec<Option<Node>>,
         knob_capabilities: VolumeKnobCapabilities
     },
     Beep {
@@ -1478,7 +3131,8 @@ impl PathNode {
             PathNode::Other {ref node, ..} => *node,
         }
     }
-    fn successors(&self) -> &[Node] {
+    // Connection list, None where the entry is not usable
+    fn successors(&self) -> &[Option<Node>] {
         match self {
             PathNode::AudioOut {..} => &[],
             PathNode::AudioIn {ref connections, ..} => connections,
@@ -1491,10 +3145,29 @@ impl PathNode {
             PathNode::Other {..} => &[],
         }
     }
+    fn caps(&self) -> Option<&WidgetCapabilities> {
+        match self {
+            PathNode::AudioOut {ref caps, ..} => Some(caps),
+            PathNode::AudioIn {ref caps, ..} => Some(caps),
+            PathNode::AudioMix {ref caps, ..} => Some(caps),
+            PathNode::AudioMux {ref caps, ..} => Some(caps),
+            PathNode::PinComplex {ref caps, ..} => Some(caps),
+            PathNode::Power {ref caps, ..} => Some(caps),
+            PathNode::Volume {ref caps, ..} => Some(caps),
+            PathNode::Beep {ref caps, ..} => Some(caps),
+            PathNode::Other {..} => None,
+        }
+    }
     fn is_dac(&self) -> bool {
         matches!(self, PathNode::AudioOut {..})
     }
-    fn is_headphones(&self) -> bool {
+    fn is_beep(&self) -> bool {
+        matches!(self, PathNode::Beep {..})
+    }
+    fn has_power_control(&self) -> bool {
+        self.caps().map_or(false, |caps| caps.power_ctl() != 0)
+    }
+    fn is_headphone_jack(&self) -> bool {
         match self {
             PathNode::PinComplex {ref config, ref presence, ..} => {
                 // Table 109. Port Connectivity -- The Port Complex is connected to a jack
@@ -1502,21 +3175,251 @@ impl PathNode {
                  config.port_connectivity() == HDA_JACK_PORT_BOTH) &&
                 // Table 111. Default device -- HP Out
                 config.device() == HDA_JACK_HP_OUT &&
-                presence.unwrap_or(false) &&
+                presence.is_some() &&
                 // Table 114. Misc -- Jack Detect Override
                 (config.misc() & HDA_JACK_MISC_DETECT_OVERRIDE) == 0
             },
             _ => false,
         }
     }
+    fn is_headphones(&self) -> bool {
+        match self {
+            PathNode::PinComplex {ref presence, ..} => {
+                self.is_headphone_jack() && presence.unwrap_or(false)
+            },
+            _ => false,
+        }
+    }
+    fn pin_config(&self) -> Option<&PinConfig> {
+        match self {
+            PathNode::PinComplex {ref config, ..} => Some(config),
+            _ => None,
+        }
+    }
+    fn output_kind(&self) -> Option<OutputKind> {
+        match self {
+            PathNode::PinComplex {ref caps, ref pin_caps, ref config, ..} => pin_output_kind(caps, pin_caps, config),
+            _ => None,
+        }
+    }
+    fn display_sink(&self) -> Option<DisplaySink> {
+        match self {
+            PathNode::PinComplex {ref caps, ref pin_caps, ..} if caps.digital() != 0 => {
+                if pin_caps.display_port() != 0 {
+                    Some(DisplaySink::DisplayPort)
+                } else if pin_caps.hdmi() != 0 {
+                    Some(DisplaySink::Hdmi)
+                } else {
+                    None
+                }
+            },
+            _ => None,
+        }
+    }
 }
 
-fn codec_collect_nodes<B: BusIo>(bus: &mut B, device: &mut DeviceContext, pci: &PciIO, codec: Codec) -> uefi::Result<alloc::vec::Vec<PathNode>> {
-    let afg = find_audio_function_node(bus, pci, codec)
+fn pin_output_kind(caps: &WidgetCapabilities, pin_caps: &PinCapabilities, config: &PinConfig) -> Option<OutputKind> {
+    if pin_caps.output_capable() == 0 {
+        None
+    } else if caps.digital() == 0 || pin_caps.hdmi() != 0 || pin_caps.display_port() != 0 {
+        Some(OutputKind::Primary)
+    } else if config.device() == HDA_JACK_SPDIF_OUT || config.device() == HDA_JACK_DIG_OTHER_OUT {
+        Some(OutputKind::Spdif)
+    } else {
+        None
+    }
+}
+
+struct PinFixup {
+    node: Node,
+    config: u32,
+}
+
+struct InitVerb {
+    node: Node,
+    verb: Verb,
+    payload: u32,
+}
+
+// GPIO pins of the function group driven as outputs
+#[derive(Copy, Clone, Debug)]
+struct GpioFixup {
+    mask: u32,
+    direction: u32,
+    data: u32,
+}
+
+// Board specific corrections of a codec, matched by the
+// codec vendor/device ID and the subsystem ID of the function
+// group (verb F20)
+struct CodecFixup {
+    name: &'static str,
+    codec_id: u32,
+    subsystem_id: u32,
+    subsystem_mask: u32,
+    // Replace the pin configuration defaults set by BIOS
+    pins: &'static [PinFixup],
+    // Pins that need EAPD enabled to drive an amplifier
+    eapd: &'static [Node],
+    gpio: Option<GpioFixup>,
+    verbs: &'static [InitVerb],
+}
+
+const CODEC_FIXUP_NONE: CodecFixup = CodecFixup {
+    name: "",
+    codec_id: 0,
+    subsystem_id: 0,
+    subsystem_mask: 0xffff_ffff,
+    pins: &[],
+    eapd: &[],
+    gpio: None,
+    verbs: &[],
+};
+
+// Dock headphone and microphone are not described by BIOS
+const ALC269_LENOVO_DOCK_PINS: &[PinFixup] = &[
+    PinFixup { node: Node(0x19), config: 0x23a1_1040 },
+    PinFixup { node: Node(0x1b), config: 0x2121_103f },
+];
+
+const CODEC_FIXUPS: &[CodecFixup] = &[
+    CodecFixup {
+        name: "ThinkPad T530",
+        codec_id: HDA_CODEC_ALC269,
+        subsystem_id: 0x17aa_21f6,
+        pins: ALC269_LENOVO_DOCK_PINS,
+        ..CODEC_FIXUP_NONE
+    },
+    CodecFixup {
+        name: "ThinkPad X230",
+        codec_id: HDA_CODEC_ALC269,
+        subsystem_id: 0x17aa_21fa,
+        pins: ALC269_LENOVO_DOCK_PINS,
+        ..CODEC_FIXUP_NONE
+    },
+    CodecFixup {
+        name: "ThinkPad X230 Tablet",
+        codec_id: HDA_CODEC_ALC269,
+        subsystem_id: 0x17aa_2203,
+        pins: ALC269_LENOVO_DOCK_PINS,
+        ..CODEC_FIXUP_NONE
+    },
+    // GPIO0 and GPIO1 unmute the amplifiers
+    CodecFixup {
+        name: "Mac Pro",
+        codec_id: HDA_CODEC_ALC885,
+        subsystem_id: 0x106b_0c00,
+        gpio: Some(GpioFixup { mask: 0x3, direction: 0x3, data: 0x3 }),
+        ..CODEC_FIXUP_NONE
+    },
+];
+
+fn codec_find_fixup(codec_id: u32, subsystem_id: u32) -> Option<&'static CodecFixup> {
+    CODEC_FIXUPS
+        .iter()
+        .find(|fixup| {
+            fixup.codec_id == codec_id
+                && (fixup.subsystem_id & fixup.subsystem_mask) == (subsystem_id & fixup.subsystem_mask)
+        })
+}
+
+fn pin_set_config<B: BusIo>(bus: &mut B, codec: Codec, node: Node, config: u32) -> uefi::Result {
+    let verbs = [
+        HDA_VERB_SET_CONFIG_DEFAULT_0,
+        HDA_VERB_SET_CONFIG_DEFAULT_1,
+        HDA_VERB_SET_CONFIG_DEFAULT_2,
+        HDA_VERB_SET_CONFIG_DEFAULT_3,
+    ];
+    for (index, &verb) in verbs.iter().enumerate() {
+        bus.exec(make_command(codec, node, verb, Param((config >> (8 * index)) & 0xff)))?;
+    }
+    Ok(().into())
+}
+
+fn codec_set_gpio<B: BusIo>(bus: &mut B, codec: Codec, afg: Node, gpio: &GpioFixup) -> uefi::Result {
+    let mask = bus.exec(make_command(codec, afg, HDA_VERB_GET_GPIO_ENABLE_MASK, Param(0x0)))
+        .ignore_warning()?;
+    let direction = bus.exec(make_command(codec, afg, HDA_VERB_GET_GPIO_DIRECTION, Param(0x0)))
+        .ignore_warning()?;
+    let data = bus.exec(make_command(codec, afg, HDA_VERB_GET_GPIO_DATA, Param(0x0)))
+        .ignore_warning()?;
+    bus.exec(make_command(codec, afg, HDA_VERB_SET_GPIO_ENABLE_MASK, Param((mask | gpio.mask) & 0xff)))?;
+    bus.exec(make_command(codec, afg, HDA_VERB_SET_GPIO_DIRECTION, Param(((direction & !gpio.mask) | (gpio.direction & gpio.mask)) & 0xff)))?;
+    bus.exec(make_command(codec, afg, HDA_VERB_SET_GPIO_DATA, Param(((data & !gpio.mask) | (gpio.data & gpio.mask)) & 0xff)))?;
+    Ok(().into())
+}
+
+// Pin configs must be fixed before anything looks at them.
+// The patch file is applied after the built-in fixup so that
+// it has the last word.
+fn codec_apply_fixups<B: BusIo>(bus: &mut B, codec: Codec, afg: Node, patches: &[PatchCodec]) -> uefi::Result {
+    let codec_id = bus.exec(make_command(codec, HDA_NODE_ROOT, HDA_VERB_PARAMS, HDA_PARAM_VID))
+        .ignore_warning()?;
+    let subsystem_id = bus.exec(make_command(codec, afg, HDA_VERB_GET_SUBSYSTEM_ID, Param(0x0)))
         .ignore_warning()?;
+    info!("codec {:?}: id {:#010x}, subsystem id {:#010x}", codec, codec_id, subsystem_id);
+    if let Some(fixup) = codec_find_fixup(codec_id, subsystem_id) {
+        codec_apply_fixup(bus, codec, afg, fixup)?;
+    }
+    if let Some(patch) = patches.iter().find(|patch| patch.matches(codec_id, subsystem_id, codec)) {
+        patch::codec_apply_patch(bus, codec, patch)?;
+    }
+    Ok(().into())
+}
+
+fn codec_apply_fixup<B: BusIo>(bus: &mut B, codec: Codec, afg: Node, fixup: &CodecFixup) -> uefi::Result {
+    info!("codec {:?}: applying {} fixup", codec, fixup.name);
+    for pin in fixup.pins.iter() {
+        pin_set_config(bus, codec, pin.node, pin.config)?;
+    }
+    if let Some(gpio) = fixup.gpio.as_ref() {
+        codec_set_gpio(bus, codec, afg, gpio)?;
+    }
+    for &node in fixup.eapd.iter() {
+        let eapd = bus.exec(make_command(codec, node, HDA_VERB_GET_EAPDBTL_ENABLE, Param(0x0)))
+            .ignore_warning()?;
+        bus.exec(make_command(codec, node, HDA_VERB_SET_EAPDBTL_ENABLE, Param(eapd | HDA_PIN_EAPDBTL_EAPD_ENABLE_BIT)))?;
+    }
+    for verb in fixup.verbs.iter() {
+        bus.exec(make_command(codec, verb.node, verb.verb, Param(verb.payload)))?;
+    }
+    Ok(().into())
+}
+
+// Check if the function group has connected pins of the given
+// kind, so that a child for them is worth creating
+fn codec_has_output<B: BusIo>(bus: &mut B, codec: Codec, afg: Node, output: OutputKind) -> uefi::Result<bool> {
     let NodeDescriptor { start_id, count } = bus.exec(make_command(codec, afg, HDA_VERB_PARAMS, HDA_PARAM_NODE_COUNT))
         .ignore_warning()
         .map(parse_node_count)?;
+    for n in start_id..(start_id + count) {
+        let node = Node(n);
+        let caps = bus.exec(make_command(codec, node, HDA_VERB_PARAMS, HDA_PARAM_AUDIO_WIDGET_CAPABILITIES))
+            .ignore_warning()
+            .map(WidgetCapabilities::from)?;
+        if caps.typ() != HDA_WIDGET_PIN_COMPLEX {
+            continue;
+        }
+        let pin_caps = bus.exec(make_command(codec, node, HDA_VERB_PARAMS, HDA_PARAM_PIN_WIDGET_CAPABILITIES))
+            .ignore_warning()
+            .map(PinCapabilities::from)?;
+        let config = bus.exec(make_command(codec, node, HDA_VERB_GET_CONFIG_DEFAULT, Param(0x0)))
+            .ignore_warning()
+            .map(PinConfig::from)?;
+        if config.port_connectivity() != HDA_JACK_PORT_NONE &&
+            pin_output_kind(&caps, &pin_caps, &config) == Some(output) {
+            return Ok(true.into());
+        }
+    }
+    Ok(false.into())
+}
+
+fn codec_collect_nodes<B: BusIo>(bus: &mut B, device: &mut DeviceContext, pci: &PciIO, codec: Codec) -> uefi::Result<alloc::vec::Vec<PathNode>> {
+    let afg = device.afg;
+    let afg_nodes = bus.exec(make_command(codec, afg, HDA_VERB_PARAMS, HDA_PARAM_NODE_COUNT))
+        .ignore_warning()
+        .map(parse_node_count)?;
+    let NodeDescriptor { start_id, count } = afg_nodes;
     info!("sub nodes: {} nodes starting from {}", start_id, count);
     let mut result = alloc::vec::Vec::with_capacity(count as usize);
     for n in start_id..(start_id + count) {
@@ -1524,25 +3427,8 @@ fn codec_collect_nodes<B: BusIo>(bus: &mut B, device: &mut DeviceContext, pci: &
         let caps = bus.exec(make_command(codec, node, HDA_VERB_PARAMS, HDA_PARAM_AUDIO_WIDGET_CAPABILITIES))
             .ignore_warning()
             .map(WidgetCapabilities::from)?;
-        let len = bus.exec(make_command(codec, node, HDA_VERB_PARAMS, HDA_PARAM_CONNECTION_LIST_LENGTH))
+        let connections = codec_get_connections(bus, codec, node, &afg_nodes)
             .ignore_warning()?;
-        let mut connections = alloc::vec::Vec::new();
-        if len & HDA_CONNECTION_LIST_LENGTH_MASK != 0 {
-            let mut step = 2;
-            if len & HDA_CONNECTION_LIST_LONG_BIT == 0 {
-                step += 2;
-            }
-            for i in (0..(len & HDA_CONNECTION_LIST_LENGTH_MASK)).step_by(step) {
-                let mut cls = bus.exec(make_command(codec, node, HDA_VERB_GET_CONNECTION_LIST, Param(i)))
-                    .ignore_warning()?;
-                for _ in 0..step {
-                    if cls & 0xff != 0 {
-                        connections.push(Node(cls & 0xff));
-                    }
-                    cls >>= 8;
-                }
-            }
-        }
         let config = bus.exec(make_command(codec, node, HDA_VERB_GET_CONFIG_DEFAULT, Param(0x0)))
             .ignore_warning()
             .map(PinConfig::from)?;
@@ -1569,11 +3455,7 @@ fn codec_collect_nodes<B: BusIo>(bus: &mut B, device: &mut DeviceContext, pci: &
                     .map(PinCapabilities::from)?;
                 let mut presence = None;
                 if pin_caps.presence_detect_capable() != 0 {
-                    if pin_caps.trigger_required() != 0 {
-                        bus.exec(make_command(codec, node, HDA_VERB_EXECUTE_PIN_SENSE, Param(0x0)))
-                            .ignore_warning()?;
-                    }
-                    let response = bus.exec(make_command(codec, node, HDA_VERB_GET_PIN_SENSE, Param(0x0)))
+                    let response = pin_sense(bus, codec, node, &pin_caps)
                         .ignore_warning()?;
                     presence = Some((response & HDA_PIN_SENSE_PRESENCE_DETECT) != 0);
                 }
@@ -1622,6 +3504,10 @@ impl<T: Sized> Fifo<T> {
         self.elems.push(value);
     }
 
+    fn len(&self) -> usize {
+        self.elems.len()
+    }
+
     fn pop(&mut self) -> Option<T> {
         if self.elems.is_empty() {
             None
@@ -1629,6 +3515,12 @@ impl<T: Sized> Fifo<T> {
             Some(self.elems.remove(0))
         }
     }
+
+    // Remove the oldest element accepted by pred
+    fn take<P: Fn(&T) -> bool>(&mut self, pred: P) -> Option<T> {
+        let index = self.elems.iter().position(pred)?;
+        Some(self.elems.remove(index))
+    }
 }
 
 struct NodeMap<T>(alloc::vec::Vec<Option<T>>);
@@ -1664,13 +3556,23 @@ impl<T> NodeMap<T> {
     }
 }
 
-fn hda_find_dac<'a, F: Fn(Node) -> Option<&'a PathNode>>(vertices: F, start: Node) -> Option<alloc::vec::Vec<Node>> {
+// Breadth-first search for the closest DAC that does not go
+// through any of the blocked nodes
+fn hda_find_dac<'a, F: Fn(Node) -> Option<&'a PathNode>, G: Fn(Node) -> bool>(vertices: F, start: Node, blocked: G) -> Option<alloc::vec::Vec<Node>> {
+    hda_find_path(vertices, start, PathNode::is_dac, blocked)
+}
+
+// Breadth first search for the closest node accepted by target
+fn hda_find_path<'a, F: Fn(Node) -> Option<&'a PathNode>, G: Fn(Node) -> bool, T: Fn(&PathNode) -> bool>(vertices: F, start: Node, target: T, blocked: G) -> Option<alloc::vec::Vec<Node>> {
     let mut queue = Fifo::new();
     queue.push(start);
     let mut path = NodeMap::<Node>::new();
     while let Some(pivot) = queue.pop() {
+        if pivot != start && blocked(pivot) {
+            continue;
+        }
         if let Some(path_node) = vertices(pivot) {
-            if path_node.is_dac() {
+            if target(path_node) {
                 let mut result = alloc::vec::Vec::new();
                 let mut pivot = pivot;
                 while let Some(parent) = path.get(pivot) {
@@ -1680,7 +3582,7 @@ fn hda_find_dac<'a, F: Fn(Node) -> Option<&'a PathNode>>(vertices: F, start: Nod
                 result.push(pivot);
                 return Some(result);
             }
-            for &succ in path_node.successors() {
+            for &succ in path_node.successors().iter().flatten() {
                 if !path.contains_key(&succ) {
                     path.insert(&succ, pivot);
                     queue.push(succ);
@@ -1698,12 +3600,12 @@ fn find_path_connection_index<'a, F: Fn(Node) -> Option<&'a PathNode>>(vertices:
     vertices(start)?
         .successors()
         .iter()
-        .position(|&node| node == next)
+        .position(|&node| node == Some(next))
 }
 
 fn get_path_next_node(path: &[Node], node: Node) -> Option<Node> {
     // Note that the path is not reversed by
-    // hda_find_dac(). Thus we are actually looking for the
+    // hda_find_path(). Thus we are actually looking for the
     // previous node
     path
         .windows(2)
@@ -1711,35 +3613,103 @@ fn get_path_next_node(path: &[Node], node: Node) -> Option<Node> {
         .map(|xs| xs[0])
 }
 
-fn codec_setup_stream<B: BusIo>(bus: &mut B, device: &mut DeviceContext, pci: &PciIO, codec: Codec, format: u16) -> uefi::Result {
-    let afg = find_audio_function_node(bus, pci, codec)
-        .ignore_warning()?;
+// Order the output pins the way OS drivers interpret the pin
+// defaults: the primary outputs (line-outs, or speakers if there
+// are none, or else headphones) go first so that they get the
+// dedicated DACs, then headphones, speakers and the rest. Within
+// a group pins are ordered by association and sequence. Pins
+// with no physical connection are left out.
+fn order_output_pins(nodes: &[PathNode]) -> alloc::vec::Vec<&PathNode> {
+    let mut pins = nodes
+        .iter()
+        .filter(|node| match node.pin_config() {
+            Some(config) if config.port_connectivity() == HDA_JACK_PORT_NONE => {
+                info!("{:?} is not connected", node.node());
+                false
+            },
+            Some(_) => true,
+            None => false,
+        })
+        .collect::<alloc::vec::Vec<_>>();
+    let primary = [HDA_JACK_LINE_OUT, HDA_JACK_SPEAKER, HDA_JACK_HP_OUT]
+        .iter()
+        .cloned()
+        .find(|&device| pins.iter().any(|pin| pin.pin_config().map(PinConfig::device) == Some(device)));
+    let priority = |device| {
+        if Some(device) == primary {
+            0
+        } else {
+            match device {
+                HDA_JACK_HP_OUT => 1,
+                HDA_JACK_SPEAKER => 2,
+                HDA_JACK_LINE_OUT => 3,
+                _ => 4,
+            }
+        }
+    };
+    // Table 112. Association 15 has the lowest priority and 0
+    // is reserved
+    let association = |association| if association == 0 { 16 } else { association };
+    pins.sort_by_key(|pin| {
+        let config = pin.pin_config().unwrap();
+        (priority(config.device()), association(config.association()), config.sequence())
+    });
+    pins
+}
+
+// Lowest stream channel of a pin of a multichannel association.
+// Sequence numbers may have gaps, so like Linux the pins are
+// ranked by sequence: two pins are front and back, more are
+// front, center/LFE, back and side. Samples are interleaved in
+// WAVE order: FL FR FC LFE BL BR SL SR. Pins whose channels the
+// stream does not have repeat the front ones.
+fn surround_channel(rank: usize, pins: usize, channels: u32) -> u32 {
+    let (center_lfe, back, side) = match channels {
+        4 => (None, Some(2), None),
+        6 => (Some(2), Some(4), None),
+        8 => (Some(2), Some(4), Some(6)),
+        _ => (None, None, None),
+    };
+    let channel = match (rank, pins) {
+        (1, 2) => back,
+        (1, _) => center_lfe,
+        (2, _) => back,
+        (3, _) => side,
+        _ => None,
+    };
+    channel.unwrap_or(0)
+}
+
+// Widgets in reserved are used by a running stream of another
+// child of the function group and are left alone
+fn codec_setup_stream<B: BusIo>(bus: &mut B, device: &mut DeviceContext, pci: &PciIO, codec: Codec, format: u16, reserved: &[Node]) -> uefi::Result {
+    let afg = device.afg;
     let NodeDescriptor { start_id, count } = bus.exec(make_command(codec, afg, HDA_VERB_PARAMS, HDA_PARAM_NODE_COUNT))
         .ignore_warning()
         .map(parse_node_count)?;
     info!("sub nodes: {} nodes starting from {}", start_id, count);
     pin_power(bus, codec, afg, true)?;
-    let afg_amp_caps = bus.exec(make_command(codec, afg, HDA_VERB_PARAMS, HDA_PARAM_AMPLIFIER_OUTPUT_CAPABILITY))
-        .ignore_warning()
-        .map(AmpCapabilities::from)?;
-    info!("AFG Output Amplifier Capabilities: {:#?}", afg_amp_caps);
+    let afg_amps = afg_get_amps(bus, codec, afg)
+        .ignore_warning()?;
+    info!("AFG Amplifier Capabilities: {:#?}", afg_amps);
     // As preparation we power-up all widgets and accomplish
     // basic preparations so to minimize the warmup time
-    for n in start_id..(start_id + count) {
+    for n in (start_id..(start_id + count)).filter(|&n| !reserved.contains(&Node(n))) {
         pin_power(bus, codec, Node(n), true)?;
-        // TBD: for mixers and selectors we need to enable input as well
+        // Inputs of mixers and selectors are unmuted along the path
         pin_enable_output(bus, codec, Node(n), true)?;
     }
-    // Collect appropriate nodes and filter out digital out
-    // and output-incapable PINs
+    // Collect appropriate nodes and filter out output-incapable
+    // PINs and the ones driven by the other child
     // TBD: filter DACs that do not support requested PCM format
+    let output = device.output;
     let nodes = codec_collect_nodes(bus, device, pci, codec)
         .ignore_warning()?
         .into_iter()
+        .filter(|node| !reserved.contains(&node.node()))
         .filter(|node| {
-            if let PathNode::PinComplex {ref caps, ref pin_caps, ..} = node {
-                pin_caps.output_capable() != 0 &&
-                    caps.digital() == 0
+            if let PathNode::PinComplex {..} = node {
+                node.output_kind() == Some(output)
             } else {
                 true
             }
@@ -1752,133 +3722,450 @@ fn codec_setup_stream<B: BusIo>(bus: &mut B, device: &mut DeviceContext, pci: &P
         node_map.insert(&path_node.node(), path_node);
     }
     let vertices = |node| node_map.get(node).cloned();
+    let amp_map = codec_get_amps(bus, codec, &afg_amps, nodes.as_slice())
+        .ignore_warning()?;
+    let amps = |node| amp_map.get(node).cloned().unwrap_or_default();
     let mut active_nodes = NodeMap::new();
-    for headphones in [ true, false ] {
-        // TBD: how association and sequence can be useful?
-        // TBD: pin presence status can change at any time. we have to
-        //      to poll or wait for unsolicited event
-        for pin_node in nodes.iter().filter(|path_node| headphones == path_node.is_headphones()) {
-            if let PathNode::PinComplex {..} = pin_node {
-                if let Some(path) = hda_find_dac(vertices, pin_node.node()) {
-                    info!("found DAC for {:?}: {:?}, headphones: {}", pin_node.node(), path, headphones);
-                    // In case if node is already configured
-                    // formerly, look for it in the
-                    // active_nodes list and skip it.
-                    for path_node in nodes.iter().filter(|path_node| !active_nodes.contains_key(&path_node.node())) {
-                        if path.contains(&path_node.node()) {
-                            pin_mute_unmute(bus, codec, Some(&afg_amp_caps), path_node.node(), false)?;
+    let mut routes = alloc::vec::Vec::new();
+    let pins = order_output_pins(&nodes);
+    info!("codec_setup_stream: pin order {:?}", pins.iter().map(|pin| pin.node()).collect::<alloc::vec::Vec<_>>());
+    // Pins of the association of the first line-out or speaker
+    // pin carry the channels of a multichannel stream
+    let channels = pcm_format_channels(format);
+    let surround_association = pins
+        .first()
+        .and_then(|pin| pin.pin_config())
+        .filter(|config| config.device() == HDA_JACK_LINE_OUT || config.device() == HDA_JACK_SPEAKER)
+        .map(PinConfig::association)
+        .filter(|&association| association != HDA_JACK_ASSOCIATION_INDIVIDUAL);
+    let mut surround_pins = pins
+        .iter()
+        .filter_map(|pin| pin.pin_config().map(|config| (config, pin.node())))
+        .filter(|(config, _)| Some(config.association()) == surround_association && config.device() != HDA_JACK_HP_OUT)
+        .map(|(config, node)| (config.sequence(), node))
+        .collect::<alloc::vec::Vec<_>>();
+    surround_pins.sort_by_key(|&(sequence, _)| sequence);
+    let stream_mask = device.stream.map_or(0, |stream| stream.tag_mask());
+    let spdif_control = device.spdif_control;
+    // Paths of all output pins are configured up front so that
+    // switching between headphones and speakers is only a
+    // matter of muting the pins and can be done while the
+    // stream is running.
+    for pin_node in pins.iter() {
+        if let PathNode::PinComplex {ref presence, ..} = pin_node {
+            let channel = surround_pins
+                .iter()
+                .position(|&(_, node)| node == pin_node.node())
+                .map_or(0, |rank| surround_channel(rank, surround_pins.len(), channels));
+            // Prefer a path of its own and share the DAC of a
+            // higher priority pin otherwise
+            let path = hda_find_dac(vertices, pin_node.node(), |node| active_nodes.contains_key(&node))
+                .or_else(|| hda_find_dac(vertices, pin_node.node(), |_| false));
+            if let Some(path) = path {
+                info!("found DAC for {:?}: {:?}, headphones: {}, channel: {}", pin_node.node(), path, pin_node.is_headphones(), channel);
+                // In case if node is already configured
+                // formerly, look for it in the
+                // active_nodes list and skip it.
+                for path_node in nodes.iter().filter(|path_node| !active_nodes.contains_key(&path_node.node())) {
+                    if path.contains(&path_node.node()) {
+                        // Pins are unmuted by codec_route_outputs()
+                        if path_node.node() != pin_node.node() {
+                            pin_mute_unmute(bus, codec, &amps(path_node.node()), path_node.node(), false)?;
                             pin_enable_eapd(bus, codec, path_node.node(), true)?;
-                            pin_enable_btl(bus, codec, path_node.node(), true)?;
-                            if let Some(next_node) = get_path_next_node(&path, path_node.node()) {
-                                if let Some(index) = find_path_connection_index(vertices, path_node.node(), next_node) {
-                                    // Note that it is not possible to override previous pin selection
-                                    // because we always choose lexigraphically first connection to a DAC
-                                    // and do not change the graph description during entire configuration step.
-                                    pin_select(bus, codec, path_node.node(), index)?;
+                        }
+                        pin_enable_btl(bus, codec, path_node.node(), true)?;
+                        if let Some(next_node) = get_path_next_node(&path, path_node.node()) {
+                            if let Some(index) = find_path_connection_index(vertices, path_node.node(), next_node) {
+                                // Note that previous pin selections are never overridden: nodes
+                                // shared with a higher priority pin are skipped above and keep
+                                // routing to the DAC chosen for that pin.
+                                pin_select(bus, codec, path_node.node(), index, path_node.successors().len())?;
+                                if matches!(path_node, PathNode::AudioMix {..} | PathNode::AudioMux {..}) {
+                                    let count = path_node.successors().len();
+                                    pin_select_input(bus, codec, &amps(path_node.node()), path_node.node(), index, count)?;
                                 }
                             }
-                            if path_node.is_dac() {
-                                codec_set_stream(bus, codec, path_node.node(), PCI_SDCTL8_STREAM_1_MASK)?;
-                                codec_set_format(bus, codec, path_node.node(), format)?;
+                        }
+                        if path_node.is_dac() {
+                            codec_set_stream(bus, codec, path_node.node(), stream_mask, channel)?;
+                            codec_set_format(bus, codec, path_node.node(), format)?;
+                            if pin_node.display_sink().is_some() {
+                                converter_setup_display(bus, codec, path_node.node(), format)?;
+                            } else if output == OutputKind::Spdif {
+                                converter_setup_spdif(bus, codec, path_node.node(), spdif_control)?;
                             }
                         }
                     }
-                    // Mark all just found nodes as active
-                    for node in path.iter() {
-                        active_nodes.insert(node, true);
-                    }
-                } else {
-                    info!("DAC not found: {:?}, headphones: {}", pin_node.node(), headphones);
                 }
+                // Mark all just found nodes as active
+                for node in path.iter() {
+                    active_nodes.insert(node, true);
+                }
+                routes.push(OutputRoute {
+                    pin: pin_node.node(),
+                    headphones: pin_node.is_headphone_jack(),
+                    display: pin_node.display_sink(),
+                    presence: *presence,
+                    eld_valid: device.jacks
+                        .iter()
+                        .find(|jack| jack.node == pin_node.node())
+                        .map_or(false, |jack| jack.eld_valid),
+                    enabled: None,
+                    amps: amps(pin_node.node()),
+                });
+            } else {
+                info!("DAC not found: {:?}", pin_node.node());
             }
         }
-        if !active_nodes.is_empty() {
-            // Stop if headphones were detected and unmuted
+    }
+    // Adjust volume level on all volume knobs that are
+    // wired to any active nodes.
+    for knob_node in nodes.iter() {
+        if let PathNode::Volume {ref knob_capabilities, ..} = knob_node {
+            // TBD: use dfs to trace path to an active node?
+            let wired_to_an_active_node = knob_node
+                .successors()
+                .iter()
+                .flatten()
+                .any(|succ| active_nodes.contains_key(succ));
+            if wired_to_an_active_node {
+                // TBD: mute the node otherwise?
+                info!("activate volume knob {:?}", knob_node.node());
+                pin_set_volume(bus, codec, knob_node.node(), knob_capabilities)?;
+            }
+        }
+        // Mark volume knob as active
+        active_nodes.insert(&knob_node.node(), true);
+    }
+    // Mute everything else
+    for path_node in nodes.iter().filter(|path_node| !active_nodes.contains_key(&path_node.node())) {
+        pin_mute_unmute(bus, codec, &amps(path_node.node()), path_node.node(), true)?;
+        if path_node.is_dac() {
+            codec_set_stream(bus, codec, path_node.node(), 0, 0)?;
+            codec_set_format(bus, codec, path_node.node(), 0)?;
+        }
+    }
+    // Power down widgets that are not on any path again. Pins
+    // stay in D0 so that jack detection keeps working until
+    // the whole group is powered down.
+    let mut powered = alloc::vec::Vec::new();
+    for path_node in nodes.iter().filter(|path_node| path_node.has_power_control()) {
+        if active_nodes.contains_key(&path_node.node()) {
+            powered.push(path_node.node());
+        } else if path_node.pin_config().is_none() {
+            pin_power(bus, codec, path_node.node(), false)?;
+        }
+    }
+    device.routing = Some(OutputRouting {
+        format,
+        routes,
+        powered,
+        nodes: nodes
+            .iter()
+            .map(PathNode::node)
+            .filter(|node| active_nodes.contains_key(node))
+            .collect()
+    });
+    codec_route_outputs(bus, device)
+}
+
+// Enable the headphones if any are plugged in and everything
+// else otherwise. HDMI/DP pins do not depend on headphones and
+// are enabled whenever a monitor that accepts the stream format
+// is connected. Only the pins are touched so this is safe to do
+// while the stream is running.
+fn codec_route_outputs<B: BusIo>(bus: &mut B, device: &mut DeviceContext) -> uefi::Result {
+    let codec = device.codec;
+    let routing = match device.routing.as_mut() {
+        Some(routing) => routing,
+        None => return Ok(().into())
+    };
+    let headphones = routing.routes
+        .iter()
+        .any(OutputRoute::is_headphones);
+    for route in routing.routes.iter_mut() {
+        let mut enable = match route.display {
+            Some(_) => route.presence.unwrap_or(true),
+            None => !headphones || route.is_headphones(),
+        };
+        if route.enabled == Some(enable) {
+            continue;
+        }
+        info!("codec_route_outputs: {:?} enable: {}, headphones: {}", route.pin, enable, headphones);
+        if enable {
+            if let Some(sink) = route.display {
+                enable = pin_setup_display(bus, codec, route.pin, sink, routing.format)
+                    .ignore_warning()?;
+            }
+        }
+        pin_mute_unmute(bus, codec, &route.amps, route.pin, !enable)?;
+        pin_enable_output(bus, codec, route.pin, enable)?;
+        pin_enable_eapd(bus, codec, route.pin, enable)?;
+        route.enabled = Some(enable);
+    }
+    Ok(().into())
+}
+
+// Refresh the presence of the output pins. Pins with an
+// unsolicited response tag are kept up to date by the timer or
+// by device_poll_unsolicited(), the rest are polled. Returns true if anything has changed.
+fn codec_sense_outputs<B: BusIo>(bus: &mut B, device: &mut DeviceContext) -> uefi::Result<bool> {
+    let codec = device.codec;
+    let mut changed = mem::replace(&mut device.jacks_changed, false);
+    let jacks = &device.jacks;
+    let routing = match device.routing.as_mut() {
+        Some(routing) => routing,
+        None => return Ok(false.into())
+    };
+    for route in routing.routes.iter_mut().filter(|route| route.presence.is_some()) {
+        let (presence, eld_valid) = match jacks.iter().find(|jack| jack.node == route.pin) {
+            Some(jack) => (jack.presence, jack.eld_valid),
+            None => {
+                let pin_caps = bus.exec(make_command(codec, route.pin, HDA_VERB_PARAMS, HDA_PARAM_PIN_WIDGET_CAPABILITIES))
+                    .ignore_warning()
+                    .map(PinCapabilities::from)?;
+                let sense = pin_sense(bus, codec, route.pin, &pin_caps)
+                    .ignore_warning()?;
+                ((sense & HDA_PIN_SENSE_PRESENCE_DETECT) != 0,
+                 (sense & HDA_PIN_SENSE_ELD_VALID) != 0)
+            }
+        };
+        if route.presence != Some(presence) {
+            info!("codec_sense_outputs: {:?} presence: {}", route.pin, presence);
+            route.presence = Some(presence);
+            changed = true;
+        }
+        // A new ELD means another monitor, so check the stream
+        // format and send the infoframe again
+        if route.display.is_some() && route.eld_valid != eld_valid {
+            info!("codec_sense_outputs: {:?} ELD valid: {}", route.pin, eld_valid);
+            route.eld_valid = eld_valid;
+            route.enabled = None;
+            changed = true;
+        }
+    }
+    Ok(changed.into())
+}
+
+// Assign unsolicited response tags to all pins with presence
+// detection so that jack and ELD changes are reported to us.
+// Tags are unique per codec and so function groups of the same
+// codec must use disjoint tags starting from first_tag.
+// Returns the first unused tag.
+fn codec_enable_unsolicited<B: BusIo>(bus: &mut B, device: &mut DeviceContext, pci: &PciIO, first_tag: u32) -> uefi::Result<u32> {
+    let codec = device.codec;
+    let afg = device.afg;
+    let NodeDescriptor { start_id, count } = bus.exec(make_command(codec, afg, HDA_VERB_PARAMS, HDA_PARAM_NODE_COUNT))
+        .ignore_warning()
+        .map(parse_node_count)?;
+    let mut jacks = alloc::vec::Vec::new();
+    for n in start_id..(start_id + count) {
+        let node = Node(n);
+        let caps = bus.exec(make_command(codec, node, HDA_VERB_PARAMS, HDA_PARAM_AUDIO_WIDGET_CAPABILITIES))
+            .ignore_warning()
+            .map(WidgetCapabilities::from)?;
+        if caps.typ() != HDA_WIDGET_PIN_COMPLEX || caps.unsol_capable() == 0 {
+            continue;
+        }
+        let pin_caps = bus.exec(make_command(codec, node, HDA_VERB_PARAMS, HDA_PARAM_PIN_WIDGET_CAPABILITIES))
+            .ignore_warning()
+            .map(PinCapabilities::from)?;
+        if pin_caps.presence_detect_capable() == 0 {
+            continue;
+        }
+        // Each pin is reported to the child driving it only
+        let config = bus.exec(make_command(codec, node, HDA_VERB_GET_CONFIG_DEFAULT, Param(0x0)))
+            .ignore_warning()
+            .map(PinConfig::from)?;
+        let spdif = pin_output_kind(&caps, &pin_caps, &config) == Some(OutputKind::Spdif);
+        if spdif != (device.output == OutputKind::Spdif) {
+            continue;
+        }
+        let tag = first_tag + jacks.len() as u32;
+        if tag > HDA_UNSOLICITED_RESPONSE_TAG_MASK {
+            warn!("out of unsolicited response tags at {:?}", node);
             break;
         }
+        bus.exec(make_command(codec, node, HDA_VERB_SET_UNSOLICITED_RESPONSE, Param(HDA_UNSOLICITED_RESPONSE_ENABLE_BIT | tag)))?;
+        let readback = bus.exec(make_command(codec, node, HDA_VERB_GET_UNSOLICITED_RESPONSE, Param(0x0)))
+            .ignore_warning()?;
+        let sense = pin_sense(bus, codec, node, &pin_caps)
+            .ignore_warning()?;
+        let jack = JackState {
+            node,
+            tag,
+            presence: (sense & HDA_PIN_SENSE_PRESENCE_DETECT) != 0,
+            eld_valid: (sense & HDA_PIN_SENSE_ELD_VALID) != 0,
+        };
+        info!("codec_enable_unsolicited: {:?}, readback: {:#x}", jack, readback);
+        jacks.push(jack);
     }
-    // Adjust volume level on all volume knobs that are
-    // wired to any active nodes.
-    for knob_node in nodes.iter() {
-        if let PathNode::Volume {..} = knob_node {
-            // TBD: use dfs to trace path to an active node?
-            let wired_to_an_active_node = knob_node
-                .successors()
-                .iter()
-                .any(|succ| active_nodes.contains_key(succ));
-            if wired_to_an_active_node {
-                // TBD: mute the node otherwise?
-                info!("activate volume knob {:?}", knob_node.node());
-                // TBD: check volume knob capabilities
-                pin_set_volume(bus, codec, knob_node.node());
-            }
+    let next_tag = first_tag + jacks.len() as u32;
+    device.jacks = jacks;
+    Ok(next_tag.into())
+}
+
+fn device_handle_unsolicited<B: BusIo>(bus: &mut B, device: &mut DeviceContext, response: UnsolicitedResponse) -> uefi::Result {
+    let codec = device.codec;
+    let jack = match device.jacks.iter_mut().find(|jack| jack.tag == response.tag()) {
+        Some(jack) => jack,
+        None => {
+            warn!("{:?} from codec {:?} has unknown tag", response, codec);
+            return Ok(().into());
         }
-        // Mark volume knob as active
-        active_nodes.insert(&knob_node.node(), true);
+    };
+    // The response payload is widget specific so read the
+    // actual pin state instead
+    let pin_caps = bus.exec(make_command(codec, jack.node, HDA_VERB_PARAMS, HDA_PARAM_PIN_WIDGET_CAPABILITIES))
+        .ignore_warning()
+        .map(PinCapabilities::from)?;
+    let sense = pin_sense(bus, codec, jack.node, &pin_caps)
+        .ignore_warning()?;
+    let presence = (sense & HDA_PIN_SENSE_PRESENCE_DETECT) != 0;
+    let eld_valid = (sense & HDA_PIN_SENSE_ELD_VALID) != 0;
+    if presence != jack.presence || eld_valid != jack.eld_valid {
+        info!("jack {:?} of codec {:?} changed, presence: {}, ELD valid: {}",
+              jack.node, codec, presence, eld_valid);
+        jack.presence = presence;
+        jack.eld_valid = eld_valid;
+        device.jacks_changed = true;
     }
-    // Mute everything else
-    for path_node in nodes.iter().filter(|path_node| !active_nodes.contains_key(&path_node.node())) {
-        pin_mute_unmute(bus, codec, Some(&afg_amp_caps), path_node.node(), true)?;
-        if path_node.is_dac() {
-            codec_set_stream(bus, codec, path_node.node(), 0)?;
-            codec_set_format(bus, codec, path_node.node(), 0)?;
+    Ok(().into())
+}
+
+// Dispatch the unsolicited responses for the jacks of the
+// device while it is playing and the timer stays away. Those of
+// other function groups of the codec are left queued.
+fn device_poll_unsolicited<B: BusIo>(bus: &mut B, device: &mut DeviceContext) -> uefi::Result {
+    bus.poll()?;
+    let tags = device.jacks
+        .iter()
+        .map(|jack| jack.tag)
+        .collect::<alloc::vec::Vec<_>>();
+    for tag in tags {
+        while let Some(response) = bus.take_unsolicited_tag(device.codec, tag) {
+            device_handle_unsolicited(bus, device, response)?;
         }
     }
     Ok(().into())
 }
 
+// Jacks keep their tags across a link reset
+fn codec_restore_unsolicited<B: BusIo>(bus: &mut B, device: &mut DeviceContext) -> uefi::Result {
+    let codec = device.codec;
+    for jack in device.jacks.iter_mut() {
+        bus.exec(make_command(codec, jack.node, HDA_VERB_SET_UNSOLICITED_RESPONSE, Param(HDA_UNSOLICITED_RESPONSE_ENABLE_BIT | jack.tag)))?;
+        // Changes while the link was down were not reported
+        let pin_caps = bus.exec(make_command(codec, jack.node, HDA_VERB_PARAMS, HDA_PARAM_PIN_WIDGET_CAPABILITIES))
+            .ignore_warning()
+            .map(PinCapabilities::from)?;
+        let sense = pin_sense(bus, codec, jack.node, &pin_caps)
+            .ignore_warning()?;
+        jack.presence = (sense & HDA_PIN_SENSE_PRESENCE_DETECT) != 0;
+        jack.eld_valid = (sense & HDA_PIN_SENSE_ELD_VALID) != 0;
+    }
+    device.jacks_changed = true;
+    device.jacks_stale = false;
+    Ok(().into())
+}
+
+// Widgets left in D0 by the children of the function group go
+// first, then the group itself. codec_setup_stream() powers
+// everything up again. Pins follow the group to D3 where
+// presence detection is not guaranteed, so the jacks of the
+// children are sensed again on wake.
+fn codec_power_down<'a, B: BusIo, I: Iterator<Item = &'a mut DeviceContext>>(bus: &mut B, codec: Codec, afg: Node, devices: I) -> uefi::Result {
+    info!("codec_power_down: {:?} of codec {:?}", afg, codec);
+    for device in devices {
+        device.jacks_stale = true;
+        if let Some(routing) = device.routing.as_mut() {
+            for node in routing.powered.drain(..) {
+                pin_power(bus, codec, node, false)?;
+            }
+        }
+    }
+    pin_power(bus, codec, afg, false)
+}
+
 fn stream_cleanup(device: &mut DeviceContext, pci: &PciIO) -> uefi::Result {
-    out_stream_1(device).bdpl().write(pci, 0)?;
-    out_stream_1(device).bdpu().write(pci, 0)?;
-    out_stream_1(device).ctl16().and(pci, !PCI_SDCTL16_RSVDP_MASK)?;
-    out_stream_1(device).ctl8().write(pci, 0)?;
+    device_stream(device).bdpl().write(pci, 0)?;
+    device_stream(device).bdpu().write(pci, 0)?;
+    device_stream(device).ctl16().and(pci, !PCI_SDCTL16_RSVDP_MASK)?;
+    device_stream(device).ctl8().write(pci, 0)?;
     uefi::Status::SUCCESS.into()
 }
 
 fn stream_reset(device: &mut DeviceContext, pci: &PciIO) -> uefi::Result {
     stream_clear(device, pci)?;
     // enter reset state
-    out_stream_1(device).ctl16().or(pci, PCI_SDCTL16_SRST_BIT)?;
-    out_stream_1(device).ctl16().wait(pci, 1000, PCI_SDCTL16_SRST_BIT, PCI_SDCTL16_SRST_BIT)?;
+    device_stream(device).ctl16().or(pci, PCI_SDCTL16_SRST_BIT)?;
+    device_stream(device).ctl16().wait(pci, 1000, PCI_SDCTL16_SRST_BIT, PCI_SDCTL16_SRST_BIT)?;
     // leave reset state
-    out_stream_1(device).ctl16().and(pci, !PCI_SDCTL16_SRST_BIT)?;
-    out_stream_1(device).ctl16().wait(pci, 1000, PCI_SDCTL16_SRST_BIT, 0)?;
+    device_stream(device).ctl16().and(pci, !PCI_SDCTL16_SRST_BIT)?;
+    device_stream(device).ctl16().wait(pci, 1000, PCI_SDCTL16_SRST_BIT, 0)?;
+    uefi::Status::SUCCESS.into()
+}
+
+// Reset the stream after a FIFO or descriptor error. SRST
+// clears all stream registers so the ones programmed by
+// stream_setup() are saved and written back (3.3.35).
+fn stream_recover(device: &mut DeviceContext, pci: &PciIO) -> uefi::Result {
+    let sd = device_stream(device);
+    let ctl8 = sd.ctl8().read(pci).ignore_warning()?;
+    let cbl = sd.cbl().read(pci).ignore_warning()?;
+    let lvi = sd.lvi().read(pci).ignore_warning()?;
+    let fmt = sd.fmt().read(pci).ignore_warning()?;
+    let bdpl = sd.bdpl().read(pci).ignore_warning()?;
+    let bdpu = sd.bdpu().read(pci).ignore_warning()?;
+    stream_stop(device, pci)?;
+    stream_reset(device, pci)?;
+    sd.ctl8().write(pci, ctl8 & (PCI_SDCTL8_STREAM_MASK | PCI_SDCTL8_DIR_BIT))?;
+    sd.cbl().write(pci, cbl)?;
+    sd.fmt().update(pci, fmt, !PCI_SDFMT_RSVDP_MASK)?;
+    sd.lvi().update(pci, lvi, !PCI_SDLVI_RSVDP_MASK)?;
+    sd.bdpl().write(pci, bdpl)?;
+    sd.bdpu().write(pci, bdpu)?;
+    sd.ctl16().or(pci, PCI_SDCTL16_INT_MASK)?;
     uefi::Status::SUCCESS.into()
 }
 
-fn stream_setup(device: &mut DeviceContext, pci: &PciIO, mapping: &uefi::proto::pci::Mapping, loop_buffers: u32, loop_samples: u32, format: u16) -> uefi::Result {
+fn stream_setup(device: &mut DeviceContext, pci: &PciIO, device_address: u64, loop_buffers: u32, loop_samples: u32, format: u16) -> uefi::Result {
     info!("stream_setup, buffers: {}, samples: {}, format: {:#x}", loop_buffers, loop_samples, format);
     // TBD: make sure the run bit is zero for SD like so
     // stream_clear(device, pci)?;
     // set the stream tag
-    out_stream_1(device)
+    device_stream(device)
         .ctl8()
-        .write(pci, PCI_SDCTL8_STREAM_1_MASK)?;
+        .write(pci, device.stream.map_or(0, |stream| stream.tag_mask()))?;
+    if device.stream.map_or(false, |stream| stream.bidirectional) {
+        stream_set_direction(&device_stream(device), pci, true)?;
+    }
     // the length of samples in cyclic buffer is in bytes
-    out_stream_1(device)
+    device_stream(device)
         .cbl()
         .write(pci, loop_samples * mem::size_of::<i16>() as u32)?;
     // set the stream format
-    out_stream_1(device)
+    device_stream(device)
         .fmt()
         .update(pci, format, !PCI_SDFMT_RSVDP_MASK)?;
     // set the stream LVI of the BDL
-    out_stream_1(device)
+    device_stream(device)
         .lvi()
         .update(pci, loop_buffers as u16 - 1, !PCI_SDLVI_RSVDP_MASK)?;
     // set the BDL address
-    if ((mapping.device_address() & 0xffffffff) as u32 & !PCI_SDBDPL_MASK) != 0 {
-        error!("mapping address is invalid {:#x}", mapping.device_address());
+    if ((device_address & 0xffffffff) as u32 & !PCI_SDBDPL_MASK) != 0 {
+        error!("mapping address is invalid {:#x}", device_address);
         return uefi::Status::INVALID_PARAMETER.into();
     }
-    out_stream_1(device)
+    bus_check_dma_address(pci, device_address, mem::size_of::<BufferDescriptorListWithBuffers>())?;
+    device_stream(device)
         .bdpl()
-        .write(pci, (mapping.device_address() & 0xffffffff) as u32)?;
-    out_stream_1(device)
+        .write(pci, (device_address & 0xffffffff) as u32)?;
+    device_stream(device)
         .bdpu()
-        .write(pci, ((mapping.device_address() >> 32) & 0xffffffff) as u32)?;
+        .write(pci, ((device_address >> 32) & 0xffffffff) as u32)?;
     // enable all interrupts in SD though we dont use them at the moment
-    out_stream_1(device)
+    device_stream(device)
         .ctl16()
         .or(pci, PCI_SDCTL16_INT_MASK)?;
     uefi::Status::SUCCESS.into()
@@ -1886,13 +4173,13 @@ fn stream_setup(device: &mut DeviceContext, pci: &PciIO, mapping: &uefi::proto::
 
 fn stream_start(device: &mut DeviceContext, pci: &PciIO) -> uefi::Result {
     // enable SIE interrupt bit; we don't use interrupts atm
-    INTCTL.or(pci, out_stream_1(device).intctl_mask())?;
+    INTCTL.or(pci, device_stream(device).intctl_mask())?;
     // set stripe to 0 even though it is meaningless for output streams
-    out_stream_1(device)
+    device_stream(device)
         .ctl8()
         .and(pci, !PCI_SDCTL8_STRIPE_MASK)?;
     // start DMA; next step is to wait for SDSTS.FIFOREADY
-    out_stream_1(device)
+    device_stream(device)
         .ctl16()
         .or(pci, PCI_SDCTL16_RUN_BIT | PCI_SDCTL16_INT_MASK)?;
     // TBD: a better way to check that FIFO is ready? prefill up to FIFOS bytes?
@@ -1904,7 +4191,7 @@ fn stream_start(device: &mut DeviceContext, pci: &PciIO) -> uefi::Result {
     // required to maintain the stream will depend on the
     // controller implementation but, in general, for an
     // output stream, it means that the FIFO is full."
-    out_stream_1(device)
+    device_stream(device)
         .sts()
         .wait(pci, 1000, PCI_SDSTS_READY_BIT, PCI_SDSTS_READY_BIT)?;
     uefi::Status::SUCCESS.into()
@@ -1913,15 +4200,70 @@ fn stream_start(device: &mut DeviceContext, pci: &PciIO) -> uefi::Result {
 fn stream_stop(device: &mut DeviceContext, pci: &PciIO) -> uefi::Result {
     stream_clear(device, pci)?;
     // disable SIE; we don't use interrupts atm
-    INTCTL.and(pci, !out_stream_1(device).intctl_mask())?;
-    out_stream_1(device)
+    INTCTL.and(pci, !device_stream(device).intctl_mask())?;
+    device_stream(device)
         .ctl16()
         .wait(pci, 1000, PCI_SDCTL16_RUN_BIT, 0)?;
     uefi::Status::SUCCESS.into()
 }
 
-fn stream_loop<C>(device: &mut DeviceContext, pci: &PciIO, control: &mut C, sample_count: u64, channel_count: u8, sampling_rate: u64, duration: u64) -> uefi::Result
-where C: DmaControl {
+// Stands in for the timer events of stream_loop() when it runs
+// above TPL_APPLICATION, where wait_for_event() is not allowed.
+// This is how a child plays from a callback while another one is
+// parked in wait_for_event(); the DMA of the latter keeps cycling
+// through its buffer until the nested playback returns.
+struct StreamPoll {
+    polling: bool,
+    period_ms: u64,
+    elapsed_ms: u64,
+    timeout_ms: u64,
+    next_jack_ms: u64,
+}
+
+impl StreamPoll {
+    fn new(period_ms: u64, timeout_ms: u64) -> StreamPoll {
+        StreamPoll {
+            polling: false,
+            period_ms,
+            elapsed_ms: 0,
+            timeout_ms,
+            next_jack_ms: JACK_POLL_PERIOD_MS,
+        }
+    }
+
+    fn restart(&mut self, timeout_ms: u64) {
+        self.timeout_ms = self.elapsed_ms + timeout_ms;
+    }
+
+    // Returns the index of the event that would have been
+    // signaled: 0 playback timeout, 1 refill, 2 jack poll
+    fn wait(&mut self, events: &mut [uefi::Event]) -> uefi::Result<usize> {
+        if !self.polling {
+            match boot_services().wait_for_event(events).discard_errdata() {
+                Err(error) if error.status() == uefi::Status::UNSUPPORTED => {
+                    info!("stream_loop: not at TPL_APPLICATION, polling");
+                    self.polling = true;
+                },
+                result => return result,
+            }
+        }
+        boot_services().stall(milliseconds_to_stall(self.period_ms as usize));
+        self.elapsed_ms += self.period_ms;
+        let index = if self.elapsed_ms >= self.timeout_ms {
+            0
+        } else if self.elapsed_ms >= self.next_jack_ms {
+            self.next_jack_ms += JACK_POLL_PERIOD_MS;
+            2
+        } else {
+            1
+        };
+        Ok(index.into())
+    }
+}
+
+fn stream_loop<B, C>(bus: &mut B, positions: Option<&mut DmaPositions>, device: &mut DeviceContext, pci: &PciIO, control: &mut C, channel_count: u8, sampling_rate: u64, duration: u64) -> uefi::Result
+where B: BusIo,
+      C: DmaControl {
     let playback_event = boot_services()
         .create_timer_event()
         .ignore_warning()
@@ -1930,10 +4272,16 @@ where C: DmaControl {
         .create_timer_event()
         .ignore_warning()
         .map(EventGuard::wrap)?;
-    // TBD: to prefill the buffers partially we must change
-    //      LVI which is only possible if RUN bit is deasserted
-    control.transfer(BUFFER_COUNT * BUFFER_SIZE);
-    let playback_time = milliseconds_to_timer_period(duration);
+    let jack_event = boot_services()
+        .create_timer_event()
+        .ignore_warning()
+        .map(EventGuard::wrap)?;
+    let layout = control.layout();
+    let loop_samples = layout.cbl;
+    control.transfer(loop_samples);
+    // Playback normally ends on IOC, the timer only guards
+    // against a stream that never reports it
+    let playback_time = milliseconds_to_timer_period(duration + STREAM_END_TIMEOUT_MS);
     boot_services()
         .set_timer(
             *playback_event,
@@ -1941,36 +4289,120 @@ where C: DmaControl {
     // TBD: this is basically called period length in alsa,
     //      maybe add as configuration parameter via
     //      DriverConfiguration?
-    let delay = milliseconds_to_timer_period(sample_count / u64::from(channel_count) / sampling_rate);
+    let period_ms = (1000 * layout.entry_size as u64 / u64::from(channel_count) / sampling_rate).max(1);
+    let delay = milliseconds_to_timer_period(period_ms);
+    let mut poll = StreamPoll::new(period_ms, duration + STREAM_END_TIMEOUT_MS);
     boot_services()
         .set_timer(
             *trace_event,
             uefi::table::boot::TimerTrigger::Periodic(delay))?;
+    boot_services()
+        .set_timer(
+            *jack_event,
+            uefi::table::boot::TimerTrigger::Periodic(milliseconds_to_timer_period(JACK_POLL_PERIOD_MS)))?;
+    let cbl = (loop_samples * mem::size_of::<i16>()) as u32;
+    let mut position = StreamPosition::new(device_stream(device), positions, cbl);
+    device_stream(device)
+        .sts()
+        .write(pci, PCI_SDSTS_IOC_BIT)?;
     stream_start(device, pci);
-    let mut start_lpib = out_stream_1(device)
-        .lpib()
+    let mut start_lpib = position
         .read(pci)
         .ignore_warning()?;
     // number of slots in DMA cyclic buffer ready to be utilized
     let mut queue_room = 0;
+    // samples consumed by DMA since the start, where playback
+    // is resumed after a stream error
+    let mut played = 0;
+    let mut last_lpib = start_lpib;
+    let mut fifo_errors = 0;
+    let mut descriptor_errors = 0;
     {
         loop {
-            let actual_lpib = out_stream_1(device)
-                .lpib()
+            let actual_lpib = position
                 .read(pci)
                 .ignore_warning()?;
+            played += if last_lpib <= actual_lpib {
+                (actual_lpib - last_lpib) as usize / mem::size_of::<i16>()
+            } else {
+                (cbl - last_lpib + actual_lpib) as usize / mem::size_of::<i16>()
+            };
+            last_lpib = actual_lpib;
             let room = if start_lpib <= actual_lpib {
                 queue_room
                     + actual_lpib as usize / mem::size_of::<i16>()
                     - start_lpib as usize / mem::size_of::<i16>()
             } else {
                 queue_room
-                    + BUFFER_SIZE * BUFFER_COUNT
+                    + loop_samples
                     + actual_lpib as usize / mem::size_of::<i16>()
                     - start_lpib as usize / mem::size_of::<i16>()
             };
-            if room as usize >= BUFFER_SIZE {
-                let copied = control.transfer(room - room % BUFFER_SIZE);
+            let status = device_stream(device)
+                .sts()
+                .read(pci)
+                .ignore_warning()?;
+            if status & (PCI_SDSTS_FEI_BIT | PCI_SDSTS_DEI_BIT) != 0 {
+                if status & PCI_SDSTS_FEI_BIT != 0 {
+                    fifo_errors += 1;
+                }
+                if status & PCI_SDSTS_DEI_BIT != 0 {
+                    descriptor_errors += 1;
+                }
+                warn!("stream error, status: {:#b}, played: {}, FIFO errors: {}, descriptor errors: {}",
+                      status, played, fifo_errors, descriptor_errors);
+                if fifo_errors + descriptor_errors > STREAM_MAX_RECOVERIES {
+                    error!("giving up the stream after {} FIFO and {} descriptor errors", fifo_errors, descriptor_errors);
+                    stream_stop(device, pci);
+                    return uefi::Status::DEVICE_ERROR.into();
+                }
+                // Resume from the first sample of the frame
+                // that was being played
+                played -= played % usize::from(channel_count);
+                let remaining = control.restart(played);
+                if remaining == 0 {
+                    info!("stream error after the last sample");
+                    break;
+                }
+                if let Err(error) = stream_recover(device, pci) {
+                    error!("failed to reset the stream after an error: {:?}", error.status());
+                    stream_stop(device, pci);
+                    return uefi::Status::DEVICE_ERROR.into();
+                }
+                control.transfer(loop_samples);
+                position.restart();
+                let remaining_time = 1000 * remaining as u64 / u64::from(channel_count) / sampling_rate;
+                poll.restart(remaining_time + STREAM_END_TIMEOUT_MS);
+                boot_services()
+                    .set_timer(
+                        *playback_event,
+                        uefi::table::boot::TimerTrigger::Relative(milliseconds_to_timer_period(remaining_time + STREAM_END_TIMEOUT_MS)))?;
+                if let Err(error) = stream_start(device, pci) {
+                    error!("failed to restart the stream after an error: {:?}", error.status());
+                    stream_stop(device, pci);
+                    return uefi::Status::DEVICE_ERROR.into();
+                }
+                start_lpib = position
+                    .read(pci)
+                    .ignore_warning()?;
+                last_lpib = start_lpib;
+                queue_room = 0;
+                continue;
+            }
+            if control.finished() && status & PCI_SDSTS_IOC_BIT != 0 {
+                device_stream(device)
+                    .sts()
+                    .write(pci, PCI_SDSTS_IOC_BIT)?;
+                info!("last sample played");
+                break;
+            }
+            // Only refill once IOC has been checked, the entries
+            // freed after the last sample must not be queued again
+            // before the stream is stopped
+            if room > 0 {
+                // transfer() only fills whole entries and never
+                // more than room, the rest is kept for later
+                let copied = control.transfer(room);
                 queue_room = room - copied;
                 start_lpib = actual_lpib;
             }
@@ -1978,15 +4410,38 @@ where C: DmaControl {
             // bus_trace_registers(pci)?;
             // Playback event must be placed first so that it
             // would be checked first
-            let index = boot_services()
-                .wait_for_event (&mut [*playback_event, *trace_event])
-                .discard_errdata()?;
-            if index.unwrap() == 0 {
-                break;
+            let index = poll.wait(&mut [*playback_event, *trace_event, *jack_event])?;
+            match index.unwrap() {
+                0 => {
+                    warn!("stream did not report IOC in time");
+                    break;
+                },
+                2 => {
+                    // Follow headphone plug/unplug without
+                    // stopping DMA
+                    let result = device_poll_unsolicited(bus, device)
+                        .ignore_warning()
+                        .and_then(|_| codec_sense_outputs(bus, device).ignore_warning())
+                        .and_then(|changed| {
+                            if changed {
+                                codec_route_outputs(bus, device)
+                                    .ignore_warning()
+                            } else {
+                                Ok(())
+                            }
+                        });
+                    if let Err(error) = result {
+                        warn!("failed to reroute outputs: {:?}", error.status());
+                    }
+                },
+                _ => {}
             }
         }
         info!("stopping stream");
     }
+    if fifo_errors + descriptor_errors > 0 {
+        info!("stream recovered from {} FIFO and {} descriptor errors", fifo_errors, descriptor_errors);
+    }
     stream_stop(device, pci);
 
     uefi::Status::SUCCESS.into()
@@ -2009,10 +4464,14 @@ fn stream_select_rate(device: &mut DeviceContext, pci: &PciIO, sampling_rate: u3
         .min_by_key(|&&guess| abs_diff(guess, sampling_rate))
         .cloned()
         .ok_or(uefi::Status::UNSUPPORTED)?;
-    if channel_count != 2 {
-        return Err(uefi::Status::UNSUPPORTED.into());
-    }
-    let format = PCM_FMT_CHAN_2_MASK | PCM_FMT_PACK_16_MASK | match closest_rate {
+    let channels = match channel_count {
+        2 => PCM_FMT_CHAN_2_MASK,
+        4 => PCM_FMT_CHAN_4_MASK,
+        6 => PCM_FMT_CHAN_6_MASK,
+        8 => PCM_FMT_CHAN_8_MASK,
+        _ => return Err(uefi::Status::UNSUPPORTED.into())
+    };
+    let format = channels | PCM_FMT_PACK_16_MASK | match closest_rate {
         AUDIO_RATE_8000 => { PCM_FMT_8000_MASK }
         AUDIO_RATE_11025 => { PCM_FMT_11025_MASK },
         AUDIO_RATE_16000 => { PCM_FMT_16000_MASK },
@@ -2027,9 +4486,17 @@ fn stream_select_rate(device: &mut DeviceContext, pci: &PciIO, sampling_rate: u3
 
 // TBD: add format/channel converters
 // TBD: add flow control/trottling/FIFOS handling
-// TBD: use IOC bit to gracefully stop playback
 trait DmaControl {
     fn transfer(&mut self, count: usize) -> usize;
+    // Geometry of the cyclic buffer, LVI is entries - 1
+    fn layout(&self) -> BdlLayout;
+    // All samples are queued and the descriptor holding the
+    // last one has IOC set
+    fn finished(&self) -> bool;
+    // Start over from the first descriptor with the given
+    // number of samples already played. Returns the number of
+    // samples left.
+    fn restart(&mut self, played: usize) -> usize;
 }
 
 struct Loop<'a> {
@@ -2037,45 +4504,209 @@ struct Loop<'a> {
     bdl: &'a mut BufferDescriptorListWithBuffers,
     bdl_position: usize,
     samples_position: usize,
+    layout: BdlLayout,
+    total: usize,
+    remaining: usize,
 }
 
 impl<'a> Loop<'a> {
-    fn new(bdl: &'a mut BufferDescriptorListWithBuffers, samples: &'a [i16]) -> Loop<'a> {
+    fn new(bdl: &'a mut BufferDescriptorListWithBuffers, samples: &'a [i16], layout: BdlLayout, total: usize) -> Loop<'a> {
         Loop {
             bdl,
             samples,
             bdl_position: 0,
-            samples_position: 0
+            samples_position: 0,
+            layout,
+            total,
+            remaining: total,
         }
     }
 }
 
 impl<'a> DmaControl for Loop<'a> {
     fn transfer(&mut self, count: usize) -> usize {
-        info!("transfer: count = {}", count);
+        let requested = count;
         let mut count = count;
         let mut total = 0;
-        while count > 0 {
-            let CopyResult {loop_buffers, loop_samples} =
+        loop {
+            let offset = self.layout.entry_offset(self.bdl_position);
+            // The last entry may be shorter than entry_size,
+            // stop at the first one that does not fit in count
+            let length = self.layout.entry_length(self.bdl_position);
+            if length == 0 || length > count {
+                break;
+            }
+            let CopyResult {loop_buffers, loop_samples, copied} =
                 fill_bde(
-                    &mut self.bdl.buffers[self.bdl_position],
+                    &mut self.bdl.samples[offset..offset+length],
                     &mut self.bdl.descriptors[self.bdl_position],
                     self.samples_position,
-                    self.samples
+                    self.samples,
+                    self.remaining
                 );
-            self.samples_position = (self.samples_position + loop_samples) % self.samples.len();
-            self.bdl_position = (self.bdl_position + loop_buffers) % BUFFER_COUNT;
+            if !self.samples.is_empty() {
+                self.samples_position = (self.samples_position + copied) % self.samples.len();
+            }
+            self.remaining -= copied;
+            self.bdl_position = (self.bdl_position + loop_buffers) % self.layout.entries;
             count -= loop_samples;
             total += loop_samples;
         }
+        if total > 0 {
+            info!("transfer: count = {}, queued = {}", requested, total);
+        }
         total
     }
+
+    fn layout(&self) -> BdlLayout {
+        self.layout
+    }
+
+    fn finished(&self) -> bool {
+        self.remaining == 0
+    }
+
+    fn restart(&mut self, played: usize) -> usize {
+        let played = played.min(self.total);
+        self.bdl_position = 0;
+        self.samples_position = if self.samples.is_empty() { 0 } else { played % self.samples.len() };
+        self.remaining = self.total - played;
+        self.remaining
+    }
+}
+
+// Divider of the beep generator for freq Hz, rounded to the
+// nearest one. None if the beep generator cannot produce it.
+fn beep_divider(freq: u16) -> Option<u32> {
+    if freq == 0 {
+        return None;
+    }
+    let freq = u32::from(freq);
+    let divider = (HDA_BEEP_GENERATION_RATE + freq / 2) / freq;
+    if divider == 0 || divider > HDA_BEEP_GENERATION_DIVIDER_MASK {
+        None
+    } else {
+        Some(divider)
+    }
+}
+
+// Play a tone on the beep generator widget of the function
+// group, which needs neither a stream descriptor nor DMA. It is
+// routed to the pins codec_route_outputs() would enable. Fails
+// with UNSUPPORTED if there is no beep widget, the frequency is
+// out of its range or it is not wired to any output pin so that
+// the caller can fall back to PCM.
+fn codec_play_beep(bus_context: &mut BusContext, device: &mut DeviceContext, pci: &PciIO, freq: u16, duration: u16) -> uefi::Result {
+    let divider = match beep_divider(freq) {
+        Some(divider) => divider,
+        None => return uefi::Status::UNSUPPORTED.into(),
+    };
+    let codec = device.codec;
+    let afg = device.afg;
+    let mut claimed = alloc::vec::Vec::new();
+    let result = (|| -> uefi::Result {
+        bus_context.power_wake(codec, afg)?;
+        let reserved = bus_context.busy_nodes(codec, afg);
+        pin_power(&mut bus_context.bus, codec, afg, true)?;
+        if device.jacks_stale {
+            codec_restore_unsolicited(&mut bus_context.bus, device)?;
+        }
+        let afg_amps = afg_get_amps(&mut bus_context.bus, codec, afg)
+            .ignore_warning()?;
+        // Digital pins cannot carry the beep
+        let output = device.output;
+        let nodes = codec_collect_nodes(&mut bus_context.bus, device, pci, codec)
+            .ignore_warning()?
+            .into_iter()
+            .filter(|node| !reserved.contains(&node.node()))
+            .filter(|node| {
+                if let PathNode::PinComplex {..} = node {
+                    node.output_kind() == Some(output) && node.display_sink().is_none()
+                } else {
+                    true
+                }
+            })
+            .collect::<alloc::vec::Vec<_>>();
+        let beep = match nodes.iter().find(|node| node.is_beep()) {
+            Some(beep) => beep.node(),
+            None => {
+                info!("codec_play_beep: no beep generator");
+                return uefi::Status::UNSUPPORTED.into();
+            }
+        };
+        let mut node_map = NodeMap::<&PathNode>::new();
+        for path_node in nodes.iter() {
+            node_map.insert(&path_node.node(), path_node);
+        }
+        let vertices = |node| node_map.get(node).cloned();
+        let pins = order_output_pins(&nodes);
+        let headphones = pins.iter().any(|pin| pin.is_headphones());
+        let paths = pins
+            .iter()
+            .filter(|pin| !headphones || pin.is_headphones())
+            .filter_map(|pin| hda_find_path(vertices, pin.node(), PathNode::is_beep, |_| false))
+            .collect::<alloc::vec::Vec<_>>();
+        if paths.is_empty() {
+            info!("codec_play_beep: {:?} is not wired to any output pin", beep);
+            return uefi::Status::UNSUPPORTED.into();
+        }
+        for &node in paths.iter().flatten() {
+            if !claimed.contains(&node) {
+                claimed.push(node);
+            }
+        }
+        bus_context.claim_nodes(codec, afg, claimed.as_slice());
+        let bus = &mut bus_context.bus;
+        let amp_map = codec_get_amps(bus, codec, &afg_amps, nodes.as_slice())
+            .ignore_warning()?;
+        let amps = |node| amp_map.get(node).cloned().unwrap_or_default();
+        for path in paths.iter() {
+            info!("codec_play_beep: path {:?}", path);
+            for &node in path.iter() {
+                pin_power(bus, codec, node, true)?;
+                pin_mute_unmute(bus, codec, &amps(node), node, false)?;
+                if let Some(next_node) = get_path_next_node(path, node) {
+                    if let Some(index) = find_path_connection_index(vertices, node, next_node) {
+                        pin_select(bus, codec, node, index, vertices(node).map_or(0, |path_node| path_node.successors().len()))?;
+                        if let Some(path_node) = vertices(node).filter(|path_node| matches!(path_node, PathNode::AudioMix {..} | PathNode::AudioMux {..})) {
+                            pin_select_input(bus, codec, &amps(node), node, index, path_node.successors().len())?;
+                        }
+                    }
+                }
+            }
+            // The path goes from the beep widget to the pin
+            let pin = path[path.len() - 1];
+            pin_enable_output(bus, codec, pin, true)?;
+            pin_enable_eapd(bus, codec, pin, true)?;
+        }
+        // The pins are no longer in the state codec_route_outputs()
+        // left them in
+        if let Some(routing) = device.routing.as_mut() {
+            for route in routing.routes.iter_mut().filter(|route| claimed.contains(&route.pin)) {
+                route.enabled = None;
+            }
+        }
+        bus.exec(make_command(codec, beep, HDA_VERB_SET_BEEP_GENERATION, Param(divider)))?;
+        let readback = bus.exec(make_command(codec, beep, HDA_VERB_GET_BEEP_GENERATION, Param(0x0)))
+            .ignore_warning()?;
+        info!("codec_play_beep: {:?} divider: {} readback: {}", beep, divider, readback);
+        boot_services().stall(milliseconds_to_stall(usize::from(duration)));
+        bus.exec(make_command(codec, beep, HDA_VERB_SET_BEEP_GENERATION, Param(0x0)))?;
+        Ok(().into())
+    })();
+    bus_context.release_nodes(codec, afg, claimed.as_slice());
+    bus_context.power_idle(codec, afg);
+    result
 }
 
-fn stream_play_loop(device: &mut DeviceContext, pci: &PciIO, duration: u64, samples: &[i16], sampling_rate: u32, channel_count: u8) -> uefi::Result {
-    let mut bdl_dma = pci
-        .map_ex::<BufferDescriptorListWithBuffers>(uefi::proto::pci::IoOperation::BusMasterWrite)
-        .map_err(inspect("PCI I/O map_ex(BDL)"))
+// Play total samples cycling through the samples slice, the
+// stream is stopped once IOC of the descriptor holding the
+// last sample is reported
+fn stream_play_loop(device: &mut DeviceContext, pci: &PciIO, total: usize, samples: &[i16], sampling_rate: u32, channel_count: u8) -> uefi::Result {
+    if total == 0 {
+        return uefi::Status::SUCCESS.into();
+    }
+    let mut bdl_dma = DmaBuffer::<BufferDescriptorListWithBuffers>::new(pci, "BDL")
         .ignore_warning()?;
 
     let (format, closest_rate) = stream_select_rate(device, pci, sampling_rate, channel_count)
@@ -2083,32 +4714,68 @@ fn stream_play_loop(device: &mut DeviceContext, pci: &PciIO, duration: u64, samp
 
     info!("stream_play_loop: use {} sample rate", closest_rate);
 
-    // SAFETY: this DMA buffer should not be mutated by the codec
-    init_bdl(bdl_dma.mapping().device_address(), unsafe { &mut *bdl_dma.get_mut() });
+    let bus_context = BusContext::from_controller_mut(boot_services(), device.controller_handle)
+        .ok_or(uefi::Status::NOT_READY)?;
 
-    let loop_buffers = BUFFER_COUNT;
-    let loop_samples = BUFFER_COUNT * BUFFER_SIZE;
+    let layout = BdlLayout::select(&bus_context.quirk.bdl, total, closest_rate, channel_count);
+    info!("stream_play_loop: {:?}", layout);
+    let loop_buffers = layout.entries;
+    let loop_samples = layout.cbl;
 
     // SAFETY: this DMA buffer should not be mutated by the codec
-    let mut control = Loop::new(unsafe { &mut *bdl_dma.get_mut() }, samples);
-
-    let mut bus = make_bus_io(pci).ignore_warning()?;
+    init_bdl(bdl_dma.device_address(), unsafe { &mut *bdl_dma.get_mut() }, &layout);
 
-    // TBD: reset the stream? we could only modify CBL after _some_ reset
-    codec_setup_stream(&mut bus, device, pci, device.codec, format)?;
-    stream_setup(device, pci, bdl_dma.mapping(), loop_buffers as u32, loop_samples as u32, format)?;
-
-    stream_loop(device, pci, &mut control, samples.len() as u64, channel_count, sampling_rate as u64, duration as u64)
-        .map_err(|error| {
-            stream_cleanup(device, pci).expect_success("double fail is unexpected");
-            error
-        })?;
-    stream_cleanup(device, pci)?;
-    uefi::Status::SUCCESS.into()
+    // SAFETY: this DMA buffer should not be mutated by the codec
+    let mut control = Loop::new(unsafe { &mut *bdl_dma.get_mut() }, samples, layout, total);
+    let duration = 1000 * total as u64 / u64::from(channel_count) / u64::from(sampling_rate);
+
+    let stream = match bus_context.stream_alloc() {
+        Some(stream) => stream,
+        None => {
+            warn!("no output stream descriptor or stream tag is free");
+            return uefi::Status::OUT_OF_RESOURCES.into();
+        }
+    };
+    info!("stream_play_loop: {:?}", stream);
+    device.stream = Some(stream);
+
+    let codec = device.codec;
+    let afg = device.afg;
+    let mut claimed = alloc::vec::Vec::new();
+    let result = (|| -> uefi::Result {
+        bus_context.power_wake(codec, afg)?;
+        // The timer restores the other children once we return
+        if bus_context.restore_unsolicited || device.jacks_stale {
+            // Jacks are only sensed with the group in D0
+            pin_power(&mut bus_context.bus, codec, afg, true)?;
+            codec_restore_unsolicited(&mut bus_context.bus, device)?;
+        }
+        let reserved = bus_context.busy_nodes(codec, afg);
+        // TBD: reset the stream? we could only modify CBL after _some_ reset
+        codec_setup_stream(&mut bus_context.bus, device, pci, codec, format, reserved.as_slice())?;
+        if let Some(routing) = device.routing.as_ref() {
+            claimed = routing.nodes.clone();
+            bus_context.claim_nodes(codec, afg, claimed.as_slice());
+        }
+        stream_setup(device, pci, bdl_dma.device_address(), loop_buffers as u32, loop_samples as u32, format)?;
+
+        stream_loop(&mut bus_context.bus, bus_context.positions.as_mut(), device, pci, &mut control, channel_count, sampling_rate as u64, duration)
+            .map_err(|error| {
+                stream_cleanup(device, pci).expect_success("double fail is unexpected");
+                error
+            })?;
+        stream_cleanup(device, pci)
+    })();
+    bus_context.release_nodes(codec, afg, claimed.as_slice());
+    bus_context.power_idle(codec, afg);
+    device.stream = None;
+    bus_context.stream_free(stream);
+    result
 }
 
 extern "efiapi" fn hda_tone(this: &mut SimpleAudioOut, freq: u16, duration: u16) -> Status {
     info!("hda_tone");
+    let _user = ContextUser::enter();
     let device = DeviceContext::from_protocol_mut(boot_services(), this)
         .ok_or(uefi::Status::INVALID_PARAMETER.into())?;
     // Opening protocol with GET_PROTOCOL does not require
@@ -2124,26 +4791,38 @@ extern "efiapi" fn hda_tone(this: &mut SimpleAudioOut, freq: u16, duration: u16)
         .map_err(inspect("OpenProtocol PCI I/O"))
         .ignore_warning()?;
     pci.dont_close();
-    let channel_count = 2;
-    let sampling_rate = AUDIO_RATE_44100;
-    let mut tone_samples = alloc::vec::Vec::new();
-    tone_samples.resize(BUFFER_SIZE, 0);
-    let sample_count = wave(tone_samples.as_mut_slice(), channel_count, sampling_rate, freq);
-    tone_samples.truncate(sample_count);
-    let samples = tone_samples.as_slice();
     // SAFETY: safe because no other references exist in our code
     let pci = unsafe { pci                           // OpenProtocol<'boot>
                        .as_proto()                   // &'boot UnsafeCell<PciIO>
                        .get()                        // *PciIO
                        .as_ref()                     // Option<&PciIO>
                        .unwrap() };
-    stream_play_loop(device, pci, u64::from(duration), samples, sampling_rate, channel_count)?;
+    let bus_context = BusContext::from_controller_mut(boot_services(), device.controller_handle)
+        .ok_or(uefi::Status::NOT_READY.into())?;
+    // The beep generator works even if no stream can be set up
+    match codec_play_beep(bus_context, device, pci, freq, duration).ignore_warning() {
+        Ok(()) => {
+            info!("hda_tone -- ok");
+            return uefi::Status::SUCCESS;
+        },
+        Err(error) => info!("hda_tone: beep generator not used, fall back to PCM: {:?}", error.status()),
+    }
+    let channel_count = 2;
+    let sampling_rate = AUDIO_RATE_44100;
+    let mut tone_samples = alloc::vec::Vec::new();
+    tone_samples.resize(TONE_BUFFER_SIZE, 0);
+    let sample_count = wave(tone_samples.as_mut_slice(), channel_count, sampling_rate, freq);
+    tone_samples.truncate(sample_count);
+    let samples = tone_samples.as_slice();
+    let total = u64::from(duration) * u64::from(sampling_rate) / 1000 * u64::from(channel_count);
+    stream_play_loop(device, pci, total as usize, samples, sampling_rate, channel_count)?;
     info!("hda_tone -- ok");
     uefi::Status::SUCCESS
 }
 
 extern "efiapi" fn hda_write(this: &mut SimpleAudioOut, sampling_rate: u32, channel_count: u8, format: u32, samples: *const i16, sample_count: usize) -> Status {
     info!("hda_write");
+    let _user = ContextUser::enter();
     let device = DeviceContext::from_protocol_mut(boot_services(), this)
         .ok_or(uefi::Status::INVALID_PARAMETER.into())?;
     // Opening protocol with GET_PROTOCOL does not require
@@ -2159,7 +4838,8 @@ extern "efiapi" fn hda_write(this: &mut SimpleAudioOut, sampling_rate: u32, chan
         .map_err(inspect("OpenProtocol PCI I/O"))
         .ignore_warning()?;
     pci.dont_close();
-    if channel_count != 2 {
+    // Stereo and 4.0, 5.1 and 7.1 surround
+    if ![2, 4, 6, 8].contains(&channel_count) {
         warn!("channel count {} is not supported!", channel_count);
         return uefi::Status::INVALID_PARAMETER;
     }
@@ -2177,20 +4857,20 @@ extern "efiapi" fn hda_write(this: &mut SimpleAudioOut, sampling_rate: u32, chan
     }
     // SAFETY: TBD
     let samples = unsafe { core::slice::from_raw_parts(samples, sample_count) };
-    let duration_ms = 1000 * sample_count as u64 / u64::from(channel_count) / u64::from(sampling_rate);
     // SAFETY: safe because no other references exist in our code
     let pci = unsafe { pci                           // OpenProtocol<'boot>
                        .as_proto()                   // &'boot UnsafeCell<PciIO>
                        .get()                        // *PciIO
                        .as_ref()                     // Option<&PciIO>
                        .unwrap() };
-    stream_play_loop(device, pci, duration_ms, samples, sampling_rate, channel_count)?;
+    stream_play_loop(device, pci, sample_count, samples, sampling_rate, channel_count)?;
     info!("hda_write -- ok");
     uefi::Status::SUCCESS
 }
 
 extern "efiapi" fn hda_reset(this: &mut SimpleAudioOut) -> Status {
     info!("hda_reset");
+    let _user = ContextUser::enter();
     let device = DeviceContext::from_protocol_mut(boot_services(), this)
         .ok_or(uefi::Status::INVALID_PARAMETER.into())?;
     info!("hda_reset -- ok");
@@ -2199,31 +4879,97 @@ extern "efiapi" fn hda_reset(this: &mut SimpleAudioOut) -> Status {
 
 extern "efiapi" fn hda_query_mode(this: &mut SimpleAudioOut, index: usize, mode: &mut SimpleAudioMode) -> Status {
     info!("hda_query_mode");
+    let _user = ContextUser::enter();
     let device = DeviceContext::from_protocol_mut(boot_services(), this)
         .ok_or(uefi::Status::INVALID_PARAMETER.into())?;
     if index > 0 {
         // We only support a single mode -
-        // Stereo|S16_LE|22050hz. Other modes can be
-        // specified too in write() but they are not
-        // guaranteed to work.
+        // Stereo|S16_LE|22050hz, 48000hz on S/PDIF.
+        // Other modes can be specified too in write() but
+        // they are not guaranteed to work.
         warn!("Requested mode with index {} does not exist", index);
         return uefi::Status::INVALID_PARAMETER;
     }
-    mode.sampling_rate = AUDIO_RATE_22050;
+    // S/PDIF receivers only lock to the IEC 60958 rates
+    mode.sampling_rate = match device.output {
+        OutputKind::Primary => AUDIO_RATE_22050,
+        OutputKind::Spdif => AUDIO_RATE_48000,
+    };
     mode.channel_count = 2;
     mode.sample_format = AUDIO_FORMAT_S16LE;
     info!("hda_query_mode -- ok");
     uefi::Status::SUCCESS
 }
 
-fn init_bdl(device_address: u64, bdl: &mut BufferDescriptorListWithBuffers) {
+extern "efiapi" fn hda_codec_dump(this: &mut HdaCodecInfo, buffer: *mut u8, buffer_size: &mut usize) -> Status {
+    info!("hda_codec_dump");
+    // Also keeps the unsolicited timer from putting the link
+    // back into reset meanwhile
+    let _user = ContextUser::enter();
+    let device = DeviceContext::from_info_protocol_mut(boot_services(), this)
+        .ok_or(uefi::Status::INVALID_PARAMETER.into())?;
+    let bus_context = BusContext::from_controller_mut(boot_services(), device.controller_handle)
+        .ok_or(uefi::Status::NOT_READY)?;
+    bus_context.link_wake()?;
+    let text = codec_dump::codec_dump(&mut bus_context.bus, device.codec, device.afg)
+        .ignore_warning()?;
+    let size = mem::replace(buffer_size, text.len());
+    if buffer.is_null() || size < text.len() {
+        return uefi::Status::BUFFER_TOO_SMALL;
+    }
+    // SAFETY: the caller provides at least buffer_size bytes
+    unsafe {
+        core::ptr::copy_nonoverlapping(text.as_ptr(), buffer, text.len());
+    }
+    info!("hda_codec_dump -- ok");
+    uefi::Status::SUCCESS
+}
+
+extern "efiapi" fn hda_get_spdif(this: &mut HdaCodecInfo, control: &mut u32) -> Status {
+    info!("hda_get_spdif");
+    let _user = ContextUser::enter();
+    let device = DeviceContext::from_info_protocol_mut(boot_services(), this)
+        .ok_or(uefi::Status::INVALID_PARAMETER.into())?;
+    if device.output != OutputKind::Spdif {
+        return uefi::Status::UNSUPPORTED;
+    }
+    *control = device.spdif_control;
+    uefi::Status::SUCCESS
+}
+
+// Takes effect with the next write or tone
+extern "efiapi" fn hda_set_spdif(this: &mut HdaCodecInfo, control: u32) -> Status {
+    info!("hda_set_spdif: {:#x}", control);
+    let _user = ContextUser::enter();
+    let device = DeviceContext::from_info_protocol_mut(boot_services(), this)
+        .ok_or(uefi::Status::INVALID_PARAMETER.into())?;
+    if device.output != OutputKind::Spdif {
+        return uefi::Status::UNSUPPORTED;
+    }
+    if control & !(SPDIF_COPYRIGHT | SPDIF_PROFESSIONAL | SPDIF_NON_PCM | SPDIF_CATEGORY_MASK) != 0 {
+        return uefi::Status::INVALID_PARAMETER;
+    }
+    device.spdif_control = control;
+    info!("hda_set_spdif -- ok");
+    uefi::Status::SUCCESS
+}
+
+fn init_bdl(device_address: u64, bdl: &mut BufferDescriptorListWithBuffers, layout: &BdlLayout) {
     let bdl_base = bdl as *mut BufferDescriptorListWithBuffers as *mut u8;
-    for (descriptor, buffer) in bdl.descriptors.iter_mut().zip(bdl.buffers.iter()) {
-        // SAFETY: see dma-buffer miri test #1
-        let buffer_offset = unsafe {
-            (buffer.samples.as_ptr() as *const u8)
-                .offset_from(bdl_base)
-        };
+    // SAFETY: see dma-buffer miri test #1
+    let samples_offset = unsafe {
+        (bdl.samples.as_ptr() as *const u8)
+            .offset_from(bdl_base)
+    };
+    for (index, descriptor) in bdl.descriptors.iter_mut().enumerate() {
+        if index >= layout.entries {
+            descriptor.address = 0;
+            descriptor.length = 0;
+            descriptor.control = 0;
+            continue;
+        }
+        let buffer_offset = samples_offset
+            + (layout.entry_offset(index) * mem::size_of::<i16>()) as isize;
         // TBD: UB if mapping address or bdl_base is not a valid pointer
         // SAFETY: TBD
         let descriptor_address = unsafe {
@@ -2236,7 +4982,7 @@ fn init_bdl(device_address: u64, bdl: &mut BufferDescriptorListWithBuffers) {
     }
 }
 
-fn init_context(driver_handle: Handle, controller_handle: Handle, pci: &PciIO, codec: Codec) -> uefi::Result<Box<DeviceContext>> {
+fn init_context(driver_handle: Handle, controller_handle: Handle, pci: &PciIO, codec: Codec, afg: Node, output: OutputKind) -> uefi::Result<Box<DeviceContext>> {
     let gcap = GCAP.read(pci)
         .ignore_warning()
         .map(GlobalCapabilities::from)?;
@@ -2244,7 +4990,7 @@ fn init_context(driver_handle: Handle, controller_handle: Handle, pci: &PciIO, c
         .handle_protocol::<DevicePath>(controller_handle)
         .ignore_warning()?;
     let controller_path = unsafe { &*controller_path.get() };
-    let codec_subpath = device_path::make_codec_subpath(codec.0);
+    let codec_subpath = device_path::make_codec_subpath(codec.0, afg.0, output as u32);
     let device_path = device_path::concat_device_path(controller_path, &codec_subpath.hda.header)
         .ignore_warning()?;
     let device = Box::new(DeviceContext {
@@ -2254,7 +5000,15 @@ fn init_context(driver_handle: Handle, controller_handle: Handle, pci: &PciIO, c
         in_streams: u32::from(gcap.in_streams()),
         out_streams: u32::from(gcap.out_streams()),
         codec,
+        afg,
+        output,
         device_path,
+        jacks: alloc::vec::Vec::new(),
+        jacks_changed: false,
+        jacks_stale: false,
+        routing: None,
+        stream: None,
+        spdif_control: SPDIF_DEFAULT_CONTROL,
         audio_interface: Box::new(SimpleAudioOut {
             reset: hda_reset,
             write: hda_write,
@@ -2262,6 +5016,11 @@ fn init_context(driver_handle: Handle, controller_handle: Handle, pci: &PciIO, c
             query_mode: hda_query_mode,
             max_mode: 1,
             capabilities: AUDIO_CAP_RESET | AUDIO_CAP_WRITE | AUDIO_CAP_TONE | AUDIO_CAP_MODE
+        }),
+        info_interface: Box::new(HdaCodecInfo {
+            dump: hda_codec_dump,
+            get_spdif: hda_get_spdif,
+            set_spdif: hda_set_spdif
         })
     });
     Ok (device.into())
@@ -2271,27 +5030,40 @@ fn init_context(driver_handle: Handle, controller_handle: Handle, pci: &PciIO, c
 struct CopyResult {
     loop_buffers: usize,
     loop_samples: usize,
+    copied: usize,
 }
 
-fn fill_bde(buffer: &mut SampleBuffer, descriptor: &mut Descriptor, samples_position: usize, samples: &[i16]) -> CopyResult {
-    // Cycle through samples and fill entire buffer
+fn fill_bde(buffer: &mut [i16], descriptor: &mut Descriptor, samples_position: usize, samples: &[i16], remaining: usize) -> CopyResult {
+    // Cycle through samples until remaining are exhausted and
+    // pad the rest of the buffer with silence
+    let copied = remaining.min(buffer.len());
     let mut samples_position = samples_position;
-    let mut samples_to_copy = buffer.samples.len();
+    let mut samples_to_copy = if samples.is_empty() { 0 } else { copied };
     let mut bdl_pos = 0;
     while samples_to_copy > 0 {
         let count = (samples.len() - samples_position).min(samples_to_copy);
         // TBD: copy volatile?
-        buffer.samples[bdl_pos..bdl_pos+count]
+        buffer[bdl_pos..bdl_pos+count]
             .copy_from_slice(&samples[samples_position..samples_position+count]);
         bdl_pos += count;
         samples_position = (samples_position + count) % samples.len();
         samples_to_copy -= count;
     }
-    descriptor.length = buffer.samples.len() as u32 * mem::size_of::<i16>() as u32;
-    descriptor.control = 0;
+    for sample in buffer[bdl_pos..].iter_mut() {
+        *sample = 0;
+    }
+    descriptor.length = buffer.len() as u32 * mem::size_of::<i16>() as u32;
+    // Request IOC once the buffer with the last sample is
+    // consumed (3.6.3)
+    descriptor.control = if remaining > 0 && remaining <= buffer.len() {
+        BDBAR_IOC_BIT
+    } else {
+        0
+    };
     CopyResult {
         loop_buffers: 1,
-        loop_samples: buffer.samples.len()
+        loop_samples: buffer.len(),
+        copied
     }
 }
 
@@ -2333,6 +5105,10 @@ fn milliseconds_to_stall(msec: usize) -> usize {
     msec * 1000
 }
 
+fn div_round_up(a: usize, b: usize) -> usize {
+    (a + b - 1) / b
+}
+
 //
 // DriverBinding routines
 //
@@ -2341,11 +5117,260 @@ fn milliseconds_to_stall(msec: usize) -> usize {
 // PCI Vendor ID
 //
 const VID_INTEL: u16 = 0x8086;
+const VID_ATI: u16 = 0x1002;
+const VID_AMD: u16 = 0x1022;
+const VID_NVIDIA: u16 = 0x10de;
+const VID_VIA: u16 = 0x1106;
+const VID_LOONGSON: u16 = 0x0014;
 
 const HDA_ICH9: u16 = 0x293e;
 const HDA_ICH6: u16 = 0x2668;
 const HDA_ICH7: u16 = 0x27d8;
 const HDA_HM170: u16 = 0xa170;
+const HDA_ATI_SB450: u16 = 0x437b;
+
+// Intel PCH generations from Ibex Peak on
+const HDA_INTEL_PCH: &[u16] = &[
+    0x3b56, // 5 Series/3400 Series (Ibex Peak)
+    0x1c20, // 6 Series/C200 Series (Cougar Point)
+    0x1e20, // 7 Series/C216 (Panther Point)
+    0x8c20, // 8 Series/C220 (Lynx Point)
+    0x9c20, // 8 Series (Lynx Point-LP)
+    0x8ca0, // 9 Series (Wildcat Point)
+    0x9ca0, // Wildcat Point-LP
+    HDA_HM170, // 100 Series/C230 (Sunrise Point)
+    0x9d70, // Sunrise Point-LP
+    0xa2f0, // 200 Series (Union Point)
+    0xa348, // 300 Series (Cannon Point)
+    0x9dc8, // Cannon Point-LP
+    0x02c8, // Comet Lake-LP
+    0x06c8, // Comet Lake-H
+    0xa0c8, // Tiger Lake-LP
+    0x43c8, // Tiger Lake-H
+    0x7ad0, // Alder Lake-S
+    0x51c8, // Alder Lake-P
+];
+
+//
+// PCI Subsystem Vendor ID of emulated controllers
+//
+const SVID_QEMU: u16 = 0x1af4;
+const SVID_VBOX: u16 = 0x80ee;
+
+// Controller specific workarounds, the first matching entry
+// is used
+struct ControllerQuirk {
+    name: &'static str,
+    vendor_id: Option<u16>,
+    // Any device of the vendor if empty
+    device_ids: &'static [u16],
+    subsystem_vendor_id: Option<u16>,
+    bdl: BdlLimits,
+    snoop: Snoop,
+    // Clear TCSEL to select traffic class TC0
+    tcsel: bool,
+    position_fix: PositionFix,
+    // Maximum number of CORB and RIRB entries
+    ring_entries: usize,
+    // CORBRP.RST does not read back as 1 while in reset
+    corbrp_self_clear: bool,
+    // Time given to codecs to request a status change after
+    // the link leaves reset (4.3 Codec Discovery, at least
+    // 521 us)
+    reset_delay_us: usize,
+    // GCAP.64OK is not to be trusted
+    no_64bit: bool,
+}
+
+// Vendor specific way to make the controller snoop DMA
+// buffers in the processor caches
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Snoop {
+    None,
+    Ati,
+    Nvidia,
+    IntelPch,
+}
+
+// How the position of a running stream is determined
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum PositionFix {
+    // DMA position buffer checked against LPIB
+    Auto,
+    Lpib,
+    PositionBuffer,
+}
+
+const CONTROLLER_DEFAULT: ControllerQuirk = ControllerQuirk {
+    name: "generic",
+    vendor_id: None,
+    device_ids: &[],
+    subsystem_vendor_id: None,
+    bdl: BDL_DEFAULT_LIMITS,
+    snoop: Snoop::None,
+    tcsel: false,
+    position_fix: PositionFix::Auto,
+    ring_entries: 256,
+    corbrp_self_clear: false,
+    reset_delay_us: 1000,
+    no_64bit: false,
+};
+
+// While trying to split the DMA timer intervals the vbox
+// r3vm goes into infinite loop in
+// hdaR3StreamAddScheduleItem() but only if the guest driver
+// supplies more than 200 msec BDL. Real ICH6 controllers,
+// which vbox emulates, do not need it.
+const BDL_VBOX_LIMITS: BdlLimits = BdlLimits {
+    max_ms: Some(190),
+    ..BDL_DEFAULT_LIMITS
+};
+
+const CONTROLLER_QUIRKS: &[ControllerQuirk] = &[
+    // QEMU intel-hda has no limits on the BDL
+    ControllerQuirk {
+        name: "qemu",
+        subsystem_vendor_id: Some(SVID_QEMU),
+        bdl: BdlLimits { entries: 32, ..BDL_DEFAULT_LIMITS },
+        ..CONTROLLER_DEFAULT
+    },
+    ControllerQuirk {
+        name: "vbox",
+        subsystem_vendor_id: Some(SVID_VBOX),
+        bdl: BDL_VBOX_LIMITS,
+        ..CONTROLLER_DEFAULT
+    },
+    ControllerQuirk {
+        name: "ich6",
+        vendor_id: Some(VID_INTEL),
+        device_ids: &[HDA_ICH6],
+        tcsel: true,
+        ..CONTROLLER_DEFAULT
+    },
+    ControllerQuirk {
+        name: "intel pch",
+        vendor_id: Some(VID_INTEL),
+        device_ids: HDA_INTEL_PCH,
+        bdl: BdlLimits { entries: 32, ..BDL_DEFAULT_LIMITS },
+        snoop: Snoop::IntelPch,
+        tcsel: true,
+        position_fix: PositionFix::PositionBuffer,
+        ..CONTROLLER_DEFAULT
+    },
+    // Real ICH controllers take the whole storage
+    ControllerQuirk {
+        name: "intel ich",
+        vendor_id: Some(VID_INTEL),
+        bdl: BdlLimits { entries: 32, ..BDL_DEFAULT_LIMITS },
+        tcsel: true,
+        ..CONTROLLER_DEFAULT
+    },
+    // SB450 DMA is broken above 4 GiB even though 64OK is set
+    ControllerQuirk {
+        name: "ati sb450",
+        vendor_id: Some(VID_ATI),
+        device_ids: &[HDA_ATI_SB450],
+        snoop: Snoop::Ati,
+        position_fix: PositionFix::Lpib,
+        no_64bit: true,
+        ..CONTROLLER_DEFAULT
+    },
+    ControllerQuirk {
+        name: "ati",
+        vendor_id: Some(VID_ATI),
+        snoop: Snoop::Ati,
+        position_fix: PositionFix::Lpib,
+        ..CONTROLLER_DEFAULT
+    },
+    ControllerQuirk {
+        name: "amd",
+        vendor_id: Some(VID_AMD),
+        snoop: Snoop::Ati,
+        position_fix: PositionFix::Lpib,
+        ..CONTROLLER_DEFAULT
+    },
+    ControllerQuirk {
+        name: "nvidia",
+        vendor_id: Some(VID_NVIDIA),
+        snoop: Snoop::Nvidia,
+        corbrp_self_clear: true,
+        ..CONTROLLER_DEFAULT
+    },
+    // The position buffer is not updated for playback streams
+    ControllerQuirk {
+        name: "via",
+        vendor_id: Some(VID_VIA),
+        position_fix: PositionFix::Lpib,
+        ..CONTROLLER_DEFAULT
+    },
+    ControllerQuirk {
+        name: "loongson",
+        vendor_id: Some(VID_LOONGSON),
+        position_fix: PositionFix::Lpib,
+        reset_delay_us: 10000,
+        ..CONTROLLER_DEFAULT
+    },
+];
+
+impl ControllerQuirk {
+    fn matches(&self, vendor_id: u16, device_id: u16, subsystem_vendor_id: u16) -> bool {
+        self.vendor_id.map_or(true, |id| id == vendor_id)
+            && (self.device_ids.is_empty() || self.device_ids.contains(&device_id))
+            && self.subsystem_vendor_id.map_or(true, |id| id == subsystem_vendor_id)
+    }
+}
+
+fn controller_quirk(pci: &PciIO) -> uefi::Result<&'static ControllerQuirk> {
+    let vendor_id = pci.read_config_single::<u16>(PCI_VID)
+        .ignore_warning()?;
+    let device_id = pci.read_config_single::<u16>(PCI_DID)
+        .ignore_warning()?;
+    let subsystem_vendor_id = pci.read_config_single::<u16>(PCI_SVID)
+        .ignore_warning()?;
+    let quirk = CONTROLLER_QUIRKS
+        .iter()
+        .find(|quirk| quirk.matches(vendor_id, device_id, subsystem_vendor_id))
+        .unwrap_or(&CONTROLLER_DEFAULT);
+    Ok(quirk.into())
+}
+
+fn pci_update_config_byte(pci: &PciIO, offset: u32, mask: u8, value: u8) -> uefi::Result {
+    let data = pci.read_config_single::<u8>(offset)
+        .ignore_warning()?;
+    pci.write_config_single::<u8>(offset, (data & !mask) | (value & mask))
+}
+
+fn pci_update_config_word(pci: &PciIO, offset: u32, mask: u16, value: u16) -> uefi::Result {
+    let data = pci.read_config_single::<u16>(offset)
+        .ignore_warning()?;
+    pci.write_config_single::<u16>(offset, (data & !mask) | (value & mask))
+}
+
+// Program the PCI configuration space before the link is
+// taken out of reset
+fn bus_apply_quirk(pci: &PciIO, quirk: &ControllerQuirk) -> uefi::Result {
+    info!("controller quirk: {}", quirk.name);
+    if quirk.tcsel {
+        // Traffic class other than TC0 causes playback static
+        // on some codecs
+        pci_update_config_byte(pci, PCI_TCSEL, PCI_TCSEL_MASK, 0)?;
+    }
+    match quirk.snoop {
+        Snoop::None => {},
+        Snoop::Ati => {
+            pci_update_config_byte(pci, PCI_ATI_MISC_CNTR2, PCI_ATI_SNOOP_MASK, PCI_ATI_SNOOP_ENABLE)?;
+        },
+        Snoop::Nvidia => {
+            pci_update_config_byte(pci, PCI_NVIDIA_TRANSREG, PCI_NVIDIA_COHBITS_MASK, PCI_NVIDIA_COHBITS_MASK)?;
+            pci_update_config_byte(pci, PCI_NVIDIA_ISTRM_COH, PCI_NVIDIA_COH_BIT, PCI_NVIDIA_COH_BIT)?;
+            pci_update_config_byte(pci, PCI_NVIDIA_OSTRM_COH, PCI_NVIDIA_COH_BIT, PCI_NVIDIA_COH_BIT)?;
+        },
+        Snoop::IntelPch => {
+            pci_update_config_word(pci, PCI_INTEL_DEVC, PCI_INTEL_DEVC_NOSNOOP_BIT, 0)?;
+        },
+    }
+    Ok(().into())
+}
 
 extern "efiapi" fn hda_supported(this: &DriverBinding, handle: Handle, remaining_path: *mut DevicePath) -> Status {
     // Opening the protocol BY_DRIVER results in
@@ -2365,9 +5390,9 @@ extern "efiapi" fn hda_supported(this: &DriverBinding, handle: Handle, remaining
         .ignore_warning()?;
     let device_id = pci.read_config_single::<u16>(PCI_DID)
         .ignore_warning()?;
-    let class_id = pci.read_config_single::<u8>(PCI_SCC)
+    let class_id = pci.read_config_single::<u8>(PCI_BCC)
         .ignore_warning()?;
-    let subclass_id = pci.read_config_single::<u8>(PCI_BCC)
+    let subclass_id = pci.read_config_single::<u8>(PCI_SCC)
         .ignore_warning()?;
     info!("vendor: {:#x}, device: {:#x}", vendor_id, device_id);
     info!("class: {:#x}, subclass: {:#x}", class_id, subclass_id);
@@ -2380,8 +5405,8 @@ extern "efiapi" fn hda_supported(this: &DriverBinding, handle: Handle, remaining
         ].iter().any(|&(vid, did)| {
             vendor_id == vid && device_id == did
         })
-    } || (class_id == 0x4                                // HDA
-          && subclass_id == 0x3                          // multimedia
+    } || (class_id == 0x4                                // multimedia
+          && subclass_id == 0x3                          // HDA
     );
     if !supported {
         return uefi::Status::UNSUPPORTED;
@@ -2412,22 +5437,79 @@ extern "efiapi" fn hda_start(this: &DriverBinding, controller_handle: Handle, re
                            .as_ref()                     // Option<&PciIO>
                            .unwrap() };
 
-        let codec_mask = bus_reset(pci).ignore_warning()?;
+        let quirk = controller_quirk(pci).ignore_warning()?;
+        bus_apply_quirk(pci, quirk)?;
+        let codec_mask = bus_reset(pci, quirk).ignore_warning()?;
         let gcap = GCAP.read(pci)
             .ignore_warning()
             .map(GlobalCapabilities::from)?;
-        if gcap.out_streams() == 0 {
+        if gcap.out_streams() == 0 && gcap.bd_streams() == 0 {
             info!("No output streams supported!");
             return uefi::Status::UNSUPPORTED;
         }
+        if gcap.out_streams() == 0 {
+            info!("using bidirectional streams for output");
+        }
 
-        let detected_codecs = bus_probe_codecs(pci, codec_mask).ignore_warning()?;
+        let detected_codecs = bus_probe_codecs(pci, quirk, codec_mask).ignore_warning()?;
 
-        let mut bus = make_bus_io(pci).ignore_warning()?;
+        // SAFETY: PCI I/O stays open BY_DRIVER until
+        //         hda_stop_bus() which drops the bus context first
+        let pci: &'static PciIO = unsafe { &*(pci as *const PciIO) };
+        let mut bus_context = BusContext::new(controller_handle, pci, quirk)
+            .ignore_warning()?;
+
+        let patches = patch::load_patch();
 
         for codec in detected_codecs.into_iter() {
-            bus_create_child(this.driver_handle(), controller_handle, &mut bus, pci, Codec(codec));
+            let codec = Codec(codec);
+            let groups = match codec_function_groups(&mut bus_context.bus, codec).ignore_warning() {
+                Ok(groups) => groups,
+                Err(error) => {
+                    warn!("failed to enumerate function groups of codec {:?}: {:?}", codec, error.status());
+                    continue;
+                }
+            };
+            // Tag 0 is not used so that it never matches a jack
+            let mut first_tag = 1;
+            for group in groups.iter() {
+                if group.is_audio() {
+                    if let Err(error) = codec_apply_fixups(&mut bus_context.bus, codec, group.node, patches.as_slice()) {
+                        warn!("failed to apply fixup to {:?} of codec {:?}: {:?}", group.node, codec, error.status());
+                    }
+                    let spdif = match codec_has_output(&mut bus_context.bus, codec, group.node, OutputKind::Spdif).ignore_warning() {
+                        Ok(spdif) => spdif,
+                        Err(error) => {
+                            warn!("failed to look for S/PDIF pins in {:?} of codec {:?}: {:?}", group.node, codec, error.status());
+                            false
+                        }
+                    };
+                    let outputs: &[OutputKind] = if spdif {
+                        &[OutputKind::Primary, OutputKind::Spdif]
+                    } else {
+                        &[OutputKind::Primary]
+                    };
+                    for &output in outputs.iter() {
+                        match bus_create_child(this.driver_handle(), controller_handle, &mut bus_context.bus, pci, codec, group.node, output, first_tag).ignore_warning() {
+                            Ok(next_tag) => first_tag = next_tag,
+                            Err(error) => warn!("failed to create {:?} child for {:?} of codec {:?}: {:?}", output, group.node, codec, error.status())
+                        }
+                    }
+                    bus_context.groups.push(GroupPower::new(codec, group.node));
+                } else if group.is_modem() {
+                    info!("codec {:?} has modem function group {:?} which is not supported", codec, group);
+                } else {
+                    info!("codec {:?} has vendor function group {:?}", codec, group);
+                }
+            }
+            if !groups.iter().any(FunctionGroup::is_audio) {
+                info!("codec {:?} has no audio function group", codec);
+            }
         }
+
+        bus_context.patches = patches;
+        bus_context.register()
+            .ignore_warning()?;
     }
 
     // All children are created so now consume PCI I/O by this bus controller
@@ -2436,15 +5518,27 @@ extern "efiapi" fn hda_start(this: &DriverBinding, controller_handle: Handle, re
     uefi::Status::SUCCESS
 }
 
-fn bus_create_child<B: BusIo>(driver_handle: Handle, controller_handle: Handle, bus: &mut B, pci: &PciIO, codec: Codec) -> uefi::Result {
-    let mut device = init_context(driver_handle, controller_handle, pci, codec)
+// Create a child for the given output of the audio function
+// group. Returns the first unsolicited response tag that is not
+// used by the child.
+fn bus_create_child<B: BusIo>(driver_handle: Handle, controller_handle: Handle, bus: &mut B, pci: &PciIO, codec: Codec, afg: Node, output: OutputKind, first_tag: u32) -> uefi::Result<u32> {
+    let mut device = init_context(driver_handle, controller_handle, pci, codec, afg, output)
         .ignore_warning()?;
+    let next_tag = match codec_enable_unsolicited(bus, &mut device, pci, first_tag).ignore_warning() {
+        Ok(next_tag) => next_tag,
+        Err(error) => {
+            warn!("jack detection is not available for {:?} of codec {:?}: {:?}", afg, codec, error.status());
+            first_tag
+        }
+    };
     let audio_out = &*device.audio_interface;
+    let codec_info = &*device.info_interface;
     let device_path = &*device.device_path;
     let child_handle = boot_services()
-        .install_multiple_protocol_interfaces2::<SimpleAudioOut, DevicePath>(
+        .install_multiple_protocol_interfaces3::<SimpleAudioOut, HdaCodecInfo, DevicePath>(
             None,
             audio_out,
+            codec_info,
             device_path
         )
         .map_err(inspect("InstallMultipleProtocolInterfaces"))
@@ -2462,11 +5556,12 @@ fn bus_create_child<B: BusIo>(driver_handle: Handle, controller_handle: Handle,
         Err(error) => {
             error!("failed to open PCI I/O by child: {:?}", error.status());
             boot_services()
-                .uninstall_multiple_protocol_interfaces2::<SimpleAudioOut, DevicePath>(
+                .uninstall_multiple_protocol_interfaces3::<SimpleAudioOut, HdaCodecInfo, DevicePath>(
                     child_handle,
                     audio_out,
+                    codec_info,
                     device_path);
-            return error.status().into();
+            return Err(error.status().into());
         }
         Ok(mut pci) => {
             pci.dont_close();
@@ -2476,7 +5571,7 @@ fn bus_create_child<B: BusIo>(driver_handle: Handle, controller_handle: Handle,
     // long as the driver's image stay resident or until the
     // DisconnectController() will be invoked
     device.register();
-    uefi::Status::SUCCESS.into()
+    Ok(next_tag.into())
 }
 
 fn hda_stop_bus(this: &DriverBinding, controller: Handle) -> uefi::Result {
@@ -2499,7 +5594,14 @@ fn hda_stop_bus(this: &DriverBinding, controller: Handle) -> uefi::Result {
                            .get()                        // *PciIO
                            .as_ref()                     // Option<&PciIO>
                            .unwrap() };
+        let dual_address_cycle = BusContext::from_controller_mut(boot_services(), controller)
+            .map_or(false, |context| context.dual_address_cycle);
+        // Stop the command rings before the link goes down
+        BusContext::unregister(controller);
         bus_stop(pci)?;
+        if dual_address_cycle {
+            bus_disable_dual_address_cycle(pci);
+        }
     }
     pci.close()
         .map_err(inspect("CloseProtocol PCI I/O"))
@@ -2547,11 +5649,13 @@ fn hda_stop_child(this: &DriverBinding, controller: Handle, child: Handle) -> ue
     if let Err(status) = pci.close() {
         warn!("failed to close PCI I/O: {:?}", status);
     }
+    let codec_info = &*device.info_interface;
     let device_path = &*device.device_path;
     boot_services()
-        .uninstall_multiple_protocol_interfaces2::<SimpleAudioOut, DevicePath>(
+        .uninstall_multiple_protocol_interfaces3::<SimpleAudioOut, HdaCodecInfo, DevicePath>(
             child,
             audio_out,
+            codec_info,
             device_path
         )
         .map_err(inspect("UninstallMultipleProtocolInterfaces"))
@@ -2623,7 +5727,7 @@ extern "efiapi" fn hda_unload(image_handle: Handle) -> Status {
             warn!("failed to disconnect PCI I/O controller {:?}: {:?}", controller, error.status());
         }
     }
-    if unsafe { !DEVICE_CONTEXTS.is_empty() } {
+    if unsafe { !DEVICE_CONTEXTS.is_empty() || !BUS_CONTEXTS.is_empty() } {
         error!("failed to disconnect some devices");
         return uefi::Status::DEVICE_ERROR;
     }
@@ -2651,6 +5755,10 @@ fn efi_main(handle: Handle, system_table: SystemTable<Boot>) -> uefi::Status {
     // SAFETY: TBD
     let loaded_image = unsafe { &mut *loaded_image.get() };
     loaded_image.set_unload_routine(Some(hda_unload));
+    // SAFETY: nothing else runs until we return
+    unsafe {
+        patch::IMAGE_DEVICE = Some(loaded_image.device());
+    }
     boot_services()
         .install_multiple_protocol_interfaces3::<DriverBinding, ComponentName, ComponentName2>(
             Some(handle),
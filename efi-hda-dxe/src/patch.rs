@@ -0,0 +1,268 @@
+// Board fixups in the format of Linux HDA firmware patch files
+// (Documentation/sound/hd-audio/notes.rst, "Early Patching")
+// as written by hda-jack-retask. The patch is taken from the
+// ESP the driver is loaded from or from the HdaPatch variable
+// so that a board can be fixed without rebuilding the driver.
+use alloc::string::String;
+use alloc::vec::Vec;
+use uefi::proto::media::file::{File, FileAttribute, FileMode, FileType};
+use uefi::proto::media::fs::SimpleFileSystem;
+use uefi::table::runtime::VariableVendor;
+use uefi::CStr16;
+
+use super::*;
+
+pub(crate) const HDA_PATCH_VARIABLE_GUID: uefi::Guid = uefi::Guid::from_values(
+    0x5b1d7f4e,
+    0x3a62,
+    0x4c0b,
+    0x9e17,
+    [0x2d, 0x8a, 0x61, 0xf0, 0x4c, 0x93]
+);
+
+// "HdaPatch" as a null terminated UCS-2 string
+const HDA_PATCH_VARIABLE_NAME: &[u16] = &[
+    b'H' as u16, b'd' as u16, b'a' as u16, b'P' as u16,
+    b'a' as u16, b't' as u16, b'c' as u16, b'h' as u16,
+    0
+];
+
+const HDA_PATCH_FILE_NAME: &str = "\\efi\\hda\\hda-jack-retask.fw";
+
+// Patch files are a few hundred bytes long
+const HDA_PATCH_MAX_SIZE: usize = 64 * 1024;
+
+// Image device handle saved by efi_main()
+pub(crate) static mut IMAGE_DEVICE: Option<Handle> = None;
+
+#[derive(Debug)]
+pub(crate) struct PatchCodec {
+    pub vendor_id: u32,
+    pub subsystem_id: u32,
+    pub address: u32,
+    pub pins: Vec<(Node, u32)>,
+    pub verbs: Vec<(Node, Verb, u32)>,
+    pub hints: Vec<(String, String)>,
+}
+
+impl PatchCodec {
+    pub fn matches(&self, vendor_id: u32, subsystem_id: u32, codec: Codec) -> bool {
+        self.vendor_id == vendor_id
+            && self.subsystem_id == subsystem_id
+            && self.address == codec.0
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Section {
+    None,
+    Codec,
+    PinConfig,
+    Verb,
+    Hint,
+    // Known to Linux but not to us
+    Ignored,
+}
+
+// Field widths of a codec command, values past them would be
+// ORed into the neighbouring fields by make_command()
+const PATCH_MAX_CODEC: u32 = 0xf;
+const PATCH_MAX_NODE: u32 = 0x7f;
+const PATCH_MAX_VERB: u32 = 0xfff;
+const PATCH_MAX_PAYLOAD: u32 = 0xffff;
+
+// Numbers are parsed like strtoul() with base 0
+fn parse_number(text: &str) -> Option<u32> {
+    if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        u32::from_str_radix(hex, 16).ok()
+    } else if text.len() > 1 && text.starts_with('0') {
+        u32::from_str_radix(&text[1..], 8).ok()
+    } else {
+        text.parse::<u32>().ok()
+    }
+}
+
+fn parse_numbers(line: &str) -> Option<Vec<u32>> {
+    line.split_whitespace()
+        .map(parse_number)
+        .collect()
+}
+
+pub(crate) fn parse_patch(text: &str) -> Vec<PatchCodec> {
+    let mut codecs: Vec<PatchCodec> = Vec::new();
+    let mut section = Section::None;
+    // Set after an invalid [codec] line so that its sections
+    // are not applied to the previous codec
+    let mut skip_codec = false;
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('[') {
+            section = match line {
+                "[codec]" => Section::Codec,
+                "[pincfg]" => Section::PinConfig,
+                "[verb]" | "[init_verb]" => Section::Verb,
+                "[hint]" => Section::Hint,
+                _ => {
+                    warn!("patch line {}: section {} is not supported", index + 1, line);
+                    Section::Ignored
+                }
+            };
+            continue;
+        }
+        // Everything but [codec] applies to the last codec
+        if section != Section::Codec && codecs.is_empty() {
+            if section != Section::Ignored {
+                warn!("patch line {}: no [codec] line yet", index + 1);
+            }
+            continue;
+        }
+        if section != Section::Codec && skip_codec {
+            continue;
+        }
+        match section {
+            Section::Codec => match parse_numbers(line).as_deref() {
+                Some(&[_, _, address]) if address > PATCH_MAX_CODEC => {
+                    warn!("patch line {}: codec address {:#x} out of range", index + 1, address);
+                    skip_codec = true;
+                },
+                Some(&[vendor_id, subsystem_id, address]) => {
+                    skip_codec = false;
+                    codecs.push(PatchCodec {
+                        vendor_id,
+                        subsystem_id,
+                        address,
+                        pins: Vec::new(),
+                        verbs: Vec::new(),
+                        hints: Vec::new(),
+                    });
+                },
+                _ => warn!("patch line {}: invalid codec line", index + 1)
+            },
+            Section::PinConfig => match parse_numbers(line).as_deref() {
+                Some(&[node, _]) if node > PATCH_MAX_NODE => {
+                    warn!("patch line {}: pincfg node {:#x} out of range", index + 1, node);
+                },
+                Some(&[node, config]) => {
+                    codecs.last_mut().unwrap().pins.push((Node(node), config));
+                },
+                _ => warn!("patch line {}: invalid pincfg line", index + 1)
+            },
+            Section::Verb => match parse_numbers(line).as_deref() {
+                Some(&[node, verb, payload]) if node > PATCH_MAX_NODE || verb > PATCH_MAX_VERB || payload > PATCH_MAX_PAYLOAD => {
+                    warn!("patch line {}: verb {:#x} {:#x} {:#x} out of range", index + 1, node, verb, payload);
+                },
+                Some(&[node, verb, payload]) => {
+                    codecs.last_mut().unwrap().verbs.push((Node(node), Verb(verb), payload));
+                },
+                _ => warn!("patch line {}: invalid verb line", index + 1)
+            },
+            Section::Hint => {
+                let mut parts = line.splitn(2, |c: char| c == '=' || c.is_whitespace());
+                let key = parts.next().unwrap_or("").trim();
+                let value = parts.next().unwrap_or("").trim().trim_start_matches('=').trim();
+                codecs.last_mut().unwrap().hints.push((String::from(key), String::from(value)));
+            },
+            Section::None => warn!("patch line {}: no section", index + 1),
+            Section::Ignored => {}
+        }
+    }
+    codecs
+}
+
+fn read_patch_file(device: Handle) -> uefi::Result<Vec<u8>> {
+    let fs = boot_services()
+        .handle_protocol::<SimpleFileSystem>(device)
+        .ignore_warning()?;
+    // SAFETY: the protocol is not used by anyone else meanwhile
+    let fs = unsafe { &mut *fs.get() };
+    let mut root = fs.open_volume()
+        .ignore_warning()?;
+    let file = root.open(HDA_PATCH_FILE_NAME, FileMode::Read, FileAttribute::empty())
+        .ignore_warning()?;
+    let mut file = match file.into_type().ignore_warning()? {
+        FileType::Regular(file) => file,
+        FileType::Dir(_) => return Err(uefi::Status::NOT_FOUND.into())
+    };
+    let mut data = vec![0u8; HDA_PATCH_MAX_SIZE];
+    let mut size = 0;
+    loop {
+        let count = file.read(&mut data[size..])
+            .map_err(|error| uefi::Error::from(error.status()))
+            .ignore_warning()?;
+        if count == 0 {
+            break;
+        }
+        size += count;
+        if size == data.len() {
+            warn!("patch file is larger than {} bytes", HDA_PATCH_MAX_SIZE);
+            break;
+        }
+    }
+    data.truncate(size);
+    Ok(data.into())
+}
+
+fn read_patch_variable() -> uefi::Result<Vec<u8>> {
+    let name = CStr16::from_u16_with_nul(HDA_PATCH_VARIABLE_NAME)
+        .unwrap();
+    let vendor = VariableVendor(HDA_PATCH_VARIABLE_GUID);
+    let size = runtime_services().get_variable_size(name, &vendor)
+        .ignore_warning()?;
+    if size > HDA_PATCH_MAX_SIZE {
+        warn!("HdaPatch variable is larger than {} bytes", HDA_PATCH_MAX_SIZE);
+        return Err(uefi::Status::BAD_BUFFER_SIZE.into());
+    }
+    let mut data = vec![0u8; size];
+    let (size, _attributes) = runtime_services().get_variable(name, &vendor, data.as_mut_slice())
+        .ignore_warning()?;
+    data.truncate(size);
+    Ok(data.into())
+}
+
+// The file on the ESP takes precedence over the variable
+pub(crate) fn load_patch() -> Vec<PatchCodec> {
+    // SAFETY: only written once by efi_main()
+    let device = unsafe { IMAGE_DEVICE };
+    let data = device
+        .ok_or(uefi::Status::NOT_FOUND.into())
+        .and_then(|device| read_patch_file(device).ignore_warning())
+        .or_else(|error| {
+            if error.status() != uefi::Status::NOT_FOUND && error.status() != uefi::Status::UNSUPPORTED {
+                warn!("failed to read {}: {:?}", HDA_PATCH_FILE_NAME, error.status());
+            }
+            read_patch_variable().ignore_warning()
+        });
+    let data = match data {
+        Ok(data) => data,
+        Err(error) => {
+            if error.status() != uefi::Status::NOT_FOUND {
+                warn!("failed to read HdaPatch variable: {:?}", error.status());
+            }
+            return Vec::new();
+        }
+    };
+    match str::from_utf8(data.as_slice()) {
+        Ok(text) => parse_patch(text),
+        Err(_) => {
+            warn!("patch is not a text file");
+            Vec::new()
+        }
+    }
+}
+
+pub(crate) fn codec_apply_patch<B: BusIo>(bus: &mut B, codec: Codec, patch: &PatchCodec) -> uefi::Result {
+    info!("codec {:?}: applying patch for {:#010x}/{:#010x}", codec, patch.vendor_id, patch.subsystem_id);
+    for &(node, config) in patch.pins.iter() {
+        pin_set_config(bus, codec, node, config)?;
+    }
+    for &(node, verb, payload) in patch.verbs.iter() {
+        bus.exec(make_command(codec, node, verb, Param(payload)))?;
+    }
+    for (key, value) in patch.hints.iter() {
+        info!("codec {:?}: hint {} = {} ignored, hints are not implemented", codec, key, value);
+    }
+    Ok(().into())
+}
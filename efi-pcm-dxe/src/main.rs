@@ -161,6 +161,9 @@ const VID_INTEL: u16 = 0x8086;
 // Reset bit selfclear timeout in microseconds
 //
 const RESET_TIMEOUT: u64 = 1000;
+// Extra time given to the stream to report completion of
+// the last buffer before playback is stopped anyway
+const STREAM_END_TIMEOUT_MS: u64 = 500;
 
 
 //
@@ -744,7 +747,12 @@ fn play_samples(pci: &PciIO, samples: &[i16], channel_count: u8, sampling_rate:
     write_register_byte(pci, CONTROL_PCM_OUT, CONTROL_RESET_BIT)?;
     wait_byte(pci, RESET_TIMEOUT, CONTROL_PCM_OUT, CONTROL_RESET_BIT, 0)?;
     write_register_dword(pci, BDBAR_PCM_OUT, mapping.unwrap().device_address() as u32)?;
-    let playback_time = milliseconds_to_timer_period(1000 * samples.len() as u64 / channel_count as u64 / sampling_rate as u64);
+    // Clear stale completion status before the first buffer
+    // is queued
+    write_register_word(pci, STATUS_PCM_OUT, STATUS_LVBCI_BIT | STATUS_BCIS_BIT | STATUS_FIFOE_BIT)?;
+    // Playback normally ends on LVBCI, the timer only guards
+    // against a stream that never reports it
+    let playback_time = milliseconds_to_timer_period(1000 * samples.len() as u64 / channel_count as u64 / sampling_rate as u64 + STREAM_END_TIMEOUT_MS);
     boot_services()
         .set_timer(
             device.playback_event.unwrap(),
@@ -753,8 +761,7 @@ fn play_samples(pci: &PciIO, samples: &[i16], channel_count: u8, sampling_rate:
     // are transferred. For each chunk of BUFFER_SIZE
     // samples there is a DMA transfer going on. The
     // duration is choosen based on assumestion that all but
-    // last buffer must be fill completely. The last buffer
-    // transfer will be interrupted by playback event.
+    // last buffer must be fill completely.
     // TBD: Relative timer would be better because it does not
     //      suffer from biasing.
     let delay = milliseconds_to_timer_period(1000 * BUFFER_SIZE as u64 / channel_count as u64 / sampling_rate as u64);
@@ -765,6 +772,8 @@ fn play_samples(pci: &PciIO, samples: &[i16], channel_count: u8, sampling_rate:
     // Basically, this is a cached value of (LVI+1)%32.
     // Anything besides initial CIV=0.
     let mut queue_head = 1;
+    // Index of the buffer holding the last sample once queued
+    let mut last_index = None;
     loop {
         let civ = read_register_byte(pci, CIV_PCM_OUT).warning_as_error()?;
         dump_pcm_out_registers(pci);
@@ -776,6 +785,13 @@ fn play_samples(pci: &PciIO, samples: &[i16], channel_count: u8, sampling_rate:
             if bc != 0 {
                 total_offset += sc;
                 total_sample_count -= sc;
+                if total_sample_count == 0 {
+                    // LVBCI left over from an underrun must not
+                    // be taken for the completion of the last
+                    // buffer, CELV clears with the LVI update
+                    write_register_word(pci, STATUS_PCM_OUT, STATUS_LVBCI_BIT)?;
+                    last_index = Some(queue_head);
+                }
                 write_register_byte(pci, LVI_PCM_OUT, queue_head as u8);
                 queue_head += 1;
                 if queue_head >= BUFFER_COUNT as u8 {
@@ -783,26 +799,39 @@ fn play_samples(pci: &PciIO, samples: &[i16], channel_count: u8, sampling_rate:
                 }
             }
         }
-        let picb = read_register_word(pci, PICB_PCM_OUT)
+        // Once the last buffer is queued its completion
+        // (LVBCI, or CELV with the DMA engine halted) marks
+        // the end of the playback
+        let mut status = read_register_word(pci, STATUS_PCM_OUT)
             .warning_as_error()?;
-        if picb < channel_count as u16 {
-            // Maybe there is no other buffer queued up and
-            // thus we must end the playback instead of
-            // transferring a new chunk of data. Very unlikely
-            // but might cause a piece of junk being played otherwise.
-            let playback_done = boot_services()
-                .check_event (device.playback_event.unwrap())
-                .warning_as_error();
-            if playback_done.is_err() {
+        if let Some(last) = last_index {
+            let civ = read_register_byte(pci, CIV_PCM_OUT).warning_as_error()?;
+            if civ != last && status & STATUS_DCH_BIT != 0 {
+                // Halted by an underrun before the last buffer
+                // was queued
                 write_register_byte(pci, CONTROL_PCM_OUT, CONTROL_DMA_BIT)?;
-            } else {
+                status = read_register_word(pci, STATUS_PCM_OUT)
+                    .warning_as_error()?;
+            }
+            let halted = STATUS_CELV_BIT | STATUS_DCH_BIT;
+            if civ == last && (status & STATUS_LVBCI_BIT != 0 || status & halted == halted) {
+                write_register_word(pci, STATUS_PCM_OUT, STATUS_LVBCI_BIT | STATUS_BCIS_BIT)?;
+                info!("last sample played");
                 break;
             }
+        } else if status & STATUS_LVBCI_BIT != 0 {
+            // Underrun, more buffers are still to be queued
+            write_register_word(pci, STATUS_PCM_OUT, STATUS_LVBCI_BIT)?;
+        }
+        let picb = read_register_word(pci, PICB_PCM_OUT)
+            .warning_as_error()?;
+        if picb < channel_count as u16 {
+            write_register_byte(pci, CONTROL_PCM_OUT, CONTROL_DMA_BIT)?;
             // Check for underrun condition. This is not a
             // proper way to handle underrun but will still
             // do it because it is simple.
             let lvi = read_register_byte(pci, LVI_PCM_OUT).warning_as_error()?;
-            if lvi == civ {
+            if lvi == civ && last_index == Some(civ) {
                 break;
             }
         }
@@ -812,6 +841,7 @@ fn play_samples(pci: &PciIO, samples: &[i16], channel_count: u8, sampling_rate:
             .wait_for_event (&mut [device.playback_event.unwrap(), device.picb_event.unwrap()])
             .discard_errdata()?;
         if index.unwrap() == 0 {
+            warn!("stream did not report LVBCI in time");
             break;
         }
     }
@@ -848,7 +878,13 @@ fn copy_samples_to_buffer(bdl: &mut BufferDescriptorListWithBuffers, index: usiz
         if count > 1 {
             buffer_offset += count as usize;
             descriptor.length = (count - 1) as u16;
-            descriptor.control = 0;
+            // Interrupt on the buffer with the last sample and
+            // transmit zeros once it is consumed
+            descriptor.control = if buffer_offset == samples.len() {
+                BDBAR_IOC_BIT | BDBAR_LAST_BIT
+            } else {
+                0
+            };
             buffer_count += 1;
         } else {
             descriptor.length = 0;
@@ -1,7 +1,7 @@
 use uefi::prelude::*;
 use uefi::proto::pci::PciIO;
 
-use efi_pcm::SimpleAudioOut;
+use efi_pcm::{SimpleAudioOut, HdaCodecInfo};
 
 pub fn connect_pci_recursively() -> uefi::Result {
 
@@ -32,3 +32,21 @@ pub fn enum_simple_audio_out() -> uefi::Result {
 
     uefi::Status::SUCCESS.into()
 }
+
+pub fn dump_codec_info() -> uefi::Result {
+
+    let bt = unsafe { uefi_services::system_table().as_ref().boot_services() };
+
+    let handles = bt.find_handles::<HdaCodecInfo>().ignore_warning()?;
+
+    for &handle in handles.iter() {
+        let codec_info = bt.handle_protocol::<HdaCodecInfo>(handle).ignore_warning()?;
+        let codec_info = unsafe { &mut *codec_info.get() };
+        let text = codec_info.dump().ignore_warning()?;
+        for line in text.lines() {
+            info!("{}", line);
+        }
+    }
+
+    uefi::Status::SUCCESS.into()
+}
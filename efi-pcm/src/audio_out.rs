@@ -0,0 +1,177 @@
+use uefi::prelude::*;
+use uefi::proto::device_path::DevicePath;
+use uefi::table::boot::BootServices;
+use uefi::table::runtime::{RuntimeServices, VariableAttributes, VariableVendor};
+use uefi::CStr16;
+
+use alloc::vec::Vec;
+
+use crate::SimpleAudioOut;
+
+// The vendor GUID of the AudioOut variable. This is the same
+// GUID as the protocol itself so that the variable lives in
+// our own namespace rather than in EFI_GLOBAL_VARIABLE.
+pub const AUDIO_OUT_VARIABLE_GUID: uefi::Guid = <SimpleAudioOut as uefi::Identify>::GUID;
+
+// "AudioOut" as a null terminated UCS-2 string
+const AUDIO_OUT_VARIABLE_NAME: &[u16] = &[
+    b'A' as u16, b'u' as u16, b'd' as u16, b'i' as u16,
+    b'o' as u16, b'O' as u16, b'u' as u16, b't' as u16,
+    0
+];
+
+// Table 44. Device Path End Structure, UEFI Spec 2.8
+const END_DEVICE_PATH_TYPE: u8 = 0x7f;
+const END_ENTIRE_DEVICE_PATH_SUBTYPE: u8 = 0xff;
+
+const DEVICE_PATH_HEADER_SIZE: usize = 4;
+
+fn audio_out_variable_name() -> &'static CStr16 {
+    CStr16::from_u16_with_nul(AUDIO_OUT_VARIABLE_NAME)
+        .unwrap()
+}
+
+// Split a (possibly multi-instance) device path into its
+// instances. Each instance is returned without its end node.
+// Malformed paths are cut at the first malformed node.
+fn split_instances(data: &[u8]) -> Vec<&[u8]> {
+    let mut instances = Vec::new();
+    let mut start = 0;
+    let mut offset = 0;
+    while offset + DEVICE_PATH_HEADER_SIZE <= data.len() {
+        let device_type = data[offset];
+        let sub_type = data[offset + 1];
+        let length = usize::from(u16::from_le_bytes([data[offset + 2], data[offset + 3]]));
+        if length < DEVICE_PATH_HEADER_SIZE || offset + length > data.len() {
+            warn!("malformed device path node at offset {}", offset);
+            break;
+        }
+        if device_type == END_DEVICE_PATH_TYPE {
+            if offset > start {
+                instances.push(&data[start..offset]);
+            }
+            if sub_type == END_ENTIRE_DEVICE_PATH_SUBTYPE {
+                break;
+            }
+            start = offset + length;
+        }
+        offset += length;
+    }
+    instances
+}
+
+// Return the bytes of a single instance device path without
+// its end node
+fn device_path_bytes(device_path: &DevicePath) -> &[u8] {
+    let base = device_path as *const DevicePath as *const u8;
+    let mut size = 0;
+    loop {
+        // SAFETY: the device path is terminated by an end
+        //         node per the UEFI spec
+        let header = unsafe { core::slice::from_raw_parts(base.add(size), DEVICE_PATH_HEADER_SIZE) };
+        let length = usize::from(u16::from_le_bytes([header[2], header[3]]));
+        if header[0] == END_DEVICE_PATH_TYPE || length < DEVICE_PATH_HEADER_SIZE {
+            break;
+        }
+        size += length;
+    }
+    // SAFETY: all nodes up to the end node were just visited
+    unsafe { core::slice::from_raw_parts(base, size) }
+}
+
+fn read_audio_out_variable(rt: &RuntimeServices) -> uefi::Result<Vec<u8>> {
+    let name = audio_out_variable_name();
+    let vendor = VariableVendor(AUDIO_OUT_VARIABLE_GUID);
+    let size = rt.get_variable_size(name, &vendor)
+        .ignore_warning()?;
+    let mut data = vec![0u8; size];
+    let (size, _attributes) = rt.get_variable(name, &vendor, data.as_mut_slice())
+        .ignore_warning()?;
+    data.truncate(size);
+    Ok(data.into())
+}
+
+/// Store the preferred audio output device path in the
+/// non-volatile AudioOut variable.
+pub fn set_default_audio_out(rt: &RuntimeServices, device_path: &DevicePath) -> uefi::Result {
+    let mut data = device_path_bytes(device_path).to_vec();
+    data.extend_from_slice(&[END_DEVICE_PATH_TYPE, END_ENTIRE_DEVICE_PATH_SUBTYPE, 4, 0]);
+    rt.set_variable(
+        audio_out_variable_name(),
+        &VariableVendor(AUDIO_OUT_VARIABLE_GUID),
+        VariableAttributes::NON_VOLATILE
+            | VariableAttributes::BOOTSERVICE_ACCESS
+            | VariableAttributes::RUNTIME_ACCESS,
+        data.as_slice())
+}
+
+/// Enumerate SimpleAudioOut handles in order of preference.
+///
+/// The order is:
+///  1. handles whose device path matches an AudioOut instance exactly,
+///  2. handles whose device path starts with an AudioOut instance
+///     (e.g. the variable names a controller rather than a codec),
+///  3. all remaining handles in the order of LocateHandle().
+///
+/// Within the first two groups the order of instances in the
+/// variable is preserved.
+pub fn enum_audio_out(bt: &BootServices, rt: &RuntimeServices) -> uefi::Result<Vec<Handle>> {
+    let handles = bt.find_handles::<SimpleAudioOut>()
+        .ignore_warning()?;
+    let variable = match read_audio_out_variable(rt).ignore_warning() {
+        Ok(variable) => variable,
+        Err(error) => {
+            if error.status() != uefi::Status::NOT_FOUND {
+                warn!("failed to read AudioOut variable: {:?}", error.status());
+            }
+            Vec::new()
+        }
+    };
+    let instances = split_instances(variable.as_slice());
+    let paths = handles
+        .iter()
+        .map(|&handle| {
+            bt.handle_protocol::<DevicePath>(handle)
+                .ignore_warning()
+                .ok()
+                // SAFETY: the device path is not mutated while we hold the reference
+                .map(|device_path| device_path_bytes(unsafe { &*device_path.get() }))
+        })
+        .collect::<Vec<_>>();
+    let mut result = Vec::with_capacity(handles.len());
+    let push = |handle: Handle, result: &mut Vec<Handle>| {
+        if !result.iter().any(|&other| other == handle) {
+            result.push(handle);
+        }
+    };
+    for instance in instances.iter() {
+        for (&handle, path) in handles.iter().zip(paths.iter()) {
+            if path.map(|path| path == *instance).unwrap_or(false) {
+                push(handle, &mut result);
+            }
+        }
+    }
+    for instance in instances.iter() {
+        for (&handle, path) in handles.iter().zip(paths.iter()) {
+            if path.map(|path| path.starts_with(instance)).unwrap_or(false) {
+                push(handle, &mut result);
+            }
+        }
+    }
+    for &handle in handles.iter() {
+        push(handle, &mut result);
+    }
+    Ok(result.into())
+}
+
+/// Locate the audio output the user has chosen via the
+/// AudioOut variable, falling back to the first available
+/// SimpleAudioOut handle.
+pub fn locate_audio_out(bt: &BootServices, rt: &RuntimeServices) -> uefi::Result<Handle> {
+    let handles = enum_audio_out(bt, rt)
+        .ignore_warning()?;
+    match handles.first() {
+        Some(&handle) => Ok(handle.into()),
+        None => Err(uefi::Status::NOT_FOUND.into())
+    }
+}
@@ -4,7 +4,10 @@
 // necessary for derive(Protocol) in our crate
 #![feature(negative_impls)]
 
+#[macro_use]
 extern crate log;
+#[macro_use]
+extern crate alloc;
 extern crate uefi;
 
 // TBD: these must be located on the root crate so that unsafe_guid macro will work
@@ -16,3 +19,15 @@ use uefi::Identify;
 // TBD: additional module is necessary due to uefi-rs inconsistencies
 mod proto;
 pub use proto::*;
+
+mod audio_out;
+pub use audio_out::*;
+
+mod melody;
+pub use melody::*;
+
+mod midi;
+pub use midi::*;
+
+mod synth;
+pub use synth::*;
@@ -0,0 +1,192 @@
+use uefi::prelude::*;
+use uefi::table::boot::BootServices;
+
+use alloc::vec::Vec;
+
+use crate::SimpleAudioOut;
+
+// Frequencies of the 8th octave (C8..B8) in Hz. Lower octaves
+// are obtained by halving the frequency.
+const OCTAVE_8: [u16; 12] = [
+    4186, 4435, 4699, 4978, 5274, 5588, 5920, 6272, 6645, 7040, 7459, 7902
+];
+
+const MIN_OCTAVE: u32 = 1;
+const MAX_OCTAVE: u32 = 8;
+
+// RTTTL defaults when the control section omits a value
+const DEFAULT_DURATION: u32 = 4;
+const DEFAULT_OCTAVE: u32 = 6;
+const DEFAULT_BPM: u32 = 63;
+
+/// A single note of a melody. The frequency of a rest is zero.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Note {
+    pub freq: u16,
+    pub duration: u16,
+}
+
+impl Note {
+    pub fn is_rest(&self) -> bool {
+        self.freq == 0
+    }
+}
+
+/// Frequency in Hz of the given semitone (0 is C) in the given octave
+pub fn note_frequency(semitone: u32, octave: u32) -> u16 {
+    let octave = octave.max(MIN_OCTAVE).min(MAX_OCTAVE);
+    OCTAVE_8[(semitone % 12) as usize] >> (MAX_OCTAVE - octave)
+}
+
+fn parse_number(text: &str) -> Option<u32> {
+    if text.is_empty() {
+        return None;
+    }
+    text.parse::<u32>().ok()
+}
+
+fn split_digits(text: &str) -> (&str, &str) {
+    let position = text
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(text.len());
+    text.split_at(position)
+}
+
+// Whole note duration in milliseconds; a beat is a quarter note
+fn whole_note_duration(bpm: u32) -> u32 {
+    4 * 60_000 / bpm
+}
+
+fn parse_control(section: &str) -> uefi::Result<(u32, u32, u32)> {
+    let mut duration = DEFAULT_DURATION;
+    let mut octave = DEFAULT_OCTAVE;
+    let mut bpm = DEFAULT_BPM;
+    for item in section.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+        let mut pair = item.splitn(2, '=');
+        let key = pair.next().unwrap_or("").trim();
+        let value = pair.next()
+            .map(str::trim)
+            .and_then(parse_number);
+        match (key, value) {
+            ("d", Some(value)) if value != 0 => duration = value,
+            ("o", Some(value)) if value >= MIN_OCTAVE && value <= MAX_OCTAVE => octave = value,
+            ("b", Some(value)) if value != 0 => bpm = value,
+            _ => {
+                warn!("RTTTL: invalid control item {:?}", item);
+                return Err(uefi::Status::INVALID_PARAMETER.into());
+            }
+        }
+    }
+    Ok((duration, octave, bpm).into())
+}
+
+fn parse_note(item: &str, default_duration: u32, default_octave: u32, bpm: u32) -> Option<Note> {
+    let (duration, rest) = split_digits(item);
+    let duration = if duration.is_empty() {
+        default_duration
+    } else {
+        parse_number(duration).filter(|&d| d != 0)?
+    };
+    let mut chars = rest.chars();
+    let semitone = match chars.next()?.to_ascii_lowercase() {
+        'c' => Some(0),
+        'd' => Some(2),
+        'e' => Some(4),
+        'f' => Some(5),
+        'g' => Some(7),
+        'a' => Some(9),
+        'b' | 'h' => Some(11),
+        'p' => None,
+        _ => return None,
+    };
+    let mut rest = chars.as_str();
+    let mut semitone = semitone;
+    if let Some(stripped) = rest.strip_prefix('#') {
+        semitone = semitone.map(|s| s + 1);
+        rest = stripped;
+    }
+    // The dot may appear either before or after the octave
+    let mut dotted = false;
+    if let Some(stripped) = rest.strip_prefix('.') {
+        dotted = true;
+        rest = stripped;
+    }
+    let (octave, rest) = split_digits(rest);
+    let octave = if octave.is_empty() {
+        default_octave
+    } else {
+        parse_number(octave).filter(|&o| o >= MIN_OCTAVE && o <= MAX_OCTAVE)?
+    };
+    match rest {
+        "" => {},
+        "." => dotted = true,
+        _ => return None,
+    }
+    let mut duration_ms = whole_note_duration(bpm) / duration;
+    if dotted {
+        duration_ms += duration_ms / 2;
+    }
+    let freq = semitone
+        .map(|semitone| {
+            // B# rolls over into the next octave
+            note_frequency(semitone, octave + semitone / 12)
+        })
+        .unwrap_or(0);
+    Some(Note {
+        freq,
+        duration: duration_ms.min(u32::from(u16::MAX)) as u16
+    })
+}
+
+/// Parse a melody in RTTTL (Nokia ring tone) notation.
+///
+/// The format is `name:d=4,o=5,b=120:8c,8d,4e.,p,2c6` where the
+/// control section sets the default duration, octave and tempo
+/// in beats per minute. Each note is `[duration]note[#][.][octave][.]`
+/// and `p` denotes a pause.
+pub fn parse_rtttl(text: &str) -> uefi::Result<Vec<Note>> {
+    let mut sections = text.splitn(3, ':');
+    let name = sections.next().unwrap_or("").trim();
+    let (control, notes) = match (sections.next(), sections.next()) {
+        (Some(control), Some(notes)) => (control, notes),
+        _ => {
+            warn!("RTTTL: missing sections in {:?}", name);
+            return Err(uefi::Status::INVALID_PARAMETER.into());
+        }
+    };
+    let (duration, octave, bpm) = parse_control(control)
+        .ignore_warning()?;
+    let mut result = Vec::new();
+    for item in notes.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+        match parse_note(item, duration, octave, bpm) {
+            Some(note) => result.push(note),
+            None => {
+                warn!("RTTTL: invalid note {:?} in {:?}", item, name);
+                return Err(uefi::Status::INVALID_PARAMETER.into());
+            }
+        }
+    }
+    Ok(result.into())
+}
+
+/// Play a melody through SimpleAudioOut::tone(). Pauses are
+/// made by stalling because tone() does not accept zero
+/// frequency.
+pub fn play_melody(bt: &BootServices, audio_out: &mut SimpleAudioOut, notes: &[Note]) -> uefi::Result {
+    for note in notes {
+        if note.is_rest() {
+            bt.stall(usize::from(note.duration) * 1000);
+        } else {
+            audio_out.tone(note.freq, note.duration)
+                .ignore_warning()?;
+        }
+    }
+    Ok(().into())
+}
+
+/// Parse and play a melody in RTTTL notation
+pub fn play_rtttl(bt: &BootServices, audio_out: &mut SimpleAudioOut, text: &str) -> uefi::Result {
+    let notes = parse_rtttl(text)
+        .ignore_warning()?;
+    play_melody(bt, audio_out, notes.as_slice())
+}
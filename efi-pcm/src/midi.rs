@@ -0,0 +1,283 @@
+use alloc::vec::Vec;
+
+// Standard MIDI File 1.0, The MIDI Manufacturers Association
+const SMF_HEADER_CHUNK: &[u8; 4] = b"MThd";
+const SMF_TRACK_CHUNK: &[u8; 4] = b"MTrk";
+
+const SMF_FORMAT_SINGLE_TRACK: u16 = 0;
+const SMF_FORMAT_MULTI_TRACK: u16 = 1;
+
+const SMF_DIVISION_SMPTE_BIT: u16 = 0x8000;
+
+// Status bytes
+const MIDI_NOTE_OFF: u8 = 0x80;
+const MIDI_NOTE_ON: u8 = 0x90;
+const MIDI_POLY_PRESSURE: u8 = 0xa0;
+const MIDI_CONTROL_CHANGE: u8 = 0xb0;
+const MIDI_PROGRAM_CHANGE: u8 = 0xc0;
+const MIDI_CHANNEL_PRESSURE: u8 = 0xd0;
+const MIDI_PITCH_BEND: u8 = 0xe0;
+const MIDI_SYSEX: u8 = 0xf0;
+const MIDI_SYSEX_ESCAPE: u8 = 0xf7;
+const MIDI_META: u8 = 0xff;
+
+// Meta event types
+const MIDI_META_END_OF_TRACK: u8 = 0x2f;
+const MIDI_META_SET_TEMPO: u8 = 0x51;
+
+/// Tempo assumed until the first Set Tempo event (120 bpm)
+pub const MIDI_DEFAULT_TEMPO: u32 = 500_000;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum MidiEvent {
+    NoteOn {
+        channel: u8,
+        key: u8,
+        velocity: u8
+    },
+    NoteOff {
+        channel: u8,
+        key: u8
+    },
+    ControlChange {
+        channel: u8,
+        controller: u8,
+        value: u8
+    },
+    ProgramChange {
+        channel: u8,
+        program: u8
+    },
+    // Microseconds per quarter note
+    Tempo(u32),
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct TimedEvent {
+    pub tick: u64,
+    pub event: MidiEvent
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Division {
+    TicksPerQuarter(u16),
+    Smpte {
+        frames_per_second: u8,
+        ticks_per_frame: u8
+    }
+}
+
+/// A parsed Standard MIDI File with the events of all tracks
+/// merged into a single list ordered by their absolute tick
+pub struct MidiFile {
+    pub format: u16,
+    pub division: Division,
+    pub events: Vec<TimedEvent>
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader {
+            data,
+            position: 0
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.position >= self.data.len()
+    }
+
+    fn bytes(&mut self, count: usize) -> Option<&'a [u8]> {
+        let end = self.position.checked_add(count)?;
+        let bytes = self.data.get(self.position..end)?;
+        self.position = end;
+        Some(bytes)
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.data.get(self.position).cloned()
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|bytes| bytes[0])
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes(4).map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    // Variable-length quantity of at most 4 bytes
+    fn vlq(&mut self) -> Option<u32> {
+        let mut value = 0u32;
+        for _ in 0..4 {
+            let byte = self.u8()?;
+            value = (value << 7) | u32::from(byte & 0x7f);
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+        None
+    }
+}
+
+fn parse_track(track: &[u8], events: &mut Vec<TimedEvent>) -> Option<()> {
+    let mut reader = Reader::new(track);
+    let mut tick = 0u64;
+    let mut running_status = None;
+    while !reader.is_empty() {
+        tick += u64::from(reader.vlq()?);
+        let status = match reader.peek()? {
+            byte if byte & 0x80 != 0 => {
+                reader.u8()?;
+                byte
+            },
+            // Running status: the data byte belongs to the previous channel message
+            _ => running_status?
+        };
+        let channel = status & 0x0f;
+        let event = match status & 0xf0 {
+            MIDI_NOTE_OFF => {
+                let key = reader.u8()?;
+                reader.u8()?;
+                Some(MidiEvent::NoteOff {channel, key})
+            },
+            MIDI_NOTE_ON => {
+                let key = reader.u8()?;
+                let velocity = reader.u8()?;
+                if velocity == 0 {
+                    Some(MidiEvent::NoteOff {channel, key})
+                } else {
+                    Some(MidiEvent::NoteOn {channel, key, velocity})
+                }
+            },
+            MIDI_CONTROL_CHANGE => {
+                let controller = reader.u8()?;
+                let value = reader.u8()?;
+                Some(MidiEvent::ControlChange {channel, controller, value})
+            },
+            MIDI_PROGRAM_CHANGE => {
+                let program = reader.u8()?;
+                Some(MidiEvent::ProgramChange {channel, program})
+            },
+            MIDI_POLY_PRESSURE | MIDI_PITCH_BEND => {
+                reader.bytes(2)?;
+                None
+            },
+            MIDI_CHANNEL_PRESSURE => {
+                reader.u8()?;
+                None
+            },
+            _ => {
+                // System messages cancel the running status
+                running_status = None;
+                match status {
+                    MIDI_SYSEX | MIDI_SYSEX_ESCAPE => {
+                        let length = reader.vlq()?;
+                        reader.bytes(length as usize)?;
+                    },
+                    MIDI_META => {
+                        let typ = reader.u8()?;
+                        let length = reader.vlq()?;
+                        let data = reader.bytes(length as usize)?;
+                        match typ {
+                            MIDI_META_END_OF_TRACK => {
+                                return Some(());
+                            },
+                            MIDI_META_SET_TEMPO if data.len() == 3 => {
+                                let tempo = u32::from_be_bytes([0, data[0], data[1], data[2]]);
+                                if tempo != 0 {
+                                    events.push(TimedEvent {tick, event: MidiEvent::Tempo(tempo)});
+                                }
+                            },
+                            _ => {}
+                        }
+                    },
+                    _ => {
+                        warn!("SMF: unexpected status byte {:#x}", status);
+                        return None;
+                    }
+                }
+                continue;
+            }
+        };
+        running_status = Some(status);
+        if let Some(event) = event {
+            events.push(TimedEvent {tick, event});
+        }
+    }
+    // Tolerate tracks without an End Of Track event
+    Some(())
+}
+
+fn parse_division(division: u16) -> Option<Division> {
+    if division & SMF_DIVISION_SMPTE_BIT != 0 {
+        // The upper byte is a negative SMPTE format (-24, -25, -29 or -30)
+        let frames_per_second = ((division >> 8) as u8 as i8).wrapping_neg() as u8;
+        let ticks_per_frame = (division & 0xff) as u8;
+        match (frames_per_second, ticks_per_frame) {
+            (24, t) | (25, t) | (29, t) | (30, t) if t != 0 => Some(Division::Smpte {
+                frames_per_second,
+                ticks_per_frame
+            }),
+            _ => None
+        }
+    } else if division != 0 {
+        Some(Division::TicksPerQuarter(division))
+    } else {
+        None
+    }
+}
+
+/// Parse a Standard MIDI File of format 0 or 1
+pub fn parse_smf(data: &[u8]) -> uefi::Result<MidiFile> {
+    let mut reader = Reader::new(data);
+    let invalid = |what: &str| {
+        warn!("SMF: {}", what);
+        uefi::Status::INVALID_PARAMETER
+    };
+    if reader.bytes(4) != Some(&SMF_HEADER_CHUNK[..]) {
+        return Err(invalid("no header chunk").into());
+    }
+    let header_length = reader.u32().ok_or_else(|| invalid("truncated header"))?;
+    let header = reader.bytes(header_length as usize)
+        .filter(|header| header.len() >= 6)
+        .ok_or_else(|| invalid("truncated header"))?;
+    let format = u16::from_be_bytes([header[0], header[1]]);
+    let track_count = u16::from_be_bytes([header[2], header[3]]);
+    let division = parse_division(u16::from_be_bytes([header[4], header[5]]))
+        .ok_or_else(|| invalid("unsupported time division"))?;
+    if format != SMF_FORMAT_SINGLE_TRACK && format != SMF_FORMAT_MULTI_TRACK {
+        warn!("SMF: format {} is not supported", format);
+        return Err(uefi::Status::UNSUPPORTED.into());
+    }
+    let mut events = Vec::new();
+    let mut tracks = 0;
+    while tracks < track_count && !reader.is_empty() {
+        let chunk_type = reader.bytes(4).ok_or_else(|| invalid("truncated chunk"))?;
+        let chunk_length = reader.u32().ok_or_else(|| invalid("truncated chunk"))?;
+        let chunk = reader.bytes(chunk_length as usize).ok_or_else(|| invalid("truncated chunk"))?;
+        // Unknown chunk types must be skipped
+        if chunk_type != &SMF_TRACK_CHUNK[..] {
+            continue;
+        }
+        parse_track(chunk, &mut events).ok_or_else(|| invalid("malformed track"))?;
+        tracks += 1;
+    }
+    if tracks != track_count {
+        warn!("SMF: expected {} tracks, found {}", track_count, tracks);
+    }
+    // Merge tracks. The sort is stable so that events of the
+    // same tick keep their track order; in format 1 the
+    // first track is the tempo map.
+    events.sort_by_key(|event| event.tick);
+    Ok(MidiFile {
+        format,
+        division,
+        events
+    }.into())
+}
@@ -2,6 +2,8 @@ use uefi::proto::Protocol;
 
 use uefi::unsafe_guid;
 
+use alloc::string::String;
+
 type ResetFn =
     extern "efiapi" fn(this: &mut SimpleAudioOut) -> uefi::Status;
 
@@ -14,6 +16,15 @@ type ToneFn =
 type QueryModeFn =
     extern "efiapi" fn(this: &mut SimpleAudioOut, index: usize, mode: &mut SimpleAudioMode) -> uefi::Status;
 
+type DumpFn =
+    extern "efiapi" fn(this: &mut HdaCodecInfo, buffer: *mut u8, buffer_size: &mut usize) -> uefi::Status;
+
+type GetSpdifFn =
+    extern "efiapi" fn(this: &mut HdaCodecInfo, control: &mut u32) -> uefi::Status;
+
+type SetSpdifFn =
+    extern "efiapi" fn(this: &mut HdaCodecInfo, control: u32) -> uefi::Status;
+
 //
 // device capabilities
 //
@@ -38,6 +49,15 @@ pub const AUDIO_RATE_48000: u32 = 48000;
 //
 pub const AUDIO_FORMAT_S16LE: u32 = 0x0;
 
+//
+// S/PDIF channel status, IEC 60958-3 category code in bits 14:8
+//
+pub const SPDIF_COPYRIGHT: u32 = 0x1;
+pub const SPDIF_PROFESSIONAL: u32 = 0x2;
+pub const SPDIF_NON_PCM: u32 = 0x4;
+pub const SPDIF_CATEGORY_SHIFT: u32 = 8;
+pub const SPDIF_CATEGORY_MASK: u32 = 0x7f << SPDIF_CATEGORY_SHIFT;
+
 #[repr(C)]
 pub struct SimpleAudioMode {
     pub sampling_rate: u32,
@@ -71,4 +91,55 @@ impl SimpleAudioOut {
         (self.write)(self, sampling_rate, channel_count, format, samples.as_ptr(), samples.len())
             .into()
     }
+    pub fn query_mode(&mut self, index: usize) -> uefi::Result<SimpleAudioMode> {
+        let mut mode = SimpleAudioMode {
+            sampling_rate: 0,
+            channel_count: 0,
+            sample_format: 0,
+        };
+        (self.query_mode)(self, index, &mut mode)
+            .into_with_val(|| mode)
+    }
+}
+
+/// Text description of the codec widget graph in the same
+/// format as Linux /proc/asound/card*/codec#* so that both can
+/// be diffed. S/PDIF outputs also take the channel status bits
+/// (SPDIF_*) to send with the following writes.
+#[repr(C)]
+#[unsafe_guid("1007c375-80af-4348-8a04-670a422dd249")]
+#[derive(Protocol)]
+pub struct HdaCodecInfo {
+    pub dump: DumpFn,
+    pub get_spdif: GetSpdifFn,
+    pub set_spdif: SetSpdifFn,
+}
+
+impl HdaCodecInfo {
+    pub fn dump(&mut self) -> uefi::Result<String> {
+        let mut buffer = alloc::vec::Vec::new();
+        loop {
+            // The codec state may change between the calls and
+            // so may the size of the dump
+            let mut size = buffer.len();
+            let status = (self.dump)(self, buffer.as_mut_ptr(), &mut size);
+            if status == uefi::Status::BUFFER_TOO_SMALL {
+                buffer.resize(size, 0);
+                continue;
+            }
+            return status.into_with_val(|| {
+                buffer.truncate(size);
+                String::from_utf8_lossy(&buffer).into_owned()
+            });
+        }
+    }
+    pub fn get_spdif(&mut self) -> uefi::Result<u32> {
+        let mut control = 0;
+        (self.get_spdif)(self, &mut control)
+            .into_with_val(|| control)
+    }
+    pub fn set_spdif(&mut self, control: u32) -> uefi::Result {
+        (self.set_spdif)(self, control)
+            .into()
+    }
 }
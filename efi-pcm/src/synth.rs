@@ -0,0 +1,442 @@
+use uefi::prelude::*;
+
+use alloc::vec::Vec;
+
+use crate::{
+    parse_smf,
+    Division,
+    MidiEvent,
+    SimpleAudioMode,
+    SimpleAudioOut,
+    AUDIO_FORMAT_S16LE,
+    AUDIO_RATE_22050,
+    MIDI_DEFAULT_TEMPO,
+};
+
+pub const SYNTH_MAX_VOICES: usize = 16;
+
+const MIDI_CHANNELS: usize = 16;
+const MIDI_PERCUSSION_CHANNEL: u8 = 9;
+const MIDI_DEFAULT_VOLUME: u8 = 100;
+
+// Controller numbers
+const MIDI_CC_VOLUME: u8 = 7;
+const MIDI_CC_ALL_SOUND_OFF: u8 = 120;
+const MIDI_CC_ALL_NOTES_OFF: u8 = 123;
+
+// Frequencies of the lowest MIDI octave (C-1..B-1) in mHz. Key
+// 69 is A4 at 440 Hz.
+const OCTAVE_MINUS_1: [u32; 12] = [
+    8176, 8662, 9177, 9723, 10301, 10913, 11562, 12250, 12978, 13750, 14568, 15434
+];
+
+const ENVELOPE_SHIFT: u32 = 15;
+const ENVELOPE_MAX: i32 = 1 << ENVELOPE_SHIFT;
+
+// Envelope timings in milliseconds. The attack and release are
+// short ramps that only exist to avoid clicks.
+const ATTACK_MS: u32 = 5;
+const RELEASE_MS: u32 = 60;
+const PERCUSSION_MS: u32 = 120;
+
+// This many voices at full level can play before the mix clips
+const MIX_HEADROOM: i32 = 4;
+
+/// Longest song play_midi() renders. Every write() sets the
+/// stream up again, so the song is played by a single one and
+/// has to fit into memory as a whole.
+pub const SYNTH_MAX_MS: u32 = 60 * 1000;
+
+const SINE_TABLE_SIZE: usize = 256;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Timbre {
+    Square,
+    Sawtooth,
+    Triangle,
+    Sine,
+    Noise,
+}
+
+impl Timbre {
+    /// The built-in timbre closest to a General MIDI program
+    pub fn from_program(program: u8) -> Timbre {
+        match program {
+            // Pianos, chromatic percussion
+            0..=15 => Timbre::Triangle,
+            // Organs
+            16..=23 => Timbre::Sine,
+            // Guitars, basses
+            24..=39 => Timbre::Triangle,
+            // Strings, ensembles
+            40..=55 => Timbre::Sawtooth,
+            // Brass, reeds, pipes, synth leads
+            56..=87 => Timbre::Square,
+            // Synth pads, effects
+            88..=103 => Timbre::Sine,
+            _ => Timbre::Triangle,
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Voice {
+    channel: u8,
+    key: u8,
+    timbre: Timbre,
+    phase: u32,
+    phase_step: u32,
+    // velocity times channel volume
+    level: i32,
+    envelope: i32,
+    release_step: i32,
+    releasing: bool,
+    age: u32,
+    noise: u32,
+}
+
+impl Voice {
+    fn oscillator(&mut self, sine: &[i16; SINE_TABLE_SIZE]) -> i32 {
+        let phase = self.phase;
+        self.phase = self.phase.wrapping_add(self.phase_step);
+        match self.timbre {
+            Timbre::Square => if phase < 0x8000_0000 { i32::from(i16::MAX) } else { -i32::from(i16::MAX) },
+            Timbre::Sawtooth => (phase >> 16) as i32 - 0x8000,
+            Timbre::Triangle => {
+                let phase = (phase >> 15) as i32;
+                if phase < 0x10000 {
+                    phase - 0x8000
+                } else {
+                    0x18000 - phase
+                }
+            },
+            Timbre::Sine => i32::from(sine[(phase >> 24) as usize]),
+            Timbre::Noise => {
+                // xorshift32
+                self.noise ^= self.noise << 13;
+                self.noise ^= self.noise >> 17;
+                self.noise ^= self.noise << 5;
+                i32::from((self.noise >> 16) as i16)
+            }
+        }
+    }
+
+    fn next(&mut self, sine: &[i16; SINE_TABLE_SIZE], attack_step: i32) -> i32 {
+        let sample = self.oscillator(sine);
+        if self.releasing {
+            self.envelope = (self.envelope - self.release_step).max(0);
+        } else {
+            self.envelope = (self.envelope + attack_step).min(ENVELOPE_MAX);
+        }
+        (sample * self.level / (127 * 127) * self.envelope) >> ENVELOPE_SHIFT
+    }
+
+    fn is_silent(&self) -> bool {
+        self.releasing && self.envelope == 0
+    }
+}
+
+// Bhaskara I's approximation of a half period of sine, where
+// the half period is 128 steps:
+//   sin(x) ~ 4x(128 - x) / (20480 - x(128 - x))
+fn make_sine_table() -> [i16; SINE_TABLE_SIZE] {
+    let mut table = [0i16; SINE_TABLE_SIZE];
+    let half = SINE_TABLE_SIZE / 2;
+    for x in 0..half {
+        let a = (x * (half - x)) as i32;
+        let value = (i32::from(i16::MAX) * 4 * a / (20480 - a)) as i16;
+        table[x] = value;
+        table[x + half] = -value;
+    }
+    table
+}
+
+// Number of envelope steps per sample to ramp over the given time
+fn envelope_step(sampling_rate: u32, ms: u32) -> i32 {
+    let samples = (sampling_rate * ms / 1000).max(1);
+    (ENVELOPE_MAX / samples as i32).max(1)
+}
+
+/// A small polyphonic synthesizer driven by MIDI events
+pub struct Synth {
+    sampling_rate: u32,
+    voices: [Option<Voice>; SYNTH_MAX_VOICES],
+    programs: [u8; MIDI_CHANNELS],
+    volumes: [u8; MIDI_CHANNELS],
+    sine: [i16; SINE_TABLE_SIZE],
+    attack_step: i32,
+    release_step: i32,
+    percussion_step: i32,
+    age: u32,
+    noise: u32,
+}
+
+impl Synth {
+    pub fn new(sampling_rate: u32) -> Synth {
+        Synth {
+            sampling_rate,
+            voices: [None; SYNTH_MAX_VOICES],
+            programs: [0; MIDI_CHANNELS],
+            volumes: [MIDI_DEFAULT_VOLUME; MIDI_CHANNELS],
+            sine: make_sine_table(),
+            attack_step: envelope_step(sampling_rate, ATTACK_MS),
+            release_step: envelope_step(sampling_rate, RELEASE_MS),
+            percussion_step: envelope_step(sampling_rate, PERCUSSION_MS),
+            age: 0,
+            noise: 0x1234_5678,
+        }
+    }
+
+    // Pick a free voice, else the quietest releasing voice, else
+    // the oldest one
+    fn allocate_voice(&mut self) -> usize {
+        if let Some(index) = self.voices.iter().position(Option::is_none) {
+            return index;
+        }
+        let releasing = self.voices
+            .iter()
+            .enumerate()
+            .filter_map(|(index, voice)| voice.filter(|voice| voice.releasing).map(|voice| (index, voice.envelope)))
+            .min_by_key(|&(_, envelope)| envelope)
+            .map(|(index, _)| index);
+        releasing.unwrap_or_else(|| {
+            self.voices
+                .iter()
+                .enumerate()
+                .filter_map(|(index, voice)| voice.map(|voice| (index, voice.age)))
+                .min_by_key(|&(_, age)| age)
+                .map(|(index, _)| index)
+                .unwrap_or(0)
+        })
+    }
+
+    fn note_on(&mut self, channel: u8, key: u8, velocity: u8) {
+        let key = key & 0x7f;
+        let velocity = velocity & 0x7f;
+        let index = self.allocate_voice();
+        let percussion = channel == MIDI_PERCUSSION_CHANNEL;
+        let timbre = if percussion {
+            Timbre::Noise
+        } else {
+            Timbre::from_program(self.programs[usize::from(channel)])
+        };
+        let freq = u64::from(OCTAVE_MINUS_1[usize::from(key % 12)] << (key / 12));
+        let phase_step = ((freq << 32) / (u64::from(self.sampling_rate) * 1000)) as u32;
+        self.age = self.age.wrapping_add(1);
+        self.noise = self.noise.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+        self.voices[index] = Some(Voice {
+            channel,
+            key,
+            timbre,
+            phase: 0,
+            phase_step,
+            level: i32::from(velocity) * i32::from(self.volumes[usize::from(channel)]),
+            // Percussion decays on its own and ignores note off
+            envelope: if percussion { ENVELOPE_MAX } else { 0 },
+            release_step: if percussion { self.percussion_step } else { self.release_step },
+            releasing: percussion,
+            age: self.age,
+            noise: self.noise | 1,
+        });
+    }
+
+    fn note_off(&mut self, channel: u8, key: u8) {
+        for voice in self.voices.iter_mut().flatten() {
+            if voice.channel == channel && voice.key == key {
+                voice.releasing = true;
+            }
+        }
+    }
+
+    fn all_notes_off(&mut self, channel: u8) {
+        for voice in self.voices.iter_mut().flatten() {
+            if voice.channel == channel {
+                voice.releasing = true;
+            }
+        }
+    }
+
+    /// Apply a MIDI event. Tempo changes are handled by the
+    /// caller and ignored here.
+    pub fn event(&mut self, event: &MidiEvent) {
+        match *event {
+            MidiEvent::NoteOn {channel, key, velocity} => self.note_on(channel, key, velocity),
+            MidiEvent::NoteOff {channel, key} => self.note_off(channel, key),
+            MidiEvent::ProgramChange {channel, program} => {
+                self.programs[usize::from(channel)] = program;
+            },
+            MidiEvent::ControlChange {channel, controller, value} => match controller {
+                MIDI_CC_VOLUME => self.volumes[usize::from(channel)] = value,
+                MIDI_CC_ALL_SOUND_OFF => {
+                    for voice in self.voices.iter_mut() {
+                        if voice.map(|voice| voice.channel == channel).unwrap_or(false) {
+                            *voice = None;
+                        }
+                    }
+                },
+                MIDI_CC_ALL_NOTES_OFF => self.all_notes_off(channel),
+                _ => {}
+            },
+            MidiEvent::Tempo(_) => {}
+        }
+    }
+
+    pub fn is_silent(&self) -> bool {
+        self.voices.iter().all(Option::is_none)
+    }
+
+    /// Render interleaved S16LE frames. The mix is mono and is
+    /// copied to every channel.
+    pub fn render(&mut self, samples: &mut [i16], channel_count: u8) {
+        let channel_count = usize::from(channel_count.max(1));
+        for frame in samples.chunks_mut(channel_count) {
+            let mut mix = 0;
+            for slot in self.voices.iter_mut() {
+                if let Some(voice) = slot {
+                    mix += voice.next(&self.sine, self.attack_step);
+                    if voice.is_silent() {
+                        *slot = None;
+                    }
+                }
+            }
+            let value = (mix / MIX_HEADROOM)
+                .max(i32::from(i16::MIN))
+                .min(i32::from(i16::MAX)) as i16;
+            for sample in frame.iter_mut() {
+                *sample = value;
+            }
+        }
+    }
+}
+
+// Converts MIDI ticks to frames following the tempo map
+struct MidiClock {
+    division: Division,
+    sampling_rate: u32,
+    tempo: u32,
+    tick_base: u64,
+    frame_base: u64,
+}
+
+impl MidiClock {
+    fn new(division: Division, sampling_rate: u32) -> MidiClock {
+        MidiClock {
+            division,
+            sampling_rate,
+            tempo: MIDI_DEFAULT_TEMPO,
+            tick_base: 0,
+            frame_base: 0,
+        }
+    }
+
+    fn frame(&self, tick: u64) -> u64 {
+        let ticks = u128::from(tick - self.tick_base);
+        let rate = u128::from(self.sampling_rate);
+        let frames = match self.division {
+            Division::TicksPerQuarter(ticks_per_quarter) => {
+                ticks * u128::from(self.tempo) * rate / (u128::from(ticks_per_quarter) * 1_000_000)
+            },
+            // SMPTE time does not depend on tempo; 29 stands for 29.97 drop frame
+            Division::Smpte {frames_per_second: 29, ticks_per_frame} => {
+                ticks * rate * 100 / (2997 * u128::from(ticks_per_frame))
+            },
+            Division::Smpte {frames_per_second, ticks_per_frame} => {
+                ticks * rate / (u128::from(frames_per_second) * u128::from(ticks_per_frame))
+            }
+        };
+        self.frame_base + frames as u64
+    }
+
+    fn set_tempo(&mut self, tick: u64, tempo: u32) {
+        self.frame_base = self.frame(tick);
+        self.tick_base = tick;
+        self.tempo = tempo;
+    }
+}
+
+fn native_mode(audio_out: &mut SimpleAudioOut) -> SimpleAudioMode {
+    match audio_out.query_mode(0).ignore_warning() {
+        Ok(mode) if mode.sample_format == AUDIO_FORMAT_S16LE
+            && mode.sampling_rate != 0
+            && mode.channel_count != 0 => mode,
+        _ => {
+            warn!("no usable native mode, falling back to 22050 Hz stereo");
+            SimpleAudioMode {
+                sampling_rate: AUDIO_RATE_22050,
+                channel_count: 2,
+                sample_format: AUDIO_FORMAT_S16LE,
+            }
+        }
+    }
+}
+
+// Renders the whole song into one buffer that is handed to
+// write() at once
+struct Renderer {
+    mode: SimpleAudioMode,
+    buffer: Vec<i16>,
+    max_frames: u64,
+    frame: u64,
+}
+
+impl Renderer {
+    fn render_until(&mut self, synth: &mut Synth, frame: u64) -> uefi::Result {
+        if frame > self.max_frames {
+            warn!("song is longer than {} ms", SYNTH_MAX_MS);
+            return uefi::Status::BAD_BUFFER_SIZE.into();
+        }
+        if frame > self.frame {
+            let channel_count = usize::from(self.mode.channel_count);
+            let start = self.buffer.len();
+            let frames = (frame - self.frame) as usize;
+            self.buffer.resize(start + frames * channel_count, 0);
+            synth.render(&mut self.buffer[start..], self.mode.channel_count);
+            self.frame = frame;
+        }
+        Ok(().into())
+    }
+}
+
+/// Render a Standard MIDI File with the built-in synthesizer
+/// and play it through SimpleAudioOut::write() at the native
+/// mode of the device. Songs longer than SYNTH_MAX_MS fail
+/// with BAD_BUFFER_SIZE.
+pub fn play_midi(audio_out: &mut SimpleAudioOut, data: &[u8]) -> uefi::Result {
+    let midi = parse_smf(data)
+        .ignore_warning()?;
+    let mode = native_mode(audio_out);
+    let mut synth = Synth::new(mode.sampling_rate);
+    let mut clock = MidiClock::new(midi.division, mode.sampling_rate);
+    let max_frames = u64::from(mode.sampling_rate) * u64::from(SYNTH_MAX_MS) / 1000;
+    let mut renderer = Renderer {
+        mode,
+        buffer: Vec::new(),
+        max_frames,
+        frame: 0,
+    };
+    for event in midi.events.iter() {
+        renderer.render_until(&mut synth, clock.frame(event.tick))
+            .ignore_warning()?;
+        match event.event {
+            MidiEvent::Tempo(tempo) => clock.set_tempo(event.tick, tempo),
+            _ => synth.event(&event.event)
+        }
+    }
+    // Let the released voices fade out
+    let tail = u64::from(renderer.mode.sampling_rate * PERCUSSION_MS.max(RELEASE_MS) / 1000);
+    for channel in 0..MIDI_CHANNELS {
+        synth.all_notes_off(channel as u8);
+    }
+    if !synth.is_silent() {
+        let frame = (renderer.frame + tail).min(renderer.max_frames);
+        renderer.render_until(&mut synth, frame)
+            .ignore_warning()?;
+    }
+    if renderer.buffer.is_empty() {
+        return Ok(().into());
+    }
+    audio_out.write(renderer.mode.sampling_rate,
+                    renderer.mode.channel_count,
+                    renderer.mode.sample_format,
+                    renderer.buffer.as_slice())
+}
@@ -0,0 +1,93 @@
+// Byte layouts of the ELD and the audio infoframe used by
+// efi-hda-dxe for HDMI/DP outputs, checked on the host
+#![allow(dead_code)]
+
+extern crate alloc;
+
+#[path = "../../../efi-hda-dxe/src/hda.rs"]
+mod hda;
+#[path = "../../../efi-hda-dxe/src/display.rs"]
+mod display;
+
+use display::*;
+use hda::*;
+
+// ELD version 2 of an HDMI TV named "TV" with two LPCM
+// descriptors: 2 channels at 32-48 kHz and 8 channels at
+// 32-192 kHz, both 16/20/24 bits
+const ELD_HDMI: [u8; 28] = [
+    0x10, 0x00, 0x06, 0x00,
+    0x62, 0x20, 0x00, 0x01,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x4c, 0x2d, 0x10, 0x0a,
+    b'T', b'V',
+    0x09, 0x07, 0x07,
+    0x0f, 0x7f, 0x07,
+];
+
+fn main() {
+    // TEST#1 -- baseline fields
+    let eld = Eld::from(&ELD_HDMI).expect("valid ELD");
+    assert_eq!(eld.version(), 2);
+    assert_eq!(eld.baseline_length(), 24);
+    assert_eq!(eld.connection_type(), 0);
+    assert_eq!(eld.speaker_allocation(), 0x01);
+    assert_eq!(eld.monitor_name(), b"TV");
+    assert_eq!(eld.sad_count(), 2);
+
+    // TEST#2 -- short audio descriptors
+    let sads = eld.sads().collect::<Vec<_>>();
+    assert_eq!(sads.len(), 2);
+    assert_eq!(sads[0].format(), 1);
+    assert_eq!(sads[0].channels(), 2);
+    assert!(sads[0].supports(48000, 16, 2));
+    assert!(sads[0].supports(32000, 24, 1));
+    assert!(!sads[0].supports(96000, 16, 2));
+    assert!(!sads[0].supports(48000, 16, 6));
+    assert!(!sads[0].supports(48000, 32, 2));
+    assert_eq!(sads[1].channels(), 8);
+    assert!(sads[1].supports(192000, 24, 8));
+
+    // TEST#3 -- DisplayPort connection type
+    let mut dp = ELD_HDMI;
+    dp[5] |= 0x1 << 2;
+    assert_eq!(Eld::from(&dp).expect("valid ELD").connection_type(), 1);
+
+    // TEST#4 -- malformed buffers are rejected
+    assert!(Eld::from(&ELD_HDMI[..19]).is_none());
+    assert!(Eld::from(&ELD_HDMI[..27]).is_none());
+    let mut overlong = ELD_HDMI;
+    overlong[5] = 0x30;
+    assert!(Eld::from(&overlong).is_none());
+
+    // TEST#5 -- HDMI audio infoframe with checksum
+    assert_eq!(make_audio_infoframe(DisplaySink::Hdmi, 2),
+               [0x84, 0x01, 0x0a, 0x70, 0x01, 0x00, 0x00, 0x00, 0x00]);
+    assert_eq!(make_audio_infoframe(DisplaySink::Hdmi, 6),
+               [0x84, 0x01, 0x0a, 0x61, 0x05, 0x00, 0x00, 0x0b, 0x00]);
+    assert_eq!(make_audio_infoframe(DisplaySink::Hdmi, 8),
+               [0x84, 0x01, 0x0a, 0x57, 0x07, 0x00, 0x00, 0x13, 0x00]);
+    for channels in 1..=8 {
+        let frame = make_audio_infoframe(DisplaySink::Hdmi, channels);
+        assert_eq!(frame.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)), 0);
+    }
+
+    // TEST#6 -- DisplayPort audio infoframe
+    assert_eq!(make_audio_infoframe(DisplaySink::DisplayPort, 2),
+               [0x84, 0x1b, 0x44, 0x01, 0x00, 0x00, 0x00, 0x00]);
+    assert_eq!(make_audio_infoframe(DisplaySink::DisplayPort, 4),
+               [0x84, 0x1b, 0x44, 0x03, 0x00, 0x00, 0x08, 0x00]);
+
+    // TEST#7 -- every channel goes to its own slot
+    for channels in 1..=8 {
+        let (_, slots) = hdmi_channel_allocation(channels);
+        assert!(slots.len() >= channels as usize);
+        let mut used = [false; 8];
+        for &slot in slots.iter() {
+            assert!(!used[slot as usize]);
+            used[slot as usize] = true;
+        }
+    }
+
+    println!("ok");
+}